@@ -0,0 +1,11 @@
+-- Keys created by the authorize endpoint, recorded for `/my/history`
+CREATE TABLE IF NOT EXISTS key_history (
+    key_id TEXT PRIMARY KEY NOT NULL,
+    -- SHA-256 of the key, to tell its holder apart without storing the key itself
+    key_hash BLOB NOT NULL,
+    owner_id TEXT NOT NULL,
+    -- Unix timestamp in milliseconds
+    created_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS key_history_owner_id ON key_history (owner_id, created_at);
@@ -0,0 +1,151 @@
+//! Circuit breaker sparing calls to Unkey while it keeps failing
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// State of the breaker, exposed as a gauge in the metrics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Calls go through
+    Closed,
+    /// Calls fail fast until the cooldown elapses
+    Open,
+    /// The cooldown elapsed, a single probe call decides whether to close again
+    HalfOpen,
+}
+
+impl CircuitState {
+    /// Value of the state in the `unkey_circuit_state` gauge
+    pub fn as_gauge(self) -> i64 {
+        match self {
+            CircuitState::Closed => 0,
+            CircuitState::Open => 1,
+            CircuitState::HalfOpen => 2,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Breaker {
+    failures: u32,
+    opened_at: Option<Instant>,
+    /// When the half-open probe was let through, `None` while no probe is running
+    probe_started: Option<Instant>,
+}
+
+/// Opens after `threshold` consecutive failures, failing calls fast for `cooldown`
+///
+/// Clones share the same state.
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Arc<Mutex<Breaker>>,
+}
+
+impl CircuitBreaker {
+    /// Creates a closed breaker, a zero `threshold` disables it
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            state: Arc::new(Mutex::new(Breaker::default())),
+        }
+    }
+
+    /// Whether a call may go through
+    ///
+    /// Once the cooldown elapsed, a single probe is let through; a probe that never
+    /// reports back is replaced after another cooldown.
+    pub fn allow(&self) -> bool {
+        let mut breaker = self.state.lock().unwrap();
+        let Some(opened_at) = breaker.opened_at else {
+            return true;
+        };
+        let now = Instant::now();
+        let probing = breaker
+            .probe_started
+            .is_some_and(|started| now.duration_since(started) < self.cooldown);
+        if now.duration_since(opened_at) < self.cooldown || probing {
+            return false;
+        }
+        breaker.probe_started = Some(now);
+        true
+    }
+
+    /// Closes the breaker after a call Unkey answered
+    pub fn record_success(&self) {
+        *self.state.lock().unwrap() = Breaker::default();
+    }
+
+    /// Counts a failed call, opening the breaker at the threshold or when the probe failed
+    pub fn record_failure(&self) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut breaker = self.state.lock().unwrap();
+        breaker.failures = breaker.failures.saturating_add(1);
+        if breaker.probe_started.is_some() || breaker.failures >= self.threshold {
+            breaker.opened_at = Some(Instant::now());
+            breaker.probe_started = None;
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        let breaker = self.state.lock().unwrap();
+        match breaker.opened_at {
+            None => CircuitState::Closed,
+            Some(opened_at) if opened_at.elapsed() < self.cooldown => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        assert!(breaker.allow());
+        breaker.record_failure();
+
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn half_opens_for_a_single_probe() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(10));
+        breaker.record_failure();
+        std::thread::sleep(Duration::from_millis(20));
+
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(breaker.allow());
+        breaker.record_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn never_opens_when_disabled() {
+        let breaker = CircuitBreaker::new(0, Duration::from_secs(60));
+
+        breaker.record_failure();
+        breaker.record_failure();
+
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.allow());
+    }
+}
@@ -0,0 +1,103 @@
+//! In-memory limit on how many image generations each key owner may run at once
+
+use rocket::tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use rocket::tokio::time::timeout;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Slot held by an owner for the duration of a generation, released on drop
+pub struct OwnerPermit(#[allow(dead_code)] Option<OwnedSemaphorePermit>);
+
+/// Semaphore per owner, each allowing `max` generations at the same time
+///
+/// Clones share the same semaphores.
+#[derive(Clone)]
+pub struct OwnerConcurrencyLimiter {
+    max: usize,
+    wait: Duration,
+    semaphores: Arc<Mutex<HashMap<String, Arc<Semaphore>>>>,
+}
+
+impl OwnerConcurrencyLimiter {
+    /// Creates a limiter allowing `max` generations per owner, 0 disables it
+    ///
+    /// Requests over the limit wait up to `wait` for a generation of the owner to finish.
+    pub fn new(max: usize, wait: Duration) -> Self {
+        Self {
+            max,
+            wait,
+            semaphores: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Takes a slot for `owner_id`, `None` when none frees up in time
+    pub async fn acquire(&self, owner_id: &str) -> Option<OwnerPermit> {
+        if self.max == 0 {
+            return Some(OwnerPermit(None));
+        }
+
+        let semaphore = {
+            let mut semaphores = self.semaphores.lock().unwrap();
+            // A semaphore only referenced by the map has no permits out nor waiters
+            semaphores.retain(|_, semaphore| Arc::strong_count(semaphore) > 1);
+            semaphores
+                .entry(owner_id.to_string())
+                .or_insert_with(|| Arc::new(Semaphore::new(self.max)))
+                .clone()
+        };
+
+        match timeout(self.wait, semaphore.acquire_owned()).await {
+            Ok(Ok(permit)) => Some(OwnerPermit(Some(permit))),
+            _ => None,
+        }
+    }
+
+    /// Drops the semaphores of owners without generations running or waiting,
+    /// returning how many were dropped
+    pub fn prune(&self) -> usize {
+        let mut semaphores = self.semaphores.lock().unwrap();
+        let before = semaphores.len();
+        semaphores.retain(|_, semaphore| Arc::strong_count(semaphore) > 1);
+        before - semaphores.len()
+    }
+
+    /// Number of owners currently tracked
+    #[cfg(test)]
+    fn tracked(&self) -> usize {
+        self.semaphores.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[rocket::async_test]
+    async fn rejects_an_owner_over_the_limit() {
+        let limiter = OwnerConcurrencyLimiter::new(2, Duration::from_millis(50));
+
+        let first = limiter.acquire("alice").await;
+        let second = limiter.acquire("alice").await;
+
+        assert!(first.is_some() && second.is_some());
+        assert!(limiter.acquire("alice").await.is_none());
+        assert!(limiter.acquire("bob").await.is_some());
+
+        drop(first);
+        assert!(limiter.acquire("alice").await.is_some());
+    }
+
+    #[rocket::async_test]
+    async fn forgets_idle_owners() {
+        let limiter = OwnerConcurrencyLimiter::new(1, Duration::from_millis(50));
+
+        drop(limiter.acquire("alice").await);
+        let bob = limiter.acquire("bob").await;
+
+        assert_eq!(limiter.tracked(), 1);
+        drop(bob);
+        assert_eq!(limiter.prune(), 1);
+        assert_eq!(limiter.tracked(), 0);
+    }
+}
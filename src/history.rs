@@ -0,0 +1,164 @@
+//! SQLite store of the keys created by the authorize endpoint
+
+use anyhow::{Context, Result};
+use rocket::serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::models::KeyCreateData;
+use crate::LEGACY_OWNER_ID;
+
+/// Struct for a key listed by the history endpoint
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct KeyHistoryEntry {
+    pub key_id: String,
+    pub owner_id: String,
+    /// Unix timestamp in milliseconds
+    pub created_at: i64,
+}
+
+/// Record of every key created, looked up by the key's owner
+pub struct KeyHistory {
+    pool: SqlitePool,
+}
+
+impl KeyHistory {
+    /// Opens the database at `url`, creating it and running migrations as needed
+    pub async fn connect(url: &str) -> Result<Self> {
+        let options = SqliteConnectOptions::from_str(url)
+            .context("Invalid database URL")?
+            .create_if_missing(true);
+        // An in-memory database only lives as long as its single connection
+        let pool = SqlitePoolOptions::new()
+            .max_connections(if url.contains(":memory:") { 1 } else { 5 })
+            .connect_with(options)
+            .await
+            .context("Failed to open the key history database")?;
+
+        sqlx::migrate!()
+            .run(&pool)
+            .await
+            .context("Failed to migrate the key history database")?;
+        Ok(Self { pool })
+    }
+
+    /// Records a key created for `owner_id`
+    pub async fn record(&self, data: &KeyCreateData, owner_id: &str) -> Result<()> {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_millis() as i64);
+
+        sqlx::query(
+            "INSERT INTO key_history (key_id, key_hash, owner_id, created_at) VALUES (?, ?, ?, ?)",
+        )
+        .bind(&data.key_id)
+        .bind(hash(data.key.expose()))
+        .bind(owner_id)
+        .bind(created_at)
+        .execute(&self.pool)
+        .await
+        .context("Failed to record key")?;
+        Ok(())
+    }
+
+    /// Lists the keys of the owner of `session`, newest first
+    ///
+    /// The session must hold the key itself, not just its id, so a caller only
+    /// ever sees the history of the keys they hold. Keys of the legacy shared
+    /// owner belong to no one else, their sessions only see the key itself.
+    pub async fn list_for(&self, session: &KeyCreateData) -> Result<Vec<KeyHistoryEntry>> {
+        let rows: Vec<(String, String, i64)> = sqlx::query_as(
+            "SELECT history.key_id, history.owner_id, history.created_at
+             FROM key_history AS session
+             JOIN key_history AS history ON history.owner_id = session.owner_id
+                 AND (session.owner_id != ? OR history.key_id = session.key_id)
+             WHERE session.key_id = ? AND session.key_hash = ?
+             ORDER BY history.created_at DESC",
+        )
+        .bind(LEGACY_OWNER_ID)
+        .bind(&session.key_id)
+        .bind(hash(session.key.expose()))
+        .fetch_all(&self.pool)
+        .await
+        .context("Failed to list key history")?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(key_id, owner_id, created_at)| KeyHistoryEntry {
+                key_id,
+                owner_id,
+                created_at,
+            })
+            .collect())
+    }
+}
+
+fn hash(key: &str) -> Vec<u8> {
+    Sha256::digest(key.as_bytes()).to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(key: &str, key_id: &str) -> KeyCreateData {
+        KeyCreateData {
+            key: key.into(),
+            key_id: key_id.into(),
+        }
+    }
+
+    #[rocket::async_test]
+    async fn lists_the_keys_of_the_session_owner() {
+        let history = KeyHistory::connect("sqlite::memory:").await.unwrap();
+        history
+            .record(&key("sk_1", "key_1"), "alice")
+            .await
+            .unwrap();
+        history.record(&key("sk_2", "key_2"), "bob").await.unwrap();
+        history
+            .record(&key("sk_3", "key_3"), "alice")
+            .await
+            .unwrap();
+
+        let entries = history.list_for(&key("sk_1", "key_1")).await.unwrap();
+
+        let ids: Vec<&str> = entries.iter().map(|entry| entry.key_id.as_str()).collect();
+        assert_eq!(ids.len(), 2);
+        assert!(ids.contains(&"key_1") && ids.contains(&"key_3"));
+    }
+
+    #[rocket::async_test]
+    async fn keeps_the_keys_of_the_legacy_owner_apart() {
+        let history = KeyHistory::connect("sqlite::memory:").await.unwrap();
+        history
+            .record(&key("sk_1", "key_1"), LEGACY_OWNER_ID)
+            .await
+            .unwrap();
+        history
+            .record(&key("sk_2", "key_2"), LEGACY_OWNER_ID)
+            .await
+            .unwrap();
+
+        let entries = history.list_for(&key("sk_1", "key_1")).await.unwrap();
+
+        let ids: Vec<&str> = entries.iter().map(|entry| entry.key_id.as_str()).collect();
+        assert_eq!(ids, ["key_1"]);
+    }
+
+    #[rocket::async_test]
+    async fn hides_the_history_from_a_session_without_the_key() {
+        let history = KeyHistory::connect("sqlite::memory:").await.unwrap();
+        history
+            .record(&key("sk_1", "key_1"), "alice")
+            .await
+            .unwrap();
+
+        let entries = history.list_for(&key("sk_forged", "key_1")).await.unwrap();
+
+        assert!(entries.is_empty());
+    }
+}
@@ -0,0 +1,303 @@
+//! In-memory store of image generation responses replayed for repeated `Idempotency-Key`s
+
+use rocket::serde::json::Value;
+use rocket::Either;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Response of a completed generation, as replayed to repeats
+#[derive(Clone, Debug)]
+pub struct CachedResponse {
+    /// JSON body, or the raw PNG for clients accepting `image/png`
+    pub body: Either<Value, Vec<u8>>,
+    pub remaining: Option<usize>,
+}
+
+/// Generation stored for an idempotency key
+struct Entry {
+    /// Hash of the request parameters, telling repeats from other requests
+    fingerprint: [u8; 32],
+    /// `None` while the first request is still generating
+    response: Option<CachedResponse>,
+    expires: Instant,
+}
+
+/// Key that made requests with an idempotency key, looked up without verifying it
+#[derive(Clone, Debug)]
+pub struct KnownKey {
+    pub key_id: String,
+    /// Owner whose requests share the idempotency keys
+    pub owner: String,
+    expires: Instant,
+}
+
+/// Outcome of looking up a request in the store
+#[derive(Debug)]
+pub enum Lookup {
+    /// No request was made with the key yet
+    Miss,
+    /// The same request completed, holding its response
+    Replay(CachedResponse),
+    /// The same request is still generating
+    InProgress,
+    /// Another request was made with the key
+    Conflict,
+}
+
+/// Store keyed by the SHA-256 of the key owner and the idempotency key, so
+/// owners can't see each other's responses
+///
+/// The API keys making requests are remembered by their SHA-256 along with
+/// their owner, for repeats to be matched without verifying the key again.
+/// Only successful responses are stored: a failed request is removed from the
+/// store, so retrying it generates again. Clones share the same entries.
+#[derive(Clone)]
+pub struct IdempotencyStore {
+    ttl: Duration,
+    entries: Arc<Mutex<HashMap<[u8; 32], Entry>>>,
+    keys: Arc<Mutex<HashMap<[u8; 32], KnownKey>>>,
+}
+
+impl IdempotencyStore {
+    /// Creates a store keeping responses for `ttl`, a zero TTL disables it
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            keys: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.ttl.is_zero()
+    }
+
+    fn hash(owner: &str, idempotency_key: &str) -> [u8; 32] {
+        Sha256::new()
+            .chain_update((owner.len() as u64).to_le_bytes())
+            .chain_update(owner.as_bytes())
+            .chain_update(idempotency_key.as_bytes())
+            .finalize()
+            .into()
+    }
+
+    /// The key that made requests with `api_key`, if it did lately
+    pub fn known_key(&self, api_key: &str) -> Option<KnownKey> {
+        let hash: [u8; 32] = Sha256::digest(api_key.as_bytes()).into();
+        let keys = self.keys.lock().unwrap();
+        keys.get(&hash)
+            .filter(|key| key.expires > Instant::now())
+            .cloned()
+    }
+
+    /// Whether `owner` made a request with `idempotency_key` that hasn't expired yet
+    pub fn contains(&self, owner: &str, idempotency_key: &str) -> bool {
+        let hash = Self::hash(owner, idempotency_key);
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(&hash)
+            .is_some_and(|entry| entry.expires > Instant::now())
+    }
+
+    /// Looks up the request `owner` made with `idempotency_key`, `fingerprint`
+    /// telling whether it's the same one
+    pub fn lookup(&self, owner: &str, idempotency_key: &str, fingerprint: [u8; 32]) -> Lookup {
+        let hash = Self::hash(owner, idempotency_key);
+        let entries = self.entries.lock().unwrap();
+        entry_lookup(
+            entries
+                .get(&hash)
+                .filter(|entry| entry.expires > Instant::now()),
+            fingerprint,
+        )
+    }
+
+    /// Drops the expired responses, returning how many were dropped
+    ///
+    /// Requests still generating are kept, they remove themselves if they fail.
+    pub fn prune(&self) -> usize {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let before = entries.len();
+        entries.retain(|_, entry| entry.response.is_none() || entry.expires > now);
+        self.keys.lock().unwrap().retain(|_, key| key.expires > now);
+        before - entries.len()
+    }
+
+    /// Records that `api_key`, verified as `key_id` of `owner`, is generating a
+    /// request for `idempotency_key`
+    ///
+    /// Returns how the request made earlier with the key looks up when there's
+    /// one, otherwise a handle to complete with the response, which forgets the
+    /// request if dropped first.
+    pub fn begin(
+        &self,
+        api_key: &str,
+        key_id: &str,
+        owner: &str,
+        idempotency_key: &str,
+        fingerprint: [u8; 32],
+    ) -> Result<PendingResponse<'_>, Lookup> {
+        let hash = Self::hash(owner, idempotency_key);
+        let now = Instant::now();
+        self.keys.lock().unwrap().insert(
+            Sha256::digest(api_key.as_bytes()).into(),
+            KnownKey {
+                key_id: key_id.into(),
+                owner: owner.into(),
+                expires: now + self.ttl,
+            },
+        );
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| entry.expires > now);
+        if let Some(entry) = entries.get(&hash) {
+            return Err(entry_lookup(Some(entry), fingerprint));
+        }
+
+        entries.insert(
+            hash,
+            Entry {
+                fingerprint,
+                response: None,
+                expires: now + self.ttl,
+            },
+        );
+        Ok(PendingResponse {
+            store: self,
+            hash,
+            completed: false,
+        })
+    }
+}
+
+fn entry_lookup(entry: Option<&Entry>, fingerprint: [u8; 32]) -> Lookup {
+    match entry {
+        None => Lookup::Miss,
+        Some(entry) if entry.fingerprint != fingerprint => Lookup::Conflict,
+        Some(Entry { response: None, .. }) => Lookup::InProgress,
+        Some(Entry {
+            response: Some(response),
+            ..
+        }) => Lookup::Replay(response.clone()),
+    }
+}
+
+/// Request being generated under an idempotency key
+pub struct PendingResponse<'a> {
+    store: &'a IdempotencyStore,
+    hash: [u8; 32],
+    completed: bool,
+}
+
+impl PendingResponse<'_> {
+    /// Stores the response for the repeats of the request
+    pub fn complete(mut self, response: CachedResponse) {
+        let mut entries = self.store.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(&self.hash) {
+            entry.response = Some(response);
+        }
+        self.completed = true;
+    }
+}
+
+impl Drop for PendingResponse<'_> {
+    fn drop(&mut self) {
+        if !self.completed {
+            self.store.entries.lock().unwrap().remove(&self.hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::serde::json::json;
+
+    fn response() -> CachedResponse {
+        CachedResponse {
+            body: Either::Left(json!({ "image_url": "https://images.test/fox.png" })),
+            remaining: Some(3),
+        }
+    }
+
+    #[test]
+    fn replays_the_completed_response() {
+        let store = IdempotencyStore::new(Duration::from_secs(60));
+
+        let pending = store
+            .begin("sk_test", "key_test", "owner", "retry-1", [1; 32])
+            .unwrap();
+        assert!(matches!(
+            store.lookup("owner", "retry-1", [1; 32]),
+            Lookup::InProgress
+        ));
+        assert!(matches!(
+            store.begin("sk_test", "key_test", "owner", "retry-1", [1; 32]),
+            Err(Lookup::InProgress)
+        ));
+        pending.complete(response());
+
+        assert!(matches!(
+            store.lookup("owner", "retry-1", [1; 32]),
+            Lookup::Replay(CachedResponse {
+                remaining: Some(3),
+                ..
+            })
+        ));
+        assert!(matches!(
+            store.lookup("owner", "retry-1", [2; 32]),
+            Lookup::Conflict
+        ));
+        assert!(!store.contains("other", "retry-1"));
+    }
+
+    #[test]
+    fn shares_responses_between_the_keys_of_an_owner() {
+        let store = IdempotencyStore::new(Duration::from_secs(60));
+
+        store
+            .begin("sk_test", "key_test", "owner", "retry-1", [1; 32])
+            .unwrap()
+            .complete(response());
+
+        assert!(matches!(
+            store.begin("sk_rotated", "key_rotated", "owner", "retry-1", [1; 32]),
+            Err(Lookup::Replay(_))
+        ));
+        let known = store.known_key("sk_rotated").unwrap();
+        assert_eq!(known.key_id, "key_rotated");
+        assert_eq!(known.owner, "owner");
+        assert!(store.known_key("sk_other").is_none());
+    }
+
+    #[test]
+    fn forgets_requests_that_never_completed() {
+        let store = IdempotencyStore::new(Duration::from_secs(60));
+
+        drop(store.begin("sk_test", "key_test", "owner", "retry-1", [1; 32]));
+
+        assert!(matches!(
+            store.lookup("owner", "retry-1", [1; 32]),
+            Lookup::Miss
+        ));
+    }
+
+    #[test]
+    fn prunes_expired_responses_only() {
+        let store = IdempotencyStore::new(Duration::from_millis(10));
+
+        store
+            .begin("sk_test", "key_test", "owner", "retry-1", [1; 32])
+            .unwrap()
+            .complete(response());
+        let _pending = store
+            .begin("sk_test", "key_test", "owner", "retry-2", [2; 32])
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+
+        assert_eq!(store.prune(), 1);
+        assert_eq!(store.entries.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,52 @@
+//! Abstraction over the services generating images, so the handlers don't depend on OpenAI
+
+use rocket::serde::{Deserialize, Serialize};
+
+use crate::openai::{GeneratedImage, ImageRequest, OpenAIError, OpenAiClient};
+
+/// Error of any image provider
+///
+/// Providers map their errors onto OpenAI's variants, so the endpoints answer
+/// with the same statuses and codes whichever provider failed.
+pub type ImageError = OpenAIError;
+
+/// Service generating the images, selected by `image_provider` in the config
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum ImageProviderKind {
+    /// OpenAI or Azure OpenAI, as set by `openai_provider`
+    #[default]
+    OpenAI,
+    /// Stability AI, authenticated with `stability_api_key`
+    Stability,
+}
+
+/// Service the image endpoints generate images with
+///
+/// It's managed as `Box<dyn ImageProvider>`, so tests can swap in a fake one.
+#[rocket::async_trait]
+pub trait ImageProvider: Send + Sync {
+    /// Name of the provider in the dependency health check
+    fn name(&self) -> &'static str;
+
+    /// Generates the images described by `req`
+    async fn generate(&self, req: &ImageRequest<'_>) -> Result<Vec<GeneratedImage>, ImageError>;
+
+    /// Checks that the provider responds to our credentials
+    async fn check(&self) -> bool;
+}
+
+#[rocket::async_trait]
+impl ImageProvider for OpenAiClient {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    async fn generate(&self, req: &ImageRequest<'_>) -> Result<Vec<GeneratedImage>, ImageError> {
+        self.generate_image(req).await
+    }
+
+    async fn check(&self) -> bool {
+        OpenAiClient::check(self).await
+    }
+}
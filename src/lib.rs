@@ -0,0 +1,3729 @@
+use base64::prelude::{Engine, BASE64_STANDARD};
+use reqwest::Client;
+use rocket::data::ByteUnit;
+use rocket::fairing::{AdHoc, Fairing, Info, Kind};
+use rocket::figment::providers::Env;
+use rocket::figment::Figment;
+use rocket::form::Form;
+use rocket::futures::stream::{self, Stream, StreamExt};
+use rocket::http::{ContentType, Cookie, CookieJar, Header, MediaType, Method, SameSite, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::content::RawHtml;
+use rocket::response::stream::{ByteStream, Event, EventStream};
+use rocket::response::{self, Redirect, Responder};
+use rocket::serde::json;
+use rocket::serde::json::{Json, Value};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::time::{Date, Month, OffsetDateTime};
+use rocket::Either;
+use rocket::{catch, catchers, get, options, post, routes, uri, State};
+use rocket::{Build, Orbit, Response, Rocket, Route};
+use unkey::models::{ErrorCode, Ratelimit, RatelimitType, Refill, RefillInterval};
+use unkey::Client as UnkeyClient;
+
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+use tracing::Instrument;
+
+mod circuit_breaker;
+mod concurrency;
+#[cfg(feature = "persistence")]
+mod history;
+mod idempotency;
+mod image_provider;
+mod metrics;
+mod models;
+mod openai;
+mod pricing;
+mod rate_limit;
+mod secret;
+mod stability;
+mod unkey_service;
+mod verify_cache;
+mod webhook;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(test)]
+mod tests;
+
+// Private to the crate, the `testing` feature exposes them to integration tests
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+use concurrency::{OwnerConcurrencyLimiter, OwnerPermit};
+#[cfg(feature = "persistence")]
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+use history::{KeyHistory, KeyHistoryEntry};
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+use idempotency::{CachedResponse, IdempotencyStore, KnownKey, Lookup};
+#[cfg(any(test, feature = "testing"))]
+pub use image_provider::ImageError;
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+use image_provider::{ImageProvider, ImageProviderKind};
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+use metrics::Metrics;
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+use models::{
+    AdminVerifyBody, AuthorizeRequest, EstimateImageRequest, GenerateImageBatchRequest,
+    GenerateImageForm, GenerateImageRequest, GenerationEstimate, KeyCreateData, KeyDetailsData,
+    KeyInfoData, KeyListData, KeyUsageData, KeyVerificationData, KeyVerifyData, UpdateKeyBody,
+};
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+use openai::{
+    GeneratedImage, ImageContent, ImageRequest, OpenAIError, OpenAiClient, OpenAiProvider,
+};
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+use rate_limit::IpRateLimiter;
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+use secret::{Secret, SecretKey};
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+use stability::StabilityProvider;
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+use unkey_service::{UnkeyService, VerifyError};
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+use verify_cache::VerifyCache;
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+use webhook::Webhook;
+
+/// Application configuration loaded from Rocket's figment
+///
+/// Values come from `Rocket.toml`, `ROCKET_*` variables, and the plain
+/// environment variables listed in [`AppConfig::ENV_KEYS`]. Secrets are held
+/// as [`Secret`]s, so they stay redacted when the config is logged or served.
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct AppConfig {
+    // Secrets default to empty so that missing and empty ones are reported alike
+    #[serde(default)]
+    unkey_root_key: Secret,
+    #[serde(default)]
+    unkey_api_id: String,
+    /// Further Unkey APIs a request can select with `X-Unkey-Env`, by environment
+    /// name, e.g. `{staging="api_123"}`, requests without the header use `unkey_api_id`
+    #[serde(default)]
+    unkey_environments: HashMap<String, String>,
+    /// Lower-privilege Unkey root key used only to verify keys, defaults to `unkey_root_key`
+    unkey_verify_key: Option<Secret>,
+    /// Required unless images are generated by another provider without moderation
+    #[serde(default)]
+    openai_api_key: Secret,
+    /// Stability AI API key, required with the `stability` image provider
+    #[serde(default)]
+    stability_api_key: Secret,
+    /// Base URL of Stability's API
+    #[serde(default = "AppConfig::default_stability_base_url")]
+    stability_base_url: String,
+    /// Stability engine generating the images
+    #[serde(default = "AppConfig::default_stability_engine")]
+    stability_engine: String,
+    /// Whether to start without the secrets above, e.g. for local smoke tests,
+    /// accepts `1` as well as `true`
+    #[serde(
+        default,
+        deserialize_with = "rocket::figment::util::bool_from_str_or_int"
+    )]
+    allow_missing_secrets: bool,
+    /// Timeout for outgoing OpenAI requests, in seconds, long enough for an image
+    /// generation. Also read from `request_timeout_secs`, its former name.
+    #[serde(
+        default = "AppConfig::default_openai_timeout_secs",
+        alias = "request_timeout_secs"
+    )]
+    openai_timeout_secs: u64,
+    /// Timeout for outgoing Unkey and webhook requests, in seconds
+    #[serde(default = "AppConfig::default_unkey_timeout_secs")]
+    unkey_timeout_secs: u64,
+    /// Consecutive failed verifications after which Unkey isn't called for
+    /// `unkey_circuit_cooldown_secs`, never when 0
+    #[serde(default = "AppConfig::default_unkey_circuit_failure_threshold")]
+    unkey_circuit_failure_threshold: u32,
+    /// Seconds verifications fail fast once the circuit opened, before one probes Unkey
+    #[serde(default = "AppConfig::default_unkey_circuit_cooldown_secs")]
+    unkey_circuit_cooldown_secs: u64,
+    /// Whether `/authorize` and `/rotate` create keys, disable it when keys are
+    /// provisioned outside the app and only verified here
+    #[serde(default = "AppConfig::default_allow_key_creation")]
+    allow_key_creation: bool,
+    /// Whether `/authorize` rejects requests without a `User-Agent` header
+    #[serde(default)]
+    require_user_agent: bool,
+    /// Number of calls granted to newly created keys
+    #[serde(default = "AppConfig::default_remaining")]
+    default_remaining: usize,
+    /// Whether the session cookie is only sent over HTTPS. Disable it for local
+    /// development over plain HTTP, otherwise browsers will drop the cookie.
+    #[serde(default = "AppConfig::default_cookie_secure")]
+    cookie_secure: bool,
+    /// Name of the session cookie
+    #[serde(default = "AppConfig::default_cookie_name")]
+    cookie_name: String,
+    /// Path the session cookie is sent for, e.g. the subpath the app is mounted under
+    #[serde(default = "AppConfig::default_cookie_path")]
+    cookie_path: String,
+    /// Seconds until the session cookie expires, it lasts for the browser session when unset
+    cookie_max_age_secs: Option<u64>,
+    /// Maximum number of characters accepted in an image prompt
+    #[serde(default = "AppConfig::default_max_prompt_length")]
+    max_prompt_length: usize,
+    /// Text prepended verbatim to every prompt, e.g. a safety instruction
+    prompt_prefix: Option<String>,
+    /// Text appended verbatim to every prompt, e.g. a style like ", digital art"
+    prompt_suffix: Option<String>,
+    /// Base URL of the OpenAI API, e.g. to go through a proxy or gateway
+    #[serde(default = "AppConfig::default_openai_base_url")]
+    openai_base_url: String,
+    /// Service generating the images, `openai` or `stability`
+    #[serde(default)]
+    image_provider: ImageProviderKind,
+    /// API serving image generations, `openai` or `azure`
+    #[serde(default = "AppConfig::default_openai_provider")]
+    openai_provider: OpenAiProvider,
+    /// Azure OpenAI deployment of the image model, required with the `azure` provider
+    openai_deployment: Option<String>,
+    /// Azure OpenAI API version sent with each request
+    #[serde(default = "AppConfig::default_openai_api_version")]
+    openai_api_version: String,
+    /// Number of times a failed OpenAI request is retried
+    #[serde(default = "AppConfig::default_openai_max_retries")]
+    openai_max_retries: u32,
+    /// Delay before the first OpenAI retry, in milliseconds, doubled on each attempt
+    #[serde(default = "AppConfig::default_openai_retry_base_delay_ms")]
+    openai_retry_base_delay_ms: u64,
+    /// Image size generated when the request doesn't specify one
+    #[serde(default = "AppConfig::default_image_size")]
+    default_image_size: String,
+    /// Number of images generated when the request doesn't specify one
+    #[serde(default = "AppConfig::default_image_count")]
+    default_image_count: u8,
+    /// Image model used when the request doesn't specify one, OpenAI picks when unset
+    default_image_model: Option<String>,
+    /// Response format used when the request doesn't specify one
+    #[serde(default = "AppConfig::default_response_format")]
+    default_response_format: String,
+    /// Whether image generation returns placeholders instead of calling OpenAI
+    #[serde(default)]
+    dry_run: bool,
+    /// Whether calls are given back to keys when OpenAI fails to generate their images
+    #[serde(default = "AppConfig::default_refund_on_failure")]
+    refund_on_failure: bool,
+    /// Whether prompts are checked with OpenAI's moderation before generating images
+    #[serde(default)]
+    enable_moderation: bool,
+    /// Whether `/me/usage` serves the key's verifications from Unkey's analytics
+    #[serde(default)]
+    usage_analytics: bool,
+    /// Smallest JSON body in bytes compressed for clients sending `Accept-Encoding`
+    #[serde(default = "AppConfig::default_compression_min_size")]
+    compression_min_size: usize,
+    /// Origins whose images `/images/proxy` streams, e.g. where OpenAI hosts generated images
+    #[serde(default = "AppConfig::default_image_proxy_origins")]
+    image_proxy_origins: Vec<String>,
+    /// Browser origins allowed to call the API from another site
+    #[serde(default = "AppConfig::default_cors_allowed_origins")]
+    cors_allowed_origins: Vec<String>,
+    /// Seconds browsers may cache the answer to a CORS preflight request
+    #[serde(default = "AppConfig::default_cors_max_age_secs")]
+    cors_max_age_secs: u64,
+    /// How often the remaining calls of a key are refilled, `daily` or `monthly`,
+    /// keys aren't refilled when unset
+    refill_interval: Option<RefillInterval>,
+    /// Calls a key is refilled to every interval, defaults to `default_remaining`
+    refill_amount: Option<usize>,
+    /// Maximum burst of calls per key, enables Unkey's per-key ratelimit when set
+    ratelimit_limit: Option<usize>,
+    /// Calls added back to the ratelimit bucket every interval, defaults to the limit
+    ratelimit_refill_rate: Option<usize>,
+    /// Interval between ratelimit refills, in milliseconds
+    #[serde(default = "AppConfig::default_ratelimit_refill_interval_ms")]
+    ratelimit_refill_interval_ms: usize,
+    /// Ratelimit algorithm, `fast` or `consistent`
+    #[serde(default = "AppConfig::default_ratelimit_type")]
+    ratelimit_type: RatelimitType,
+    /// Prefix prepended to created keys, e.g. `img`, left out when empty
+    #[serde(default)]
+    key_prefix: String,
+    /// Random bytes of created keys, at least 16, Unkey's default of 16 when unset
+    #[serde(default, deserialize_with = "AppConfig::deserialize_key_byte_length")]
+    key_byte_length: Option<usize>,
+    /// Key granting access to admin endpoints via the `X-Admin-Key` header,
+    /// admin endpoints are disabled when unset
+    admin_root_key: Option<Secret>,
+    /// Permission the verified key must carry in Unkey, by route name, routes
+    /// left out require none
+    ///
+    /// Image generation requires `image:generate` by default, `{}` opts out.
+    #[serde(default = "AppConfig::default_required_permissions")]
+    required_permissions: HashMap<String, String>,
+    /// How long successful verifications of keys without a quota or an expiry are
+    /// cached, in milliseconds, caching is disabled when 0
+    ///
+    /// Cached keys skip Unkey's checks, so a revoked or disabled key keeps working
+    /// until its entry expires.
+    #[serde(default)]
+    verify_cache_ttl_ms: u64,
+    /// Calls to the authorize endpoint allowed per client IP and minute, unlimited when 0
+    #[serde(default = "AppConfig::default_authorize_rate_limit_per_minute")]
+    authorize_rate_limit_per_minute: u32,
+    /// Remaining calls under which responses carry `X-RateLimit-Warning`, never when 0
+    #[serde(default = "AppConfig::default_remaining_warning_threshold")]
+    remaining_warning_threshold: usize,
+    /// Image generations a key owner may run at the same time, unlimited when 0
+    #[serde(default = "AppConfig::default_max_concurrent_generations")]
+    max_concurrent_generations: usize,
+    /// Seconds a generation is replayed for repeats with its `Idempotency-Key`, disabled when 0
+    #[serde(default = "AppConfig::default_idempotency_ttl_secs")]
+    idempotency_ttl_secs: u64,
+    /// Seconds between sweeps dropping expired entries from the in-memory caches,
+    /// entries are then only dropped as new ones come in when 0
+    #[serde(default = "AppConfig::default_cache_prune_interval_secs")]
+    cache_prune_interval_secs: u64,
+    /// Milliseconds between startup checks while Unkey can't be reached
+    #[serde(default = "AppConfig::default_readiness_retry_interval_ms")]
+    readiness_retry_interval_ms: u64,
+    /// Whether to take the client IP from `X-Forwarded-For` or `X-Real-IP`,
+    /// only enable it behind a proxy that sets these headers
+    #[serde(default)]
+    trust_proxy: bool,
+    /// Number of trusted proxies appending to `X-Forwarded-For` in front of the app
+    #[serde(default = "AppConfig::default_trusted_proxy_hops")]
+    trusted_proxy_hops: usize,
+    /// URL receiving a JSON event whenever a key is created or deleted
+    webhook_url: Option<Secret>,
+    /// SQLite database recording created keys, e.g. `sqlite://key_history.db`
+    #[cfg(feature = "persistence")]
+    #[serde(default = "AppConfig::default_database_url")]
+    database_url: String,
+}
+
+impl AppConfig {
+    /// Environment variables read without the `ROCKET_` prefix
+    const ENV_KEYS: &'static [&'static str] = &[
+        "unkey_root_key",
+        "unkey_api_id",
+        "unkey_environments",
+        "unkey_verify_key",
+        "openai_api_key",
+        "allow_missing_secrets",
+        "openai_timeout_secs",
+        "request_timeout_secs",
+        "unkey_timeout_secs",
+        "unkey_circuit_failure_threshold",
+        "unkey_circuit_cooldown_secs",
+        "allow_key_creation",
+        "require_user_agent",
+        "default_remaining",
+        "cookie_secure",
+        "cookie_name",
+        "cookie_path",
+        "cookie_max_age_secs",
+        "max_prompt_length",
+        "prompt_prefix",
+        "prompt_suffix",
+        "openai_base_url",
+        "image_provider",
+        "stability_api_key",
+        "stability_base_url",
+        "stability_engine",
+        "openai_provider",
+        "openai_deployment",
+        "openai_api_version",
+        "openai_max_retries",
+        "openai_retry_base_delay_ms",
+        "default_image_size",
+        "default_image_count",
+        "default_image_model",
+        "default_response_format",
+        "dry_run",
+        "refund_on_failure",
+        "enable_moderation",
+        "usage_analytics",
+        "compression_min_size",
+        "image_proxy_origins",
+        "cors_allowed_origins",
+        "cors_max_age_secs",
+        "refill_interval",
+        "refill_amount",
+        "ratelimit_limit",
+        "ratelimit_refill_rate",
+        "ratelimit_refill_interval_ms",
+        "ratelimit_type",
+        "key_prefix",
+        "key_byte_length",
+        "admin_root_key",
+        "required_permissions",
+        "verify_cache_ttl_ms",
+        "authorize_rate_limit_per_minute",
+        "remaining_warning_threshold",
+        "max_concurrent_generations",
+        "idempotency_ttl_secs",
+        "cache_prune_interval_secs",
+        "readiness_retry_interval_ms",
+        "trust_proxy",
+        "trusted_proxy_hops",
+        "webhook_url",
+        "database_url",
+        // Read directly into Rocket's `limits.json` by `build_rocket`
+        "max_body_size",
+        // Read directly into Rocket's `shutdown.grace` by `build_rocket`
+        "shutdown_grace_secs",
+        // Rocket's own key encrypting the session cookie, required in release builds
+        "secret_key",
+        // Rocket's own settings, the first two sizing the Tokio runtime built by `main`
+        "workers",
+        "max_blocking",
+        "keep_alive",
+    ];
+
+    fn default_openai_timeout_secs() -> u64 {
+        30
+    }
+
+    fn default_unkey_timeout_secs() -> u64 {
+        5
+    }
+
+    fn default_unkey_circuit_failure_threshold() -> u32 {
+        5
+    }
+
+    fn default_unkey_circuit_cooldown_secs() -> u64 {
+        30
+    }
+
+    fn default_allow_key_creation() -> bool {
+        true
+    }
+
+    fn default_remaining() -> usize {
+        10
+    }
+
+    fn default_cookie_secure() -> bool {
+        true
+    }
+
+    fn default_cookie_name() -> String {
+        "unkey".into()
+    }
+
+    fn default_cookie_path() -> String {
+        "/".into()
+    }
+
+    fn default_refund_on_failure() -> bool {
+        true
+    }
+
+    fn default_max_prompt_length() -> usize {
+        1000
+    }
+
+    fn default_openai_base_url() -> String {
+        "https://api.openai.com/v1".into()
+    }
+
+    fn default_stability_base_url() -> String {
+        "https://api.stability.ai".into()
+    }
+
+    fn default_stability_engine() -> String {
+        "stable-diffusion-xl-1024-v1-0".into()
+    }
+
+    fn default_openai_provider() -> OpenAiProvider {
+        OpenAiProvider::OpenAI
+    }
+
+    fn default_openai_api_version() -> String {
+        "2024-02-01".into()
+    }
+
+    fn default_openai_max_retries() -> u32 {
+        3
+    }
+
+    fn default_openai_retry_base_delay_ms() -> u64 {
+        500
+    }
+
+    fn default_image_size() -> String {
+        "1024x1024".into()
+    }
+
+    fn default_image_count() -> u8 {
+        1
+    }
+
+    fn default_response_format() -> String {
+        "url".into()
+    }
+
+    fn default_required_permissions() -> HashMap<String, String> {
+        [
+            "generate_image",
+            "generate_image_form",
+            "generate_image_stream",
+            "generate_image_batch",
+        ]
+        .into_iter()
+        .map(|route| (route.into(), "image:generate".into()))
+        .collect()
+    }
+
+    fn default_image_proxy_origins() -> Vec<String> {
+        vec!["https://oaidalleapiprodscus.blob.core.windows.net".into()]
+    }
+
+    fn default_cors_allowed_origins() -> Vec<String> {
+        vec!["http://localhost:3000".into()]
+    }
+
+    fn default_cors_max_age_secs() -> u64 {
+        3600
+    }
+
+    fn default_ratelimit_refill_interval_ms() -> usize {
+        1000
+    }
+
+    fn default_remaining_warning_threshold() -> usize {
+        2
+    }
+
+    fn default_max_concurrent_generations() -> usize {
+        2
+    }
+
+    fn default_idempotency_ttl_secs() -> u64 {
+        3600
+    }
+
+    fn default_cache_prune_interval_secs() -> u64 {
+        60
+    }
+
+    fn default_readiness_retry_interval_ms() -> u64 {
+        5000
+    }
+
+    fn default_authorize_rate_limit_per_minute() -> u32 {
+        10
+    }
+
+    fn default_compression_min_size() -> usize {
+        1024
+    }
+
+    fn default_trusted_proxy_hops() -> usize {
+        1
+    }
+
+    #[cfg(feature = "persistence")]
+    fn default_database_url() -> String {
+        "sqlite://key_history.db".into()
+    }
+
+    fn default_ratelimit_type() -> RatelimitType {
+        RatelimitType::Fast
+    }
+
+    /// Reads `key_byte_length`, failing the config load below [`MIN_KEY_BYTE_LENGTH`]
+    fn deserialize_key_byte_length<'de, D>(deserializer: D) -> Result<Option<usize>, D::Error>
+    where
+        D: rocket::serde::Deserializer<'de>,
+    {
+        let length = Option::<usize>::deserialize(deserializer)?;
+        match length {
+            Some(length) if length < MIN_KEY_BYTE_LENGTH => {
+                Err(rocket::serde::de::Error::custom(format!(
+                    "key_byte_length must be at least {}, got {}",
+                    MIN_KEY_BYTE_LENGTH, length
+                )))
+            }
+            _ => Ok(length),
+        }
+    }
+
+    /// Returns the environment variables of the required secrets left empty
+    fn missing_secrets(&self) -> Vec<&'static str> {
+        let mut secrets = vec![
+            ("UNKEY_ROOT_KEY", self.unkey_root_key.expose()),
+            ("UNKEY_API_ID", &self.unkey_api_id),
+        ];
+        // OpenAI also moderates prompts for the other providers
+        if self.image_provider == ImageProviderKind::OpenAI || self.enable_moderation {
+            secrets.push(("OPENAI_API_KEY", self.openai_api_key.expose()));
+        }
+        if self.image_provider == ImageProviderKind::Stability {
+            secrets.push(("STABILITY_API_KEY", self.stability_api_key.expose()));
+        }
+        secrets
+            .into_iter()
+            .filter(|(_, value)| value.trim().is_empty())
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    /// Returns the refill to attach to new keys, if configured
+    fn refill(&self) -> Option<Refill> {
+        let interval = self.refill_interval.clone()?;
+        Some(Refill::new(
+            self.refill_amount.unwrap_or(self.default_remaining),
+            interval,
+        ))
+    }
+
+    /// Returns the per-key ratelimit to attach to new keys, if configured
+    fn ratelimit(&self) -> Option<Ratelimit> {
+        let limit = self.ratelimit_limit?;
+        Some(Ratelimit::new(
+            self.ratelimit_type.clone(),
+            self.ratelimit_refill_rate.unwrap_or(limit),
+            self.ratelimit_refill_interval_ms,
+            limit,
+        ))
+    }
+}
+
+/// Largest JSON body accepted when `MAX_BODY_SIZE` isn't set
+const DEFAULT_MAX_BODY_SIZE: ByteUnit = ByteUnit::Kibibyte(16);
+
+/// Image sizes accepted by OpenAI's image generation API
+const ALLOWED_IMAGE_SIZES: [&str; 5] =
+    ["256x256", "512x512", "1024x1024", "1024x1792", "1792x1024"];
+
+/// Image sizes supported by dall-e-2
+const DALL_E_2_SIZES: [&str; 3] = ["256x256", "512x512", "1024x1024"];
+
+/// Image sizes supported by dall-e-3
+const DALL_E_3_SIZES: [&str; 3] = ["1024x1024", "1792x1024", "1024x1792"];
+
+/// Image sizes accepted by Stability's SDXL engines
+const STABILITY_IMAGE_SIZES: [&str; 9] = [
+    "1024x1024",
+    "1152x896",
+    "896x1152",
+    "1216x832",
+    "832x1216",
+    "1344x768",
+    "768x1344",
+    "1536x640",
+    "640x1536",
+];
+
+/// Image models accepted by OpenAI's image generation API
+const ALLOWED_IMAGE_MODELS: [&str; 2] = ["dall-e-2", "dall-e-3"];
+
+/// Maximum number of images OpenAI generates per request
+const MAX_IMAGE_COUNT: u8 = 10;
+
+/// Response formats accepted by OpenAI's image generation API
+const ALLOWED_RESPONSE_FORMATS: [&str; 2] = ["url", "b64_json"];
+
+/// Number of keys per page when the admin key listing doesn't set a limit
+const DEFAULT_KEY_PAGE_SIZE: usize = 20;
+
+/// Seconds in-flight requests get to finish on shutdown, long enough for an image generation
+const DEFAULT_SHUTDOWN_GRACE_SECS: u32 = 30;
+
+/// Most prompts accepted by a single batch request
+const MAX_BATCH_PROMPTS: usize = 5;
+
+/// Most images of a batch generated at the same time
+const BATCH_CONCURRENCY: usize = 3;
+
+/// Fewest random bytes created keys may have, as weaker keys could be guessed
+const MIN_KEY_BYTE_LENGTH: usize = 16;
+
+/// Wait suggested to clients rate limited by OpenAI, whose limits are per minute
+const OPENAI_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Owner every key created without one used to share, telling no users apart
+const LEGACY_OWNER_ID: &str = "superuser";
+
+/// How long a generation over the owner's concurrency limit waits for a slot
+const OWNER_PERMIT_WAIT: Duration = Duration::from_secs(1);
+
+/// Time allowed to connect to Unkey, OpenAI or the webhook
+const HTTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of days `/me/usage` covers when no `start` is given
+const USAGE_DEFAULT_DAYS: u64 = 30;
+
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// Transparent 1x1 PNG returned as base64 by dry runs
+const DRY_RUN_IMAGE_B64: &str =
+    "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAQAAAC1HAwCAAAAC0lEQVR42mNkYAAAAAYAAjCB0C8AAAAASUVORK5CYII=";
+
+/// Interval between heartbeat events while a streamed image is generated
+const STREAM_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Error returned by the endpoints
+///
+/// Every variant is rendered as `{"error": {"code": "...", "message": "..."}}`
+/// with the matching HTTP status.
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+#[derive(Debug, Clone)]
+enum ApiError {
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    /// The request clashes with another one, e.g. reusing an idempotency key
+    Conflict(String),
+    /// The body isn't sent with a content type the endpoint accepts
+    UnsupportedMediaType(String),
+    /// The key has no remaining calls left
+    QuotaExceeded(String),
+    /// The key is making calls faster than its ratelimit allows
+    RateLimited(String),
+    /// The key is past its expiration date
+    KeyExpired(String),
+    /// The key was disabled in Unkey
+    KeyDisabled(String),
+    BadGateway(String),
+    /// An upstream service didn't answer in time
+    GatewayTimeout(String),
+    /// A dependency is down, so the request may succeed later
+    ServiceUnavailable(String),
+    Internal(String),
+    /// The feature isn't enabled on this deployment
+    NotImplemented(String),
+    /// The prompt was flagged by OpenAI's moderation, holding the flagged categories
+    ContentFlagged(Vec<String>),
+    /// Error reported by OpenAI that is safe to pass on to the caller
+    OpenAI {
+        status: Status,
+        code: String,
+        message: String,
+    },
+    /// Error without a specific message, e.g. from Rocket's own routing
+    Other(Status),
+    /// Error the client may retry after the given seconds, sent as `Retry-After`
+    RetryAfter(Box<ApiError>, u64),
+}
+
+impl ApiError {
+    /// Tells the client to retry after `wait`, rounded up to whole seconds
+    fn retry_after(self, wait: Duration) -> Self {
+        let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+        ApiError::RetryAfter(Box::new(self), secs.max(1))
+    }
+
+    /// Error for a key out of calls, retried once its refill is due
+    fn quota_exceeded(message: impl Into<String>, refill: Option<&RefillInterval>) -> Self {
+        let error = ApiError::QuotaExceeded(message.into());
+        match refill {
+            Some(refill) => error.retry_after(until_refill(refill)),
+            // Retrying never helps a key whose calls aren't refilled
+            None => error,
+        }
+    }
+
+    /// Error for a key over its ratelimit, retried once its window resets
+    fn rate_limited(data: &KeyVerifyData) -> Self {
+        let wait = data.ratelimit_reset.map_or(Duration::ZERO, |reset| {
+            Duration::from_millis((reset as u64).saturating_sub(now_ms()))
+        });
+        ApiError::RateLimited("Rate limit exceeded, slow down.".into()).retry_after(wait)
+    }
+
+    /// Maps a failed verification to the error matching Unkey's reason
+    fn from_verification(data: &KeyVerifyData) -> Self {
+        let quota_exceeded = || {
+            ApiError::quota_exceeded(
+                "Quota exceeded, the key has no remaining calls.",
+                data.refill_interval.as_ref(),
+            )
+        };
+        match data.code {
+            Some(ErrorCode::UsageExceeded) => quota_exceeded(),
+            Some(ErrorCode::RateLimited) => ApiError::rate_limited(data),
+            Some(ErrorCode::Expired) => ApiError::KeyExpired("The API key has expired.".into()),
+            Some(ErrorCode::Disabled) => {
+                ApiError::KeyDisabled("The API key has been disabled.".into())
+            }
+            Some(ErrorCode::Forbidden) => {
+                ApiError::Forbidden("The API key is not allowed to make this call.".into())
+            }
+            // Fall back on the counters when Unkey gives no usable reason
+            _ if data.remaining == Some(0) => quota_exceeded(),
+            _ if data.ratelimit_remaining == Some(0) => ApiError::rate_limited(data),
+            _ => ApiError::Unauthorized("Invalid API key.".into()),
+        }
+    }
+
+    /// Returns the HTTP status of the error
+    fn status(&self) -> Status {
+        match self {
+            ApiError::BadRequest(_) => Status::BadRequest,
+            ApiError::Unauthorized(_) | ApiError::KeyExpired(_) => Status::Unauthorized,
+            ApiError::Forbidden(_) | ApiError::KeyDisabled(_) => Status::Forbidden,
+            ApiError::NotFound(_) => Status::NotFound,
+            ApiError::Conflict(_) => Status::Conflict,
+            ApiError::UnsupportedMediaType(_) => Status::UnsupportedMediaType,
+            ApiError::QuotaExceeded(_) | ApiError::RateLimited(_) => Status::TooManyRequests,
+            ApiError::BadGateway(_) => Status::BadGateway,
+            ApiError::GatewayTimeout(_) => Status::GatewayTimeout,
+            ApiError::ServiceUnavailable(_) => Status::ServiceUnavailable,
+            ApiError::Internal(_) => Status::InternalServerError,
+            ApiError::NotImplemented(_) => Status::NotImplemented,
+            ApiError::ContentFlagged(_) => Status::UnprocessableEntity,
+            ApiError::OpenAI { status, .. } => *status,
+            ApiError::Other(status) => *status,
+            ApiError::RetryAfter(error, _) => error.status(),
+        }
+    }
+
+    /// Returns the machine-readable code of the error
+    fn code(&self) -> String {
+        match self {
+            ApiError::OpenAI { code, .. } => code.clone(),
+            ApiError::RetryAfter(error, _) => error.code(),
+            // Both share the 429 status, so tell them apart by code
+            ApiError::QuotaExceeded(_) => "quota_exceeded".into(),
+            ApiError::RateLimited(_) => "rate_limited".into(),
+            ApiError::KeyExpired(_) => "key_expired".into(),
+            ApiError::KeyDisabled(_) => "key_disabled".into(),
+            ApiError::ContentFlagged(_) => "content_flagged".into(),
+            _ => self
+                .status()
+                .reason_lossy()
+                .to_lowercase()
+                .replace(' ', "_"),
+        }
+    }
+
+    /// Returns the human-readable message of the error
+    fn message(&self) -> &str {
+        match self {
+            ApiError::BadRequest(message)
+            | ApiError::Unauthorized(message)
+            | ApiError::Forbidden(message)
+            | ApiError::NotFound(message)
+            | ApiError::Conflict(message)
+            | ApiError::UnsupportedMediaType(message)
+            | ApiError::QuotaExceeded(message)
+            | ApiError::RateLimited(message)
+            | ApiError::KeyExpired(message)
+            | ApiError::KeyDisabled(message)
+            | ApiError::BadGateway(message)
+            | ApiError::GatewayTimeout(message)
+            | ApiError::ServiceUnavailable(message)
+            | ApiError::Internal(message)
+            | ApiError::NotImplemented(message)
+            | ApiError::OpenAI { message, .. } => message,
+            ApiError::ContentFlagged(_) => "The prompt was flagged by content moderation.",
+            ApiError::Other(status) => status.reason_lossy(),
+            ApiError::RetryAfter(error, _) => error.message(),
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        if let ApiError::RetryAfter(error, secs) = self {
+            let mut res = error.respond_to(req)?;
+            res.set_header(Header::new("Retry-After", secs.to_string()));
+            return Ok(res);
+        }
+
+        let mut body = json::json!({
+            "error": {
+                "code": self.code(),
+                "message": self.message()
+            }
+        });
+        if let ApiError::ContentFlagged(categories) = &self {
+            body["error"]["categories"] = categories.clone().into();
+        }
+        (self.status(), Json(body)).respond_to(req)
+    }
+}
+
+impl From<VerifyError> for ApiError {
+    fn from(error: VerifyError) -> Self {
+        match error {
+            VerifyError::Unavailable(_) => ApiError::ServiceUnavailable(
+                "Unable to verify the API key right now, try again later.".into(),
+            ),
+            VerifyError::Rejected(_) => ApiError::Unauthorized("Invalid API key.".into()),
+        }
+    }
+}
+
+impl From<OpenAIError> for ApiError {
+    fn from(error: OpenAIError) -> Self {
+        // Only errors about the user's own request are safe to pass through verbatim
+        match error {
+            OpenAIError::ContentPolicy(body) => ApiError::OpenAI {
+                status: Status::UnprocessableEntity,
+                code: body
+                    .code
+                    .unwrap_or_else(|| "content_policy_violation".into()),
+                message: body.message,
+            },
+            OpenAIError::RateLimited(body) => ApiError::OpenAI {
+                status: Status::TooManyRequests,
+                code: body.code.unwrap_or_else(|| "rate_limit_exceeded".into()),
+                message: body.message,
+            }
+            .retry_after(OPENAI_RETRY_AFTER),
+            OpenAIError::InvalidRequest(body) => ApiError::OpenAI {
+                status: Status::BadRequest,
+                code: body.code.unwrap_or_else(|| "invalid_request".into()),
+                message: body.message,
+            },
+            OpenAIError::NoImage => {
+                ApiError::BadGateway("The image provider returned no image for the prompt.".into())
+            }
+            OpenAIError::Timeout => ApiError::GatewayTimeout(
+                "The image provider took too long to generate the image.".into(),
+            ),
+            OpenAIError::Api(..) | OpenAIError::Other(_) => {
+                ApiError::Internal("Unable to generate the image.".into())
+            }
+        }
+    }
+}
+
+/// Request guard for the caller's API key
+///
+/// The key is read from an `Authorization: Bearer <key>` header, falling back
+/// to the session cookie set by the authorize endpoint.
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+struct ApiKey(SecretKey);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApiKey {
+    type Error = ApiError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if let Some(header) = req.headers().get_one("Authorization") {
+            return match header.strip_prefix("Bearer ").map(str::trim) {
+                Some(key) if !key.is_empty() => Outcome::Success(ApiKey(key.into())),
+                _ => guard_error(
+                    req,
+                    ApiError::Unauthorized("Malformed Authorization header.".into()),
+                ),
+            };
+        }
+
+        // Check for the presence of the session cookie
+        let Some(config) = req.rocket().state::<AppConfig>() else {
+            return guard_error(req, ApiError::Internal("Missing managed state.".into()));
+        };
+        if req.cookies().get(&config.cookie_name).is_none() {
+            return guard_error(
+                req,
+                ApiError::Unauthorized(
+                    "Missing API key in Authorization header or cookies.".into(),
+                ),
+            );
+        }
+
+        match parse_session(req.cookies(), config) {
+            Ok(data) => Outcome::Success(ApiKey(data.key)),
+            Err(e) => guard_error(req, e),
+        }
+    }
+}
+
+/// Request guard for the ID of the Unkey API the request's keys belong to
+///
+/// The `X-Unkey-Env` header selects one of `unkey_environments`, requests
+/// without it use `unkey_api_id`.
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+struct UnkeyApi(String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UnkeyApi {
+    type Error = ApiError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(config) = req.rocket().state::<AppConfig>() else {
+            return guard_error(req, ApiError::Internal("Missing managed state.".into()));
+        };
+
+        let Some(env) = req.headers().get_one("X-Unkey-Env") else {
+            return Outcome::Success(UnkeyApi(config.unkey_api_id.clone()));
+        };
+        match config.unkey_environments.get(env) {
+            Some(api_id) => Outcome::Success(UnkeyApi(api_id.clone())),
+            None => guard_error(
+                req,
+                ApiError::BadRequest(format!("Unknown Unkey environment `{}`.", env)),
+            ),
+        }
+    }
+}
+
+/// Request guard for a caller whose API key was successfully verified with Unkey
+///
+/// Verification counts against the key's remaining calls, so only protected
+/// endpoints that consume quota should use this guard.
+/// Routes listed in `required_permissions` also require the key to carry the
+/// permission configured for them.
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+struct VerifiedKey {
+    key: SecretKey,
+    key_id: String,
+    owner_id: Option<String>,
+    remaining: Option<usize>,
+    refill_interval: Option<RefillInterval>,
+}
+
+impl VerifiedKey {
+    /// Returns the owner of the key, keys without one being their own owner
+    ///
+    /// Keys of the former shared [`LEGACY_OWNER_ID`] count as their own owner too.
+    fn owner(&self) -> &str {
+        self.owner_id
+            .as_deref()
+            .filter(|&owner_id| owner_id != LEGACY_OWNER_ID)
+            .unwrap_or(&self.key_id)
+    }
+}
+
+/// Request guard for the caller's API key, along with what verifying it takes
+///
+/// Unlike [`VerifiedKey`], it doesn't verify the key, handlers call
+/// [`UnverifiedKey::verify`] once their input is valid so invalid requests don't
+/// cost a call.
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+struct UnverifiedKey<'r> {
+    key: SecretKey,
+    api: String,
+    /// Permission the route requires, if any
+    permission: Option<&'r str>,
+    owner: &'r KeyOwner,
+    request_id: &'r RequestId,
+}
+
+impl UnverifiedKey<'_> {
+    /// Verifies the key with Unkey, spending one of its calls
+    ///
+    /// Rejections of exhausted keys carry their remaining calls for the response headers.
+    async fn verify(self, unkey: &UnkeyService) -> Result<VerifiedKey, WithRemaining<ApiError>> {
+        let result = unkey
+            .verify(&self.api, &self.key, self.permission)
+            .instrument(self.request_id.span())
+            .await;
+        match result {
+            Ok(data) if data.valid => {
+                if let Some(owner_id) = &data.owner_id {
+                    self.owner.0.set(owner_id.clone()).ok();
+                }
+                Ok(VerifiedKey {
+                    key: self.key,
+                    key_id: data.key_id.unwrap_or_default(),
+                    owner_id: data.owner_id,
+                    remaining: data.remaining,
+                    refill_interval: data.refill_interval,
+                })
+            }
+            Ok(data) => {
+                let error = match (&data.code, self.permission) {
+                    (Some(ErrorCode::Forbidden), Some(permission)) => ApiError::Forbidden(format!(
+                        "The API key lacks the `{}` permission.",
+                        permission
+                    )),
+                    _ => ApiError::from_verification(&data),
+                };
+                Err(WithRemaining(error, data.remaining.filter(|&n| n == 0)))
+            }
+            Err(e) => Err(WithRemaining(e.into(), None)),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UnverifiedKey<'r> {
+    type Error = ApiError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let api_key = match req.guard::<ApiKey>().await {
+            Outcome::Success(api_key) => api_key,
+            Outcome::Error(e) => return Outcome::Error(e),
+            Outcome::Forward(status) => return Outcome::Forward(status),
+        };
+        let api = match req.guard::<UnkeyApi>().await {
+            Outcome::Success(api) => api,
+            Outcome::Error(e) => return Outcome::Error(e),
+            Outcome::Forward(status) => return Outcome::Forward(status),
+        };
+        let Some(config) = req.rocket().state::<AppConfig>() else {
+            return guard_error(req, ApiError::Internal("Missing managed state.".into()));
+        };
+
+        let permission = req
+            .route()
+            .and_then(|route| route.name.as_deref())
+            .and_then(|name| config.required_permissions.get(name))
+            .map(String::as_str);
+        Outcome::Success(UnverifiedKey {
+            key: api_key.0,
+            api: api.0,
+            permission,
+            owner: req.local_cache(KeyOwner::default),
+            request_id: req.local_cache(RequestId::new),
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for VerifiedKey {
+    type Error = ApiError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let key = match req.guard::<UnverifiedKey<'_>>().await {
+            Outcome::Success(key) => key,
+            Outcome::Error(e) => return Outcome::Error(e),
+            Outcome::Forward(status) => return Outcome::Forward(status),
+        };
+        let Some(unkey) = req.rocket().state::<UnkeyService>() else {
+            return guard_error(req, ApiError::Internal("Missing managed state.".into()));
+        };
+
+        // Verify the key, along with the permission the route requires, if any
+        match key.verify(unkey).await {
+            Ok(key) => Outcome::Success(key),
+            Err(WithRemaining(error, remaining)) => {
+                req.local_cache(|| RemainingCalls(remaining));
+                guard_error(req, error)
+            }
+        }
+    }
+}
+
+/// Request guard for callers presenting the configured admin key in `X-Admin-Key`
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ApiError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let admin_root_key = req
+            .rocket()
+            .state::<AppConfig>()
+            .and_then(|config| config.admin_root_key.as_ref())
+            .map(Secret::expose);
+
+        match (admin_root_key, req.headers().get_one("X-Admin-Key")) {
+            // Compared in constant time, so the key can't be guessed from response times
+            (Some(expected), Some(given))
+                if bool::from(expected.as_bytes().ct_eq(given.as_bytes())) =>
+            {
+                Outcome::Success(Admin)
+            }
+            _ => guard_error(req, ApiError::Forbidden("Admin key required.".into())),
+        }
+    }
+}
+
+/// Request guard for the IP address of the client
+///
+/// With `trust_proxy` set, the address is read from `X-Forwarded-For`, skipping
+/// the entries appended by the trusted proxies, or from `X-Real-IP` when the
+/// proxies don't send `X-Forwarded-For`. It otherwise falls back to the address
+/// of the peer, also when `X-Forwarded-For` holds too few entries to be trusted.
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+#[derive(Debug, Clone, Copy)]
+struct ClientIp(IpAddr);
+
+impl ClientIp {
+    fn of(req: &Request<'_>) -> Self {
+        let config = req
+            .rocket()
+            .state::<AppConfig>()
+            .filter(|config| config.trust_proxy);
+        let proxied_ip = config.and_then(|config| {
+            // Each trusted proxy appends its peer, so the client is the entry
+            // `trusted_proxy_hops` from the right, anything before may be spoofed
+            let Some(header) = req.headers().get_one("X-Forwarded-For") else {
+                return req
+                    .headers()
+                    .get_one("X-Real-IP")
+                    .and_then(|ip| ip.trim().parse().ok());
+            };
+            let forwarded: Vec<&str> = header.split(',').map(str::trim).collect();
+            forwarded
+                .len()
+                .checked_sub(config.trusted_proxy_hops.max(1))
+                .and_then(|index| forwarded[index].parse().ok())
+        });
+
+        ClientIp(
+            proxied_ip
+                .or_else(|| req.remote().map(|remote| remote.ip()))
+                .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        )
+    }
+}
+
+impl std::fmt::Display for ClientIp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientIp {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(ClientIp::of(req))
+    }
+}
+
+/// Request guard limiting how often a client IP may call the authorize endpoint
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+struct AuthorizeRateLimit;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthorizeRateLimit {
+    type Error = ApiError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(limiter) = req.rocket().state::<IpRateLimiter>() else {
+            return guard_error(req, ApiError::Internal("Missing managed state.".into()));
+        };
+
+        let ClientIp(ip) = ClientIp::of(req);
+        match limiter.try_acquire(ip) {
+            Ok(()) => Outcome::Success(AuthorizeRateLimit),
+            Err(wait) => guard_error(
+                req,
+                ApiError::RateLimited("Too many authorize requests, try again later.".into())
+                    .retry_after(wait),
+            ),
+        }
+    }
+}
+
+/// Request guard rejecting requests without a `User-Agent` header, when required
+///
+/// A cheap filter for naive scrapers, which often don't send one.
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+struct RequireUserAgent;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequireUserAgent {
+    type Error = ApiError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(config) = req.rocket().state::<AppConfig>() else {
+            return guard_error(req, ApiError::Internal("Missing managed state.".into()));
+        };
+
+        let user_agent = req.headers().get_one("User-Agent").map(str::trim);
+        if !config.require_user_agent || user_agent.is_some_and(|ua| !ua.is_empty()) {
+            return Outcome::Success(RequireUserAgent);
+        }
+        guard_error(
+            req,
+            ApiError::BadRequest("The request must send a `User-Agent` header.".into()),
+        )
+    }
+}
+
+/// Remaining calls of a rejected key for the catcher to report
+struct RemainingCalls(Option<usize>);
+
+/// Responder wrapper setting the `X-RateLimit-Remaining` header when the
+/// remaining calls are known, unlimited keys get no header
+///
+/// `X-RateLimit-Warning: true` is added once the remaining calls fall below
+/// `remaining_warning_threshold`.
+struct WithRemaining<R>(R, Option<usize>);
+
+impl From<ApiError> for WithRemaining<ApiError> {
+    fn from(error: ApiError) -> Self {
+        WithRemaining(error, None)
+    }
+}
+
+impl<'r, 'o: 'r, R: Responder<'r, 'o>> Responder<'r, 'o> for WithRemaining<R> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'o> {
+        let mut res = self.0.respond_to(req)?;
+        if let Some(remaining) = self.1 {
+            res.set_header(Header::new("X-RateLimit-Remaining", remaining.to_string()));
+            let threshold = req
+                .rocket()
+                .state::<AppConfig>()
+                .map_or(0, |config| config.remaining_warning_threshold);
+            if remaining < threshold {
+                res.set_header(Header::new("X-RateLimit-Warning", "true"));
+            }
+        }
+        Ok(res)
+    }
+}
+
+/// Image streamed from its host, with the host's content type and length
+struct ProxiedImage<S> {
+    content_type: ContentType,
+    length: Option<u64>,
+    body: ByteStream<S>,
+}
+
+impl<'r, S> Responder<'r, 'r> for ProxiedImage<S>
+where
+    S: Stream + Send + 'r,
+    S::Item: AsRef<[u8]> + Send + Unpin + 'r,
+{
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'r> {
+        let mut res = self.body.respond_to(req)?;
+        res.set_header(self.content_type);
+        // Streamed bodies are sent chunked unless their length is known upfront
+        if let Some(length) = self.length {
+            res.set_header(Header::new("Content-Length", length.to_string()));
+        }
+        Ok(res)
+    }
+}
+
+/// HTTP client fetching the images streamed by the image proxy
+struct ImageProxyClient(Client);
+
+/// Error left by a failing request guard for the catcher to respond with
+struct GuardError(Option<ApiError>);
+
+/// Helper function to fail a request guard with an error
+fn guard_error<T>(req: &Request<'_>, error: ApiError) -> Outcome<T, ApiError> {
+    req.local_cache(|| GuardError(Some(error.clone())));
+    Outcome::Error((error.status(), error))
+}
+
+/// Fairing adding CORS headers for the configured front-end origins
+struct Cors;
+
+#[rocket::async_trait]
+impl Fairing for Cors {
+    fn info(&self) -> Info {
+        Info {
+            name: "CORS",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let (Some(origin), Some(config)) = (
+            req.headers().get_one("Origin"),
+            req.rocket().state::<AppConfig>(),
+        ) else {
+            return;
+        };
+        if !config.cors_allowed_origins.iter().any(|o| o == origin) {
+            return;
+        }
+
+        res.set_header(Header::new(
+            "Access-Control-Allow-Origin",
+            origin.to_string(),
+        ));
+        res.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
+        res.set_header(Header::new("Vary", "Origin"));
+        // Only preflights ask for a method, other OPTIONS requests get the headers above
+        let preflight = req.method() == Method::Options
+            && req.headers().contains("Access-Control-Request-Method");
+        if preflight {
+            res.set_header(Header::new(
+                "Access-Control-Allow-Methods",
+                "GET, POST, OPTIONS",
+            ));
+            res.set_header(Header::new(
+                "Access-Control-Allow-Headers",
+                "Content-Type, Authorization, X-Request-Id, X-Unkey-Env",
+            ));
+            res.set_header(Header::new(
+                "Access-Control-Max-Age",
+                config.cors_max_age_secs.to_string(),
+            ));
+        }
+    }
+}
+
+/// Fairing compressing JSON responses with gzip or deflate, as the client accepts
+///
+/// Bodies under `compression_min_size` aren't worth compressing, and streamed
+/// bodies like server-sent events and binary images are left untouched.
+struct Compression;
+
+#[rocket::async_trait]
+impl Fairing for Compression {
+    fn info(&self) -> Info {
+        Info {
+            name: "Compression",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let Some(config) = req.rocket().state::<AppConfig>() else {
+            return;
+        };
+        let compressible = res.content_type().is_some_and(|ct| ct.is_json())
+            && !res.headers().contains("Content-Encoding")
+            && res
+                .body()
+                .preset_size()
+                .is_some_and(|size| size >= config.compression_min_size);
+        if !compressible {
+            return;
+        }
+        res.adjoin_header(Header::new("Vary", "Accept-Encoding"));
+
+        let Some(encoding) = req
+            .headers()
+            .get_one("Accept-Encoding")
+            .and_then(negotiate_encoding)
+        else {
+            return;
+        };
+        let body = match res.body_mut().to_bytes().await {
+            Ok(body) => body,
+            Err(e) => {
+                tracing::error!(error = ?e, "failed to read response body to compress");
+                return;
+            }
+        };
+        match compress(encoding, &body) {
+            Ok(compressed) => {
+                res.set_header(Header::new("Content-Encoding", encoding));
+                res.set_sized_body(compressed.len(), std::io::Cursor::new(compressed));
+            }
+            Err(e) => {
+                tracing::error!(error = ?e, encoding, "failed to compress response body");
+                res.set_sized_body(body.len(), std::io::Cursor::new(body));
+            }
+        }
+    }
+}
+
+/// Returns the encoding to compress with from an `Accept-Encoding` header,
+/// preferring gzip and skipping encodings refused with `q=0`
+fn negotiate_encoding(header: &str) -> Option<&'static str> {
+    let accepted: Vec<&str> = header
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';').map(str::trim);
+            let name = parts.next()?;
+            let refused = parts.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            (!refused).then_some(name)
+        })
+        .collect();
+    ["gzip", "deflate"].into_iter().find(|encoding| {
+        accepted
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case(encoding) || *name == "*")
+    })
+}
+
+/// Compresses `body` with gzip or deflate
+fn compress(encoding: &str, body: &[u8]) -> std::io::Result<Vec<u8>> {
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use std::io::Write;
+
+    let level = flate2::Compression::default();
+    if encoding == "gzip" {
+        let mut encoder = GzEncoder::new(Vec::new(), level);
+        encoder.write_all(body)?;
+        encoder.finish()
+    } else {
+        // HTTP's deflate is the zlib format
+        let mut encoder = ZlibEncoder::new(Vec::new(), level);
+        encoder.write_all(body)?;
+        encoder.finish()
+    }
+}
+
+/// Correlation id assigned to every request and logged at each step
+///
+/// An incoming `X-Request-Id` header is reused so ids can be followed across
+/// services, otherwise a random UUID is generated.
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+#[derive(Clone, Debug)]
+struct RequestId {
+    id: String,
+    started: Instant,
+}
+
+impl RequestId {
+    /// Maximum length of an incoming request id we're willing to log
+    const MAX_LEN: usize = 128;
+
+    fn new() -> Self {
+        RequestId {
+            id: uuid::Uuid::new_v4().to_string(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Reuses the `X-Request-Id` header of the request when it looks sane
+    fn from_request(req: &Request<'_>) -> Self {
+        match req.headers().get_one("X-Request-Id") {
+            Some(id)
+                if !id.is_empty()
+                    && id.len() <= Self::MAX_LEN
+                    && id.chars().all(|c| c.is_ascii_graphic()) =>
+            {
+                RequestId {
+                    id: id.to_string(),
+                    started: Instant::now(),
+                }
+            }
+            _ => RequestId::new(),
+        }
+    }
+
+    /// Returns a span tagging the events logged within it with the request id
+    fn span(&self) -> tracing::Span {
+        tracing::info_span!("request", request_id = %self.id)
+    }
+}
+
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.id)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestId {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(req.local_cache(RequestId::new).clone())
+    }
+}
+
+/// Request guard telling whether the client prefers the raw image with `Accept: image/png`
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+struct AcceptsPng(bool);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AcceptsPng {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let png = req
+            .accept()
+            .is_some_and(|accept| accept.preferred().media_type() == &MediaType::PNG);
+        Outcome::Success(AcceptsPng(png))
+    }
+}
+
+/// Request guard requiring a JSON body
+///
+/// Routes declaring `format = "json"` answer other content types with a 404,
+/// this guard rejects them with a 415 the client can act on instead.
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+struct JsonContentType;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for JsonContentType {
+    type Error = ApiError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if req.content_type().is_some_and(|ct| ct.is_json()) {
+            return Outcome::Success(JsonContentType);
+        }
+        guard_error(
+            req,
+            ApiError::UnsupportedMediaType(
+                "The request body must be JSON, sent with `Content-Type: application/json`.".into(),
+            ),
+        )
+    }
+}
+
+/// Maximum length of an `Idempotency-Key` header
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+/// Request guard for the optional `Idempotency-Key` header
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+struct IdempotencyKey(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IdempotencyKey {
+    type Error = ApiError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match req.headers().get_one("Idempotency-Key").map(str::trim) {
+            None | Some("") => Outcome::Success(IdempotencyKey(None)),
+            Some(key) if key.len() > MAX_IDEMPOTENCY_KEY_LEN => guard_error(
+                req,
+                ApiError::BadRequest(format!(
+                    "The Idempotency-Key header is longer than {} characters.",
+                    MAX_IDEMPOTENCY_KEY_LEN
+                )),
+            ),
+            Some(key) => Outcome::Success(IdempotencyKey(Some(key.to_string()))),
+        }
+    }
+}
+
+/// Request guard for a repeated `Idempotency-Key`, forwarding when the key is new
+///
+/// It only looks the API key up among the keys that made requests, without
+/// verifying it, so replays don't cost a call.
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+struct IdempotentReplay {
+    key: KnownKey,
+    idempotency_key: String,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IdempotentReplay {
+    type Error = ApiError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Outcome::Success(IdempotencyKey(Some(idempotency_key))) =
+            req.guard::<IdempotencyKey>().await
+        else {
+            return Outcome::Forward(Status::NotFound);
+        };
+        let (Some(store), Outcome::Success(ApiKey(api_key))) = (
+            req.rocket().state::<IdempotencyStore>(),
+            req.guard::<ApiKey>().await,
+        ) else {
+            return Outcome::Forward(Status::NotFound);
+        };
+
+        match store.known_key(api_key.expose()) {
+            Some(key) if store.is_enabled() && store.contains(&key.owner, &idempotency_key) => {
+                Outcome::Success(IdempotentReplay {
+                    key,
+                    idempotency_key,
+                })
+            }
+            _ => Outcome::Forward(Status::NotFound),
+        }
+    }
+}
+
+/// Fairing assigning each request its [`RequestId`] and logging it with the latency
+///
+/// The id is also returned to the client in the `X-Request-Id` header.
+struct RequestTracing;
+
+#[rocket::async_trait]
+impl Fairing for RequestTracing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request tracing",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut rocket::Data<'_>) {
+        let request_id = req.local_cache(|| RequestId::from_request(req));
+        tracing::debug!(
+            request_id = %request_id,
+            client_ip = %ClientIp::of(req),
+            method = %req.method(),
+            uri = %req.uri(),
+            "request started"
+        );
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let request_id = req.local_cache(RequestId::new);
+        res.set_header(Header::new("X-Request-Id", request_id.id.clone()));
+        tracing::debug!(
+            request_id = %request_id,
+            status = res.status().code,
+            latency_ms = request_id.started.elapsed().as_millis() as u64,
+            "request finished"
+        );
+    }
+}
+
+/// Owner of the key verified for a request, cached for the access log
+#[derive(Default)]
+struct KeyOwner(OnceLock<String>);
+
+/// Fairing writing one `access` event per request, e.g. for log aggregation
+///
+/// It's the only event logged at info level for every request, the other
+/// fairings log theirs at debug level. With `LOG_FORMAT=json` each event is a
+/// single JSON line.
+struct AccessLog;
+
+#[rocket::async_trait]
+impl Fairing for AccessLog {
+    fn info(&self) -> Info {
+        Info {
+            name: "Access log",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let request_id = req.local_cache(RequestId::new);
+        let owner_id = req
+            .local_cache(KeyOwner::default)
+            .0
+            .get()
+            .map(String::as_str);
+        tracing::info!(
+            target: "access",
+            request_id = %request_id,
+            method = %req.method(),
+            path = %req.uri().path(),
+            status = res.status().code,
+            duration_ms = request_id.started.elapsed().as_millis() as u64,
+            client_ip = %ClientIp::of(req),
+            route = req.route().map_or("unmatched", |route| route.uri.path()),
+            owner_id,
+            "access"
+        );
+    }
+}
+
+/// Fairing timing each request by route for the metrics endpoint
+///
+/// Requests are labelled with the template of the matched route, e.g.
+/// `/keys/<key_id>`, so IDs in URIs don't each get their own series. Requests
+/// matching no route share the `unmatched` label.
+struct RouteLatency;
+
+#[rocket::async_trait]
+impl Fairing for RouteLatency {
+    fn info(&self) -> Info {
+        Info {
+            name: "Route latency",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut rocket::Data<'_>) {
+        // The request ID records when the request started
+        req.local_cache(|| RequestId::from_request(req));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let elapsed = req.local_cache(RequestId::new).started.elapsed();
+        let route = req.route().map_or("unmatched", |route| route.uri.path());
+        let method = req.method().as_str();
+        let status = res.status().code;
+        // The timing is already logged by `RequestTracing`'s "request finished"
+        if let Some(metrics) = req.rocket().state::<Arc<Metrics>>() {
+            metrics.observe_http(method, route, status, elapsed);
+        }
+    }
+}
+
+/// Fairing counting in-flight requests to log how many were drained on shutdown
+///
+/// Rocket stops accepting connections once shutdown is requested and gives
+/// pending requests its grace period to finish. Streamed responses count as
+/// finished once their headers are sent.
+#[derive(Default)]
+struct ShutdownDrain {
+    in_flight: AtomicUsize,
+}
+
+#[rocket::async_trait]
+impl Fairing for ShutdownDrain {
+    fn info(&self) -> Info {
+        Info {
+            name: "Shutdown drain",
+            kind: Kind::Request | Kind::Response | Kind::Shutdown,
+        }
+    }
+
+    async fn on_request(&self, _: &mut Request<'_>, _: &mut rocket::Data<'_>) {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+    }
+
+    async fn on_response<'r>(&self, _: &'r Request<'_>, _: &mut Response<'r>) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    async fn on_shutdown(&self, rocket: &Rocket<Orbit>) {
+        let pending = self.in_flight.load(Ordering::Relaxed);
+        tracing::info!(in_flight = pending, "shutdown requested, draining requests");
+
+        // The grace period runs alongside this fairing, so stop waiting when it's over
+        let deadline = Instant::now() + Duration::from_secs(rocket.config().shutdown.grace as u64);
+        while self.in_flight.load(Ordering::Relaxed) > 0 && Instant::now() < deadline {
+            rocket::tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        let remaining = self.in_flight.load(Ordering::Relaxed);
+        tracing::info!(
+            drained = pending.saturating_sub(remaining),
+            remaining,
+            "finished draining requests"
+        );
+    }
+}
+
+/// Whether the startup check passed, set once and for all
+#[derive(Default, Clone)]
+struct Readiness(Arc<AtomicBool>);
+
+/// Spawns the task checking that the config is complete and Unkey reachable,
+/// retrying until it is, then marking the server ready
+///
+/// Unlike `/health/deps`, the check only has to pass once, a later Unkey outage
+/// doesn't take the server out of rotation.
+fn spawn_readiness_check(rocket: &Rocket<Orbit>) {
+    let (Some(config), Some(unkey), Some(readiness)) = (
+        rocket.state::<AppConfig>(),
+        rocket.state::<UnkeyService>(),
+        rocket.state::<Readiness>(),
+    ) else {
+        return;
+    };
+    // Only allowed with `allow_missing_secrets`, for smoke tests that never serve traffic
+    if !config.missing_secrets().is_empty() {
+        tracing::warn!("required secrets are missing, the server won't report ready");
+        return;
+    }
+
+    let unkey = unkey.clone();
+    let api_id = config.unkey_api_id.clone();
+    let readiness = readiness.clone();
+    let retry_interval = Duration::from_millis(config.readiness_retry_interval_ms);
+    let shutdown = rocket.shutdown();
+    rocket::tokio::spawn(async move {
+        loop {
+            if unkey.check(&api_id).await {
+                readiness.0.store(true, Ordering::Relaxed);
+                tracing::info!("startup check passed, ready to serve requests");
+                return;
+            }
+            tracing::warn!(
+                retry_in_ms = retry_interval.as_millis() as u64,
+                "Unkey is unreachable, not ready yet"
+            );
+            rocket::tokio::select! {
+                _ = rocket::tokio::time::sleep(retry_interval) => {}
+                _ = shutdown.clone() => return,
+            }
+        }
+    });
+}
+
+/// Spawns the task sweeping expired entries from the in-memory caches every
+/// `cache_prune_interval_secs`, until Rocket shuts down
+fn spawn_cache_pruning(rocket: &Rocket<Orbit>) {
+    let Some(config) = rocket.state::<AppConfig>() else {
+        return;
+    };
+    if config.cache_prune_interval_secs == 0 {
+        return;
+    }
+
+    // Clones share the entries of the managed caches
+    let verify_cache = rocket
+        .state::<UnkeyService>()
+        .map(UnkeyService::verify_cache);
+    let idempotency = rocket.state::<IdempotencyStore>().cloned();
+    let concurrency = rocket.state::<OwnerConcurrencyLimiter>().cloned();
+    let rate_limit = rocket.state::<IpRateLimiter>().cloned();
+    let shutdown = rocket.shutdown();
+    let period = Duration::from_secs(config.cache_prune_interval_secs);
+
+    rocket::tokio::spawn(async move {
+        let mut interval =
+            rocket::tokio::time::interval_at(rocket::tokio::time::Instant::now() + period, period);
+        loop {
+            rocket::tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.clone() => break,
+            }
+
+            tracing::debug!(
+                verify = verify_cache.as_ref().map_or(0, VerifyCache::prune),
+                idempotency = idempotency.as_ref().map_or(0, IdempotencyStore::prune),
+                concurrency = concurrency
+                    .as_ref()
+                    .map_or(0, OwnerConcurrencyLimiter::prune),
+                rate_limit = rate_limit.as_ref().map_or(0, IpRateLimiter::prune),
+                "pruned expired cache entries"
+            );
+        }
+    });
+}
+
+/// Launches the application, blocking until it shuts down
+///
+/// The Tokio runtime is built here rather than by `#[launch]`, which sizes it from
+/// `Rocket.toml` and `ROCKET_*` variables alone, before `.env` is even loaded.
+pub fn run() {
+    // Load environment variables from .env file
+    dotenv::dotenv().ok();
+
+    // Log to stdout, filtered by `RUST_LOG` (defaults to info), as JSON lines with `LOG_FORMAT=json`
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    let log_format = std::env::var("LOG_FORMAT").unwrap_or_default();
+    match log_format.as_str() {
+        "json" => subscriber.json().flatten_event(true).try_init().ok(),
+        _ => subscriber.try_init().ok(),
+    };
+    if !matches!(log_format.as_str(), "" | "json" | "pretty") {
+        tracing::warn!(
+            log_format,
+            "unknown LOG_FORMAT, logging in the pretty format"
+        );
+    }
+
+    // Let plain environment variables override Rocket's own configuration sources
+    let figment = rocket::Config::figment().merge(Env::raw().only(AppConfig::ENV_KEYS));
+
+    let app_config: AppConfig = match figment.extract() {
+        Ok(config) => config,
+        Err(e) => {
+            tracing::error!(error = %e, "invalid configuration");
+            std::process::exit(1);
+        }
+    };
+    let services = match Services::from_config(&app_config) {
+        Ok(services) => services,
+        Err(e) => {
+            tracing::error!(error = %e, "failed to set up the services");
+            std::process::exit(1);
+        }
+    };
+
+    let config = rocket::Config::from(&figment);
+    let runtime = match rocket::tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(config.workers)
+        .max_blocking_threads(config.max_blocking)
+        .thread_name("rocket-worker-thread")
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            tracing::error!(error = %e, "failed to start the runtime");
+            std::process::exit(1);
+        }
+    };
+
+    let result = runtime.block_on(build_rocket(figment, app_config, services).launch());
+    // Like Rocket's own runtime, don't wait on tasks left behind when shutdown is forced
+    if config.shutdown.force {
+        runtime.shutdown_timeout(Duration::from_millis(500));
+    }
+    if let Err(e) = result {
+        tracing::error!(error = %e, "failed to launch the server");
+        std::process::exit(1);
+    }
+}
+
+/// Clients the endpoints call out through, replaced with fakes in tests
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+struct Services {
+    pub unkey: UnkeyService,
+    pub openai: OpenAiClient,
+    pub provider: Box<dyn ImageProvider>,
+    /// Fetches the images streamed by `/images/proxy`
+    pub image_proxy: Client,
+    pub metrics: Arc<Metrics>,
+}
+
+impl Services {
+    /// Builds the real clients, failing on settings they can't work with
+    pub fn from_config(config: &AppConfig) -> anyhow::Result<Self> {
+        if config.openai_provider == OpenAiProvider::Azure && config.openai_deployment.is_none() {
+            anyhow::bail!("OPENAI_DEPLOYMENT is required with the azure provider");
+        }
+        if config.openai_provider == OpenAiProvider::Azure && config.enable_moderation {
+            anyhow::bail!("ENABLE_MODERATION is only supported with the openai provider");
+        }
+        if let Err(e) = validate_image_params(
+            config.image_provider,
+            &config.default_image_size,
+            config.default_image_model.as_deref(),
+            config.default_image_count,
+            &config.default_response_format,
+        ) {
+            anyhow::bail!("invalid default image parameters: {}", e.message());
+        }
+
+        // Build HTTP clients shared by all requests, OpenAI's with a longer timeout
+        let unkey_http_client = http_client(config.unkey_timeout_secs)?;
+        let openai_http_client = http_client(config.openai_timeout_secs)?;
+        // Redirects aren't followed, they could lead the proxy off the allowed origins
+        let image_proxy = Client::builder()
+            .connect_timeout(HTTP_CONNECT_TIMEOUT)
+            .timeout(Duration::from_secs(config.openai_timeout_secs))
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        let metrics = Arc::new(Metrics::new()?);
+
+        // Build a single Unkey service shared by all requests
+        let unkey = UnkeyService::new(
+            UnkeyClient::new(config.unkey_root_key.expose()),
+            metrics.clone(),
+        )
+        .with_root_key(config.unkey_root_key.expose())
+        .with_http_client(unkey_http_client.clone())
+        .with_timeout(Duration::from_secs(config.unkey_timeout_secs))
+        .with_verify_cache_ttl(Duration::from_millis(config.verify_cache_ttl_ms))
+        .with_circuit_breaker(
+            config.unkey_circuit_failure_threshold,
+            Duration::from_secs(config.unkey_circuit_cooldown_secs),
+        );
+        let unkey = match &config.unkey_verify_key {
+            Some(key) if !key.expose().is_empty() => unkey.with_verify_key(key.expose()),
+            _ => unkey,
+        };
+        let unkey = match &config.webhook_url {
+            Some(url) => unkey.with_webhook(Webhook::new(unkey_http_client, url.expose())),
+            None => unkey,
+        };
+
+        // Build a single OpenAI client shared by all requests, which also moderates prompts
+        let openai = OpenAiClient::new(openai_http_client.clone(), config, metrics.clone());
+        let provider: Box<dyn ImageProvider> = match config.image_provider {
+            ImageProviderKind::OpenAI => Box::new(openai.clone()),
+            ImageProviderKind::Stability => Box::new(StabilityProvider::new(
+                openai_http_client.clone(),
+                config,
+                metrics.clone(),
+            )),
+        };
+
+        Ok(Services {
+            unkey,
+            openai,
+            provider,
+            image_proxy,
+            metrics,
+        })
+    }
+}
+
+/// Builds the application from its configuration and services, without launching it
+///
+/// `figment` holds Rocket's own settings, e.g. the address and the secret key.
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+fn build_rocket(figment: Figment, config: AppConfig, services: Services) -> Rocket<Build> {
+    // Reject oversized JSON bodies before they're deserialized
+    let max_body_size = figment
+        .extract_inner::<ByteUnit>("max_body_size")
+        .unwrap_or(DEFAULT_MAX_BODY_SIZE);
+    let figment = figment.merge(("limits.json", max_body_size));
+
+    // Let in-flight image generations finish when the process is asked to stop
+    let shutdown_grace_secs = figment
+        .extract_inner::<u32>("shutdown_grace_secs")
+        .unwrap_or(DEFAULT_SHUTDOWN_GRACE_SECS);
+    let figment = figment.merge(("shutdown.grace", shutdown_grace_secs));
+
+    let authorize_limiter = IpRateLimiter::new(config.authorize_rate_limit_per_minute);
+    let owner_limiter =
+        OwnerConcurrencyLimiter::new(config.max_concurrent_generations, OWNER_PERMIT_WAIT);
+    let idempotency = IdempotencyStore::new(Duration::from_secs(config.idempotency_ttl_secs));
+
+    // Mount routes for the application
+    let rocket = rocket::custom(figment)
+        .manage(config)
+        .manage(authorize_limiter)
+        .manage(owner_limiter)
+        .manage(idempotency)
+        .manage(services.unkey)
+        .manage(services.openai)
+        .manage(services.provider)
+        .manage(ImageProxyClient(services.image_proxy))
+        .manage(services.metrics)
+        .attach(AdHoc::try_on_ignite("Required secrets", |rocket| async {
+            let Some(config) = rocket.state::<AppConfig>() else {
+                return Err(rocket);
+            };
+            let missing = config.missing_secrets();
+            if missing.is_empty() {
+                return Ok(rocket);
+            }
+            if config.allow_missing_secrets {
+                tracing::warn!(?missing, "starting without required secrets");
+                return Ok(rocket);
+            }
+            tracing::error!(
+                ?missing,
+                "required secrets are missing, set them or ALLOW_MISSING_SECRETS=1"
+            );
+            Err(rocket)
+        }))
+        .attach(RequestTracing)
+        .attach(RouteLatency)
+        .attach(AccessLog)
+        .attach(ShutdownDrain::default())
+        .attach(Cors)
+        // After CORS, which replaces the `Vary` header
+        .attach(Compression)
+        .manage(Readiness::default())
+        .attach(AdHoc::on_liftoff("Runtime", |rocket| {
+            Box::pin(async move {
+                let metrics = rocket::tokio::runtime::Handle::current().metrics();
+                tracing::info!(
+                    workers = metrics.num_workers(),
+                    max_blocking = rocket.config().max_blocking,
+                    keep_alive_secs = rocket.config().keep_alive,
+                    "runtime started"
+                );
+            })
+        }))
+        .attach(AdHoc::on_liftoff("Readiness check", |rocket| {
+            Box::pin(async move { spawn_readiness_check(rocket) })
+        }))
+        .attach(AdHoc::on_liftoff("Cache pruning", |rocket| {
+            Box::pin(async move { spawn_cache_pruning(rocket) })
+        }))
+        .mount(
+            "/",
+            routes![
+                health,
+                health_deps,
+                ready,
+                prometheus_metrics,
+                openapi,
+                docs,
+                me,
+                me_usage,
+                authorize,
+                logout,
+                revoke,
+                rotate,
+                key_details,
+                list_keys,
+                admin_config,
+                admin_verify,
+                update_key_remaining,
+                generate_image,
+                generate_image_replay,
+                generate_image_form,
+                generate_image_stream,
+                generate_image_batch,
+                estimate_generation,
+                proxy_image,
+                preflight
+            ],
+        )
+        .register("/", catchers![default_catcher]);
+
+    #[cfg(feature = "persistence")]
+    let rocket = rocket
+        .attach(AdHoc::try_on_ignite("Key history", |rocket| async {
+            let Some(config) = rocket.state::<AppConfig>() else {
+                return Err(rocket);
+            };
+            // The store may already be managed, e.g. by tests using an in-memory database
+            if rocket.state::<KeyHistory>().is_some() {
+                return Ok(rocket);
+            }
+            match KeyHistory::connect(&config.database_url).await {
+                Ok(history) => Ok(rocket.manage(history)),
+                Err(e) => {
+                    tracing::error!(error = ?e, "failed to open key history");
+                    Err(rocket)
+                }
+            }
+        }))
+        .mount("/", routes![my_history]);
+
+    rocket
+}
+
+/// Catcher responding to any error with the JSON error shape used by the endpoints
+#[catch(default)]
+fn default_catcher(status: Status, req: &Request<'_>) -> WithRemaining<ApiError> {
+    let error = req
+        .local_cache(|| GuardError(None))
+        .0
+        .clone()
+        .filter(|error| error.status() == status)
+        .unwrap_or(ApiError::Other(status));
+    let remaining = req.local_cache(|| RemainingCalls(None)).0;
+    WithRemaining(error, remaining)
+}
+
+/// Endpoint answering CORS preflight requests, headers are added by the [`Cors`] fairing
+#[options("/<_..>")]
+fn preflight(_path: ServedPath) -> Status {
+    Status::NoContent
+}
+
+/// Request guard for paths served by a route other than the preflight one
+struct ServedPath;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ServedPath {
+    type Error = ApiError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let path = req.uri().path();
+        let served = req
+            .rocket()
+            .routes()
+            .filter(|route| route.method != Method::Options)
+            .any(|route| route_serves(route.uri.path(), path.as_str()));
+        if served {
+            Outcome::Success(ServedPath)
+        } else {
+            guard_error(req, ApiError::NotFound("No route serves this path.".into()))
+        }
+    }
+}
+
+/// Whether a route's path template, like `/keys/<key_id>`, matches `path`
+fn route_serves(template: &str, path: &str) -> bool {
+    let mut segments = path.split('/').filter(|segment| !segment.is_empty());
+    for expected in template.split('/').filter(|segment| !segment.is_empty()) {
+        if expected.starts_with('<') && expected.ends_with("..>") {
+            return true;
+        }
+        match segments.next() {
+            Some(_) if expected.starts_with('<') => {}
+            Some(segment) if segment == expected => {}
+            _ => return false,
+        }
+    }
+    segments.next().is_none()
+}
+
+/// Readiness endpoint, answering 503 until the startup check found the server
+/// able to serve requests
+#[get("/ready")]
+fn ready(readiness: &State<Readiness>) -> (Status, Json<Value>) {
+    if readiness.0.load(Ordering::Relaxed) {
+        (Status::Ok, Json(json::json!({ "status": "ready" })))
+    } else {
+        (
+            Status::ServiceUnavailable,
+            Json(json::json!({ "status": "starting" })),
+        )
+    }
+}
+
+/// Liveness endpoint that doesn't touch any dependency
+#[get("/health")]
+async fn health() -> Json<Value> {
+    Json(json::json!({ "status": "ok" }))
+}
+
+/// Endpoint checking that Unkey and the image provider are reachable
+///
+/// Unkey is checked with the API selected by `X-Unkey-Env`, if any.
+#[get("/health/deps")]
+async fn health_deps(
+    provider: &State<Box<dyn ImageProvider>>,
+    unkey: &State<UnkeyService>,
+    api: UnkeyApi,
+) -> (Status, Json<Value>) {
+    // Helper function to describe the state of a dependency
+    fn state(healthy: bool) -> &'static str {
+        if healthy {
+            "ok"
+        } else {
+            "unavailable"
+        }
+    }
+
+    let (unkey, images) = rocket::tokio::join!(unkey.check(&api.0), provider.check());
+
+    let status = if unkey && images {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+    let response = json::json!({
+        "status": state(status == Status::Ok),
+        "dependencies": {
+            "unkey": state(unkey),
+            provider.name(): state(images)
+        }
+    });
+    (status, Json(response))
+}
+
+/// Endpoint serving the OpenAPI document of the endpoints
+///
+/// The document is written by hand, keep it in sync with the routes and models.
+#[get("/openapi.json")]
+fn openapi() -> (ContentType, &'static str) {
+    (ContentType::JSON, include_str!("openapi.json"))
+}
+
+/// Endpoint serving Swagger UI to explore the OpenAPI document
+#[get("/docs")]
+fn docs() -> RawHtml<&'static str> {
+    RawHtml(include_str!("docs.html"))
+}
+
+/// Endpoint exposing the app's metrics in Prometheus' text format
+///
+/// It isn't authenticated, so keep it off the public internet (see the README).
+#[get("/metrics")]
+fn prometheus_metrics(metrics: &State<Arc<Metrics>>) -> (ContentType, String) {
+    (ContentType::Plain, metrics.render())
+}
+
+/// Endpoint to retrieve the current user's key information
+#[get("/me")]
+async fn me(
+    jar: &CookieJar<'_>,
+    config: &State<AppConfig>,
+    unkey: &State<UnkeyService>,
+    api: UnkeyApi,
+    request_id: RequestId,
+) -> Result<Json<KeyInfoData>, ApiError> {
+    let unkey_data = parse_session(jar, config)?;
+
+    // A failed verification still yields a session, just with unknown remaining calls
+    let remaining = unkey
+        .verify(&api.0, &unkey_data.key, None)
+        .instrument(request_id.span())
+        .await
+        .ok()
+        .and_then(|key| key.remaining);
+
+    Ok(Json(KeyInfoData {
+        key: unkey_data.key,
+        key_id: unkey_data.key_id,
+        remaining,
+    }))
+}
+
+/// Endpoint returning the daily verifications of the current user's key
+///
+/// `start` and `end` are Unix timestamps in milliseconds, covering the last
+/// 30 days by default. Reading the usage verifies the session key,
+/// so only the key's own usage can be read, and counts as one of its calls.
+#[get("/me/usage?<start>&<end>")]
+async fn me_usage(
+    jar: &CookieJar<'_>,
+    config: &State<AppConfig>,
+    unkey: &State<UnkeyService>,
+    api: UnkeyApi,
+    request_id: RequestId,
+    start: Option<u64>,
+    end: Option<u64>,
+) -> Result<Json<KeyUsageData>, ApiError> {
+    if !config.usage_analytics {
+        return Err(ApiError::NotImplemented(
+            "Usage analytics aren't enabled on this server.".into(),
+        ));
+    }
+
+    let unkey_data = parse_session(jar, config)?;
+
+    // Trust the key ID Unkey verifies rather than the one stored in the cookie
+    let key_id = unkey
+        .verify(&api.0, &unkey_data.key, None)
+        .instrument(request_id.span())
+        .await?
+        .key_id
+        .ok_or_else(|| ApiError::Unauthorized("Invalid API key.".into()))?;
+
+    let end = end.unwrap_or_else(now_ms);
+    let start = start.unwrap_or(end.saturating_sub(USAGE_DEFAULT_DAYS * DAY_MS));
+    if start > end {
+        return Err(ApiError::BadRequest("`start` must be before `end`.".into()));
+    }
+
+    let days = unkey
+        .get_verifications(&key_id, start, end)
+        .instrument(request_id.span())
+        .await
+        .map_err(|e| {
+            tracing::error!(
+                request_id = %request_id,
+                key_id = %key_id,
+                error = ?e,
+                "failed to read key usage"
+            );
+            ApiError::BadGateway("Unable to read the key usage.".into())
+        })?
+        .ok_or_else(|| {
+            ApiError::NotImplemented("Usage analytics aren't available on the Unkey plan.".into())
+        })?;
+
+    Ok(Json(KeyUsageData {
+        key_id,
+        start,
+        end,
+        days,
+    }))
+}
+
+/// Endpoint to authorize a user and create a new API key
+///
+/// Pass `expires_in_secs` to create a key that stops verifying after that many seconds.
+/// An optional JSON body sets the key's `owner_id` and `metadata`. A session whose
+/// key is still usable is kept as is instead of creating another key.
+#[post("/authorize?<expires_in_secs>", data = "<body>")]
+#[allow(clippy::too_many_arguments)]
+async fn authorize(
+    // Checked first, so requests without a User-Agent don't count against the rate limit
+    _user_agent: RequireUserAgent,
+    _rate_limit: AuthorizeRateLimit,
+    jar: &CookieJar<'_>,
+    config: &State<AppConfig>,
+    unkey: &State<UnkeyService>,
+    api: UnkeyApi,
+    route: &Route,
+    request_id: RequestId,
+    expires_in_secs: Option<u64>,
+    body: Result<Json<AuthorizeRequest>, json::Error<'_>>,
+    history: History<'_>,
+) -> Result<Redirect, ApiError> {
+    require_key_creation(config)?;
+
+    // Look the session key up rather than verifying it, which would spend one of its calls
+    if let Ok(session) = parse_session(jar, config) {
+        let key = unkey
+            .get(&session.key_id)
+            .instrument(request_id.span())
+            .await;
+        if matches!(key, Ok(Some(ref key)) if key.is_usable()) {
+            return Ok(redirect_to_me(config, route));
+        }
+    }
+
+    // An empty body is fine, only malformed JSON is rejected
+    let body = match body {
+        Ok(Json(body)) => Some(body),
+        Err(json::Error::Parse(raw, _)) if raw.trim().is_empty() => None,
+        Err(e) => {
+            return Err(ApiError::BadRequest(format!(
+                "Invalid request body: {}.",
+                e
+            )))
+        }
+    };
+    let (owner_id, metadata) = body
+        .map(|body| (body.owner_id, body.metadata))
+        .unwrap_or_default();
+    if metadata
+        .as_ref()
+        .is_some_and(|metadata| !metadata.is_object())
+    {
+        return Err(ApiError::BadRequest(
+            "Invalid metadata: expected a JSON object.".into(),
+        ));
+    }
+
+    let owner_id = owner_id.unwrap_or_else(anonymous_owner_id);
+    let owner_id = owner_id.as_str();
+    if let Some(data) = unkey
+        .create(config, &api.0, expires_in_secs, owner_id, metadata, None)
+        .instrument(request_id.span())
+        .await
+    {
+        // A key that couldn't be recorded is still handed out
+        if let History(Some(history)) = history {
+            if let Err(e) = history.record(&data, owner_id).await {
+                tracing::error!(request_id = %request_id, error = ?e, "failed to record key");
+            }
+        }
+
+        jar.add_private(session_cookie(config, &data));
+        Ok(redirect_to_me(config, route))
+    } else {
+        Err(ApiError::Unauthorized(
+            "Unable to create an API key.".into(),
+        )) // Return 401 if key creation fails
+    }
+}
+
+/// Redirect to the `me` endpoint, under the `cookie_path` the app is served at
+/// and the base the routes are mounted at
+fn redirect_to_me(config: &AppConfig, route: &Route) -> Redirect {
+    let prefix = config.cookie_path.trim_end_matches('/');
+    let base = route.uri.base().trim_end_matches('/');
+    Redirect::to(format!("{}{}{}", prefix, base, uri!(me())))
+}
+
+/// Request guard for the key history store, `None` when persistence is disabled
+///
+/// Unlike `Option<&State<KeyHistory>>`, it doesn't keep Rocket from launching
+/// when the store isn't managed.
+#[cfg_attr(feature = "testing", visibility::make(pub))]
+struct History<'r>(Option<&'r KeyHistory>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for History<'r> {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(History(req.rocket().state::<KeyHistory>()))
+    }
+}
+
+/// Stand-in for the key history store without the `persistence` feature, never managed
+#[cfg(not(feature = "persistence"))]
+enum KeyHistory {}
+
+#[cfg(not(feature = "persistence"))]
+impl KeyHistory {
+    async fn record(&self, _: &KeyCreateData, _: &str) -> anyhow::Result<()> {
+        match *self {}
+    }
+}
+
+/// Endpoint listing the keys created for the owner of the current session
+#[cfg(feature = "persistence")]
+#[get("/my/history")]
+async fn my_history(
+    jar: &CookieJar<'_>,
+    config: &State<AppConfig>,
+    history: &State<KeyHistory>,
+    request_id: RequestId,
+) -> Result<Json<Vec<KeyHistoryEntry>>, ApiError> {
+    let unkey_data = parse_session(jar, config)?;
+
+    history.list_for(&unkey_data).await.map(Json).map_err(|e| {
+        tracing::error!(request_id = %request_id, error = ?e, "failed to list key history");
+        ApiError::Internal("Unable to read the key history.".into())
+    })
+}
+
+/// Endpoint to end the current session by clearing the key cookie
+#[post("/logout")]
+async fn logout(jar: &CookieJar<'_>, config: &State<AppConfig>) -> Json<Value> {
+    // Only the cookie is removed, the key itself is left untouched in Unkey
+    jar.remove_private(removal_cookie(config));
+    Json(json::json!({ "status": "logged_out" }))
+}
+
+/// Endpoint to replace the current user's API key with a fresh one
+///
+/// The new key keeps the owner and remaining calls of the old one, which is
+/// deleted. When the old key is already gone, a key with the default quota is
+/// issued instead. The new key is recorded in the history like authorized ones.
+#[post("/rotate")]
+async fn rotate(
+    jar: &CookieJar<'_>,
+    config: &State<AppConfig>,
+    unkey: &State<UnkeyService>,
+    api: UnkeyApi,
+    history: History<'_>,
+    request_id: RequestId,
+) -> Result<Json<KeyCreateData>, ApiError> {
+    require_key_creation(config)?;
+
+    let unkey_data = parse_session(jar, config)?;
+
+    // Verifying proves the caller holds the key, even one that's out of calls or expired
+    let verification = unkey
+        .verify(&api.0, &unkey_data.key, None)
+        .instrument(request_id.span())
+        .await?;
+    let (owner_id, remaining) = match verification.key_id {
+        Some(ref key_id) if *key_id == unkey_data.key_id => {
+            unkey
+                .delete(key_id)
+                .instrument(request_id.span())
+                .await
+                .map_err(|e| {
+                    tracing::error!(request_id = %request_id, error = ?e, "failed to delete rotated key");
+                    ApiError::BadGateway("Unable to revoke the API key.".into())
+                })?;
+            (verification.owner_id, verification.remaining)
+        }
+        Some(_) => {
+            return Err(ApiError::Forbidden(
+                "The API key doesn't match the key id in cookies.".into(),
+            ))
+        }
+        None => (None, None),
+    };
+
+    let owner_id = owner_id
+        .filter(|owner_id| owner_id != LEGACY_OWNER_ID)
+        .unwrap_or_else(anonymous_owner_id);
+    let data = unkey
+        .create(config, &api.0, None, &owner_id, None, remaining)
+        .instrument(request_id.span())
+        .await
+        .ok_or_else(|| ApiError::BadGateway("Unable to create an API key.".into()))?;
+    tracing::info!(
+        request_id = %request_id,
+        old_key_id = %unkey_data.key_id,
+        key_id = %data.key_id,
+        "rotated key"
+    );
+
+    // A key that couldn't be recorded is still handed out
+    if let History(Some(history)) = history {
+        if let Err(e) = history.record(&data, &owner_id).await {
+            tracing::error!(request_id = %request_id, error = ?e, "failed to record key");
+        }
+    }
+
+    jar.add_private(session_cookie(config, &data));
+    Ok(Json(data))
+}
+
+/// Endpoint to revoke the current user's API key
+#[post("/revoke")]
+async fn revoke(
+    jar: &CookieJar<'_>,
+    config: &State<AppConfig>,
+    unkey: &State<UnkeyService>,
+    request_id: RequestId,
+) -> Result<Json<Value>, ApiError> {
+    let unkey_data = parse_session(jar, config)?;
+
+    // Delete the key from Unkey and drop the cookie once it's gone
+    unkey
+        .delete(&unkey_data.key_id)
+        .instrument(request_id.span())
+        .await
+        .map_err(|e| {
+            tracing::error!(request_id = %request_id, error = ?e, "failed to revoke key");
+            ApiError::BadGateway("Unable to revoke the API key.".into())
+        })?;
+
+    jar.remove_private(removal_cookie(config));
+    Ok(Json(
+        json::json!({ "status": "revoked", "key_id": unkey_data.key_id }),
+    ))
+}
+
+/// Endpoint to look up the current state of a key
+///
+/// Callers can only read the key stored in their own session, unless they
+/// present the admin key.
+#[get("/keys/<key_id>")]
+async fn key_details(
+    key_id: &str,
+    admin: Option<Admin>,
+    jar: &CookieJar<'_>,
+    config: &State<AppConfig>,
+    unkey: &State<UnkeyService>,
+    request_id: RequestId,
+) -> Result<Json<KeyDetailsData>, ApiError> {
+    let session_key_id = parse_session(jar, config).ok().map(|data| data.key_id);
+    if admin.is_none() && session_key_id.as_deref() != Some(key_id) {
+        return Err(ApiError::Forbidden(
+            "You can only read the key of your own session.".into(),
+        ));
+    }
+
+    match unkey.get(key_id).instrument(request_id.span()).await {
+        Ok(Some(data)) => Ok(Json(data)),
+        Ok(None) => Err(ApiError::NotFound("Key not found.".into())),
+        Err(e) => {
+            tracing::error!(request_id = %request_id, error = ?e, "failed to read key");
+            Err(ApiError::BadGateway("Unable to read the API key.".into()))
+        }
+    }
+}
+
+/// Endpoint for admins to list the keys of an owner, a page at a time
+///
+/// Pass the `cursor` of a response to get the next page. Keys are listed from
+/// the API selected by `X-Unkey-Env`, if any.
+#[get("/admin/keys?<owner_id>&<cursor>&<limit>")]
+async fn list_keys(
+    owner_id: &str,
+    cursor: Option<&str>,
+    limit: Option<usize>,
+    _admin: Admin,
+    api: UnkeyApi,
+    unkey: &State<UnkeyService>,
+    request_id: RequestId,
+) -> Result<Json<KeyListData>, ApiError> {
+    // Unkey returns at most 100 keys per page
+    let limit = limit.unwrap_or(DEFAULT_KEY_PAGE_SIZE).clamp(1, 100);
+
+    unkey
+        .list_keys(&api.0, owner_id, limit, cursor)
+        .instrument(request_id.span())
+        .await
+        .map(Json)
+        .map_err(|e| {
+            tracing::error!(request_id = %request_id, error = ?e, "failed to list keys");
+            ApiError::BadGateway("Unable to list the API keys.".into())
+        })
+}
+
+/// Endpoint for admins to read the loaded configuration, with secrets redacted
+#[get("/admin/config")]
+fn admin_config(_admin: Admin, config: &State<AppConfig>) -> Json<&AppConfig> {
+    Json(config.inner())
+}
+
+/// Endpoint for admins to verify any key, e.g. to diagnose a user's "invalid key"
+///
+/// Unlike the session endpoints it sets no cookie, but like any verification it
+/// consumes one of the key's remaining calls.
+#[post("/admin/verify", data = "<body>")]
+async fn admin_verify(
+    _admin: Admin,
+    _json: JsonContentType,
+    api: UnkeyApi,
+    unkey: &State<UnkeyService>,
+    request_id: RequestId,
+    body: Json<AdminVerifyBody>,
+) -> Result<Json<KeyVerificationData>, ApiError> {
+    let data = unkey
+        .verify(&api.0, &body.key, None)
+        .instrument(request_id.span())
+        .await
+        .map_err(|e| {
+            tracing::error!(request_id = %request_id, error = %e, "failed to verify key");
+            ApiError::from(e)
+        })?;
+    Ok(Json(data.into()))
+}
+
+/// Endpoint for admins to top up the remaining calls of a key
+#[post("/keys/<key_id>/update", format = "json", data = "<body>")]
+async fn update_key_remaining(
+    key_id: &str,
+    _admin: Admin,
+    unkey: &State<UnkeyService>,
+    request_id: RequestId,
+    body: Json<UpdateKeyBody>,
+) -> Result<Json<KeyDetailsData>, ApiError> {
+    unkey
+        .update(key_id, body.remaining as usize)
+        .instrument(request_id.span())
+        .await
+        .map_err(|e| {
+            tracing::error!(request_id = %request_id, error = ?e, "failed to update key");
+            ApiError::BadGateway("Unable to update the API key.".into())
+        })?;
+
+    // Return the key as Unkey now sees it
+    match unkey.get(key_id).instrument(request_id.span()).await {
+        Ok(Some(data)) => Ok(Json(data)),
+        Ok(None) => Err(ApiError::NotFound("Key not found.".into())),
+        Err(e) => {
+            tracing::error!(request_id = %request_id, error = ?e, "failed to read key");
+            Err(ApiError::BadGateway("Unable to read the API key.".into()))
+        }
+    }
+}
+
+/// Endpoint streaming the generation of an image as server-sent events
+///
+/// Emits a "started" event, "heartbeat" events while OpenAI works on the image,
+/// then a final "result" or "error" event. The key is verified before streaming,
+/// once the prompt passed validation and moderation.
+#[get("/generate_image/stream?<prompt>")]
+#[allow(clippy::too_many_arguments)]
+async fn generate_image_stream<'r>(
+    key: UnverifiedKey<'_>,
+    config: &'r State<AppConfig>,
+    openai: &State<OpenAiClient>,
+    provider: &'r State<Box<dyn ImageProvider>>,
+    unkey: &'r State<UnkeyService>,
+    limiter: &State<OwnerConcurrencyLimiter>,
+    request_id: RequestId,
+    prompt: String,
+) -> Result<WithRemaining<EventStream![Event + 'r]>, WithRemaining<ApiError>> {
+    let prompt = validate_prompt(config, &prompt)?;
+    moderate_prompt(config, openai, &request_id, &prompt).await?;
+    let key = key.verify(unkey).await?;
+    let permit = owner_permit(limiter, unkey, &key, &request_id).await?;
+
+    let remaining = key.remaining;
+    let stream = EventStream! {
+        // Hold the owner's slot until the stream ends
+        let _permit = permit;
+        yield Event::json(&json::json!({ "remaining_calls": remaining })).event("started");
+
+        // Streams report a single image URL, only the size and model defaults apply
+        let request = ImageRequest {
+            prompt: &prompt,
+            size: &config.default_image_size,
+            model: config.default_image_model.as_deref(),
+            n: 1,
+            response_format: "url",
+        };
+        let generation = provider.generate(&request).instrument(request_id.span());
+        rocket::tokio::pin!(generation);
+
+        // Send heartbeats until OpenAI answers, the first tick fires immediately
+        let mut heartbeat = rocket::tokio::time::interval(STREAM_HEARTBEAT_INTERVAL);
+        heartbeat.tick().await;
+        let result = loop {
+            let done = rocket::tokio::select! {
+                result = &mut generation => Some(result),
+                _ = heartbeat.tick() => None,
+            };
+            match done {
+                Some(result) => break result,
+                None => yield Event::empty().event("heartbeat"),
+            }
+        };
+
+        match result {
+            Ok(images) => {
+                // Providers returning base64 images can't honor the URL format
+                let (single_key, _) = images[0].response_keys();
+                let mut response = json::json!({ "remaining_calls": remaining });
+                response[single_key] = images[0].data().into();
+                yield Event::json(&response).event("result");
+            }
+            Err(e) => {
+                tracing::error!(
+                    request_id = %request_id,
+                    key_id = %key.key_id,
+                    error = %e,
+                    "failed to generate streamed image"
+                );
+                if e.is_upstream_failure() {
+                    refund_calls(config, unkey, &key, &request_id, 1).await;
+                }
+                let error = ApiError::from(e);
+                let body = json::json!({
+                    "error": { "code": error.code(), "message": error.message() }
+                });
+                yield Event::json(&body).event("error");
+            }
+        }
+    };
+    Ok(WithRemaining(stream, remaining))
+}
+
+/// Response of `generate_image`, JSON or the raw PNG
+type ImageResponse = WithRemaining<Either<Json<Value>, (ContentType, Vec<u8>)>>;
+
+impl From<CachedResponse> for ImageResponse {
+    fn from(response: CachedResponse) -> Self {
+        let body = response
+            .body
+            .map_left(Json)
+            .map_right(|png| (ContentType::PNG, png));
+        WithRemaining(body, response.remaining)
+    }
+}
+
+/// Hashes the parameters of a generation, telling an idempotent repeat from another request
+fn request_fingerprint(payload: &GenerateImageRequest, dry_run: bool, png: bool) -> [u8; 32] {
+    let params = json::json!({
+        "prompt": payload.prompt,
+        "size": payload.size,
+        "model": payload.model,
+        "n": payload.n,
+        "response_format": payload.response_format,
+        "dry_run": dry_run,
+        "png": png
+    });
+    Sha256::digest(params.to_string().as_bytes()).into()
+}
+
+/// Endpoint replaying the response of a generation repeated with its `Idempotency-Key`
+///
+/// It's matched before `generate_image`, and only when the key was already used
+/// by the key's owner. The key is looked up rather than verified again, which
+/// still turns away revoked keys without the repeat costing a call.
+#[post("/generate_image?<dry_run>", data = "<payload>", rank = 1)]
+#[allow(clippy::too_many_arguments)]
+async fn generate_image_replay(
+    replay: IdempotentReplay,
+    _json: JsonContentType,
+    config: &State<AppConfig>,
+    unkey: &State<UnkeyService>,
+    idempotency: &State<IdempotencyStore>,
+    request_id: RequestId,
+    accepts_png: AcceptsPng,
+    dry_run: Option<bool>,
+    payload: Json<GenerateImageRequest>,
+) -> Result<ImageResponse, ApiError> {
+    let key = unkey
+        .get(&replay.key.key_id)
+        .instrument(request_id.span())
+        .await
+        .map_err(|e| {
+            tracing::error!(request_id = %request_id, error = ?e, "failed to look up key");
+            ApiError::ServiceUnavailable(
+                "Unable to verify the API key right now, try again later.".into(),
+            )
+        })?;
+    if !key.is_some_and(|key| key.is_usable()) {
+        return Err(ApiError::Unauthorized("Invalid API key.".into()));
+    }
+
+    let dry_run = config.dry_run || dry_run == Some(true);
+    let fingerprint = request_fingerprint(&payload, dry_run, accepts_png.0);
+    match idempotency.lookup(&replay.key.owner, &replay.idempotency_key, fingerprint) {
+        // The first request expired since the route was matched
+        Lookup::Miss => Err(ApiError::Conflict(
+            "This Idempotency-Key just expired, retry the request.".into(),
+        )),
+        lookup => replayed_response(lookup),
+    }
+}
+
+/// Answers a request whose `Idempotency-Key` was already used, from how it looked up
+fn replayed_response(lookup: Lookup) -> Result<ImageResponse, ApiError> {
+    match lookup {
+        Lookup::Replay(response) => Ok(response.into()),
+        Lookup::InProgress | Lookup::Miss => Err(ApiError::Conflict(
+            "A request with this Idempotency-Key is still in progress.".into(),
+        )),
+        Lookup::Conflict => Err(ApiError::Conflict(
+            "This Idempotency-Key was already used for a different request.".into(),
+        )),
+    }
+}
+
+/// Endpoint to generate an image based on a provided prompt
+///
+/// The key is verified once the request is validated and the prompt moderated,
+/// so invalid or flagged requests don't cost a call. In dry-run mode, set by `dry_run` in the config or the `dry_run` query
+/// parameter, the key is still verified but OpenAI isn't called and placeholder
+/// images are returned instead. With an `Idempotency-Key` header, the response
+/// is kept for `generate_image_replay` to answer repeats with.
+#[post("/generate_image?<dry_run>", data = "<payload>", rank = 2)]
+#[allow(clippy::too_many_arguments)]
+async fn generate_image(
+    // Checked first, so a malformed request is rejected before the payload is parsed
+    _json: JsonContentType,
+    key: UnverifiedKey<'_>,
+    config: &State<AppConfig>,
+    openai: &State<OpenAiClient>,
+    provider: &State<Box<dyn ImageProvider>>,
+    unkey: &State<UnkeyService>,
+    limiter: &State<OwnerConcurrencyLimiter>,
+    idempotency: &State<IdempotencyStore>,
+    idempotency_key: IdempotencyKey,
+    request_id: RequestId,
+    accepts_png: AcceptsPng,
+    dry_run: Option<bool>,
+    payload: Json<GenerateImageRequest>, // Request payload containing prompt
+) -> Result<ImageResponse, WithRemaining<ApiError>> {
+    // The query can only turn dry-run mode on, never off
+    let dry_run = config.dry_run || dry_run == Some(true);
+
+    // Validate the prompt before calling OpenAI
+    let prompt = validate_prompt(config, &payload.prompt)?;
+
+    // Fields left out of the request fall back to the configured defaults
+    let size = payload
+        .size
+        .as_deref()
+        .unwrap_or(&config.default_image_size);
+    let model = payload
+        .model
+        .as_deref()
+        .or(config.default_image_model.as_deref());
+    let (n, response_format) = if accepts_png.0 {
+        // The PNG is decoded from base64, and the response body holds a single image
+        if payload.n.is_some_and(|n| n > 1) {
+            return Err(ApiError::BadRequest(
+                "A PNG response holds a single image, leave out `n` or set it to 1.".into(),
+            )
+            .into());
+        }
+        (1, "b64_json")
+    } else {
+        let n = payload
+            .n
+            .unwrap_or(config.default_image_count)
+            .clamp(1, MAX_IMAGE_COUNT);
+        let response_format = payload
+            .response_format
+            .as_deref()
+            .unwrap_or(&config.default_response_format);
+        (n, response_format)
+    };
+    validate_image_params(config.image_provider, size, model, n, response_format)?;
+    if !dry_run {
+        moderate_prompt(config, openai, &request_id, &prompt).await?;
+    }
+    let key = key.verify(unkey).await?;
+
+    // Repeats reach `generate_image_replay`, unless they race with this request
+    // or come from another key of the owner
+    let pending = match idempotency_key.0 {
+        Some(idempotency_key) if idempotency.is_enabled() => {
+            let fingerprint = request_fingerprint(&payload, dry_run, accepts_png.0);
+            match idempotency.begin(
+                key.key.expose(),
+                &key.key_id,
+                key.owner(),
+                &idempotency_key,
+                fingerprint,
+            ) {
+                Ok(pending) => Some(pending),
+                Err(lookup) => return replayed_response(lookup).map_err(Into::into),
+            }
+        }
+        _ => None,
+    };
+
+    let _permit = owner_permit(limiter, unkey, &key, &request_id).await?;
+    tracing::debug!(
+        request_id = %request_id,
+        size,
+        model,
+        n,
+        response_format,
+        dry_run,
+        "effective image parameters"
+    );
+
+    let images = if dry_run {
+        placeholder_images(size, n, response_format)
+    } else {
+        // Call OpenAI API to generate the image
+        let request = ImageRequest {
+            prompt: &prompt,
+            size,
+            model,
+            n,
+            response_format,
+        };
+        let result = provider
+            .generate(&request)
+            .instrument(request_id.span())
+            .await;
+        match result {
+            Ok(images) => images,
+            Err(e) => {
+                tracing::error!(
+                    request_id = %request_id,
+                    key_id = %key.key_id,
+                    error = %e,
+                    "failed to generate image"
+                );
+                if e.is_upstream_failure() {
+                    refund_calls(config, unkey, &key, &request_id, 1).await;
+                }
+                return Err(ApiError::from(e).into());
+            }
+        }
+    };
+
+    let body = if accepts_png.0 {
+        let png = match &images[0].content {
+            ImageContent::Base64(data) => BASE64_STANDARD.decode(data).ok(),
+            ImageContent::Url(_) => None,
+        }
+        .ok_or_else(|| ApiError::BadGateway("The image provider returned no PNG data.".into()))?;
+        Either::Right(png)
+    } else {
+        Either::Left(image_response_body(
+            &payload.prompt,
+            &prompt,
+            &images,
+            key.remaining,
+            size,
+            model,
+            n,
+            dry_run,
+        ))
+    };
+
+    let response = CachedResponse {
+        body,
+        remaining: key.remaining,
+    };
+    if let Some(pending) = pending {
+        pending.complete(response.clone());
+    }
+    Ok(response.into())
+}
+
+/// Endpoint to generate an image from a form-encoded prompt, e.g. posted by a
+/// plain HTML `<form>`
+///
+/// It shares `generate_image`'s logic and JSON response, with the other
+/// parameters left to their defaults. Forms can't send an `Idempotency-Key`,
+/// so the header is ignored.
+#[post(
+    "/generate_image?<dry_run>",
+    format = "form",
+    data = "<form>",
+    rank = 0
+)]
+#[allow(clippy::too_many_arguments)]
+async fn generate_image_form(
+    key: UnverifiedKey<'_>,
+    config: &State<AppConfig>,
+    openai: &State<OpenAiClient>,
+    provider: &State<Box<dyn ImageProvider>>,
+    unkey: &State<UnkeyService>,
+    limiter: &State<OwnerConcurrencyLimiter>,
+    idempotency: &State<IdempotencyStore>,
+    request_id: RequestId,
+    dry_run: Option<bool>,
+    form: Form<GenerateImageForm>,
+) -> Result<ImageResponse, WithRemaining<ApiError>> {
+    let form = form.into_inner();
+    let payload = GenerateImageRequest {
+        prompt: form.prompt,
+        size: form.size,
+        model: None,
+        n: None,
+        response_format: None,
+    };
+    // The route already matched the content type, the payload is built from the form
+    generate_image(
+        JsonContentType,
+        key,
+        config,
+        openai,
+        provider,
+        unkey,
+        limiter,
+        idempotency,
+        IdempotencyKey(None),
+        request_id,
+        AcceptsPng(false),
+        dry_run,
+        Json(payload),
+    )
+    .await
+}
+
+/// Builds the JSON body of a successful `generate_image`
+#[allow(clippy::too_many_arguments)]
+fn image_response_body(
+    prompt: &str,
+    effective_prompt: &str,
+    images: &[GeneratedImage],
+    remaining: Option<usize>,
+    size: &str,
+    model: Option<&str>,
+    n: u8,
+    dry_run: bool,
+) -> Value {
+    let (single_key, list_key) = images[0].response_keys();
+    let data: Vec<&str> = images.iter().map(GeneratedImage::data).collect();
+    // Echo the parameters sent to OpenAI, defaults included, so results can be audited
+    let mut response = json::json!({
+        "remaining_calls": remaining,
+        "request": {
+            "prompt": prompt,
+            "effective_prompt": effective_prompt,
+            "size": size,
+            "model": model,
+            "n": n
+        },
+        "meta": image_meta(size, model),
+        // DALL-E 3 generates a single image per request, the only one it revises prompts for
+        "revised_prompt": images[0].revised_prompt
+    });
+    response[list_key] = json::json!(data);
+    // Keep the single-image shape for clients that predate `n`
+    if n == 1 {
+        response[single_key] = json::json!(data[0]);
+    }
+    if dry_run {
+        response["dry_run"] = true.into();
+    }
+    response
+}
+
+/// Endpoint to generate one image for each of several prompts
+///
+/// The key is verified once and charged one call per prompt up front. Prompts
+/// are generated concurrently, and each result reports its own image or error,
+/// so a failed prompt doesn't fail the others.
+#[post("/generate_image/batch", format = "json", data = "<payload>")]
+#[allow(clippy::too_many_arguments)]
+async fn generate_image_batch(
+    key: UnverifiedKey<'_>,
+    config: &State<AppConfig>,
+    openai: &State<OpenAiClient>,
+    provider: &State<Box<dyn ImageProvider>>,
+    unkey: &State<UnkeyService>,
+    limiter: &State<OwnerConcurrencyLimiter>,
+    request_id: RequestId,
+    payload: Json<GenerateImageBatchRequest>,
+) -> Result<WithRemaining<Json<Value>>, WithRemaining<ApiError>> {
+    let prompts = &payload.prompts;
+    if prompts.is_empty() || prompts.len() > MAX_BATCH_PROMPTS {
+        return Err(ApiError::BadRequest(format!(
+            "Expected between 1 and {} prompts.",
+            MAX_BATCH_PROMPTS
+        ))
+        .into());
+    }
+    let effective_prompts = prompts
+        .iter()
+        .map(|prompt| validate_prompt(config, prompt))
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = key.verify(unkey).await?;
+    // The whole batch takes a single slot of the owner
+    let _permit = owner_permit(limiter, unkey, &key, &request_id).await?;
+
+    // Verifying spent one call, charge the others before generating anything
+    let mut remaining = key.remaining;
+    if let Some(calls_left) = remaining {
+        let extra_calls = prompts.len() - 1;
+        if calls_left < extra_calls {
+            // Nothing gets generated, so the verification's call isn't kept either
+            let refunded = refund_verification(unkey, &key, &request_id).await;
+            let error = ApiError::quota_exceeded(
+                format!(
+                    "Quota exceeded, the key has not enough remaining calls for {} prompts.",
+                    prompts.len()
+                ),
+                key.refill_interval.as_ref(),
+            );
+            return Err(WithRemaining(error, refunded));
+        }
+        if extra_calls > 0 {
+            let spent = unkey
+                .spend(&key.key_id, extra_calls)
+                .instrument(request_id.span())
+                .await
+                .map_err(|e| {
+                    tracing::error!(request_id = %request_id, error = ?e, "failed to spend calls");
+                    ApiError::BadGateway("Unable to charge the API key.".into())
+                })?;
+            remaining = Some(spent);
+        }
+    }
+
+    // Every prompt gets a single image with the configured defaults
+    let size = config.default_image_size.as_str();
+    let model = config.default_image_model.as_deref();
+    let response_format = config.default_response_format.as_str();
+    let (request_id, key_id) = (&request_id, &key.key_id);
+    // Each result tells whether its call should be refunded
+    // Owned prompts keep the futures free of borrowed arguments, which the route's
+    // `Send` future can't name lifetimes for
+    let prompt_pairs = prompts.iter().cloned().zip(effective_prompts);
+    let mut results: Vec<(usize, Value, bool)> = stream::iter(prompt_pairs.enumerate())
+        .map(|(index, (prompt, effective_prompt))| async move {
+            let result = async {
+                if config.dry_run {
+                    return Ok(placeholder_images(size, 1, response_format));
+                }
+                moderate_prompt(config, openai, request_id, &effective_prompt)
+                    .await
+                    .map_err(|e| (e, false))?;
+
+                let request = ImageRequest {
+                    prompt: &effective_prompt,
+                    size,
+                    model,
+                    n: 1,
+                    response_format,
+                };
+                let result = provider
+                    .generate(&request)
+                    .instrument(request_id.span())
+                    .await;
+                result.map_err(|e| {
+                    tracing::error!(
+                        request_id = %request_id,
+                        key_id = %key_id,
+                        index,
+                        error = %e,
+                        "failed to generate batch image"
+                    );
+                    let refund = e.is_upstream_failure();
+                    (ApiError::from(e), refund)
+                })
+            }
+            .await;
+
+            let mut item = json::json!({ "prompt": prompt, "effective_prompt": effective_prompt });
+            match result {
+                Ok(images) => {
+                    let (single_key, _) = images[0].response_keys();
+                    item[single_key] = images[0].data().into();
+                    (index, item, false)
+                }
+                Err((error, refund)) => {
+                    item["error"] =
+                        json::json!({ "code": error.code(), "message": error.message() });
+                    (index, item, refund)
+                }
+            }
+        })
+        .buffer_unordered(BATCH_CONCURRENCY)
+        .collect()
+        .await;
+    results.sort_by_key(|(index, ..)| *index);
+
+    let refunds = results.iter().filter(|(.., refund)| *refund).count();
+    if let Some(refunded) = refund_calls(config, unkey, &key, request_id, refunds).await {
+        remaining = Some(refunded);
+    }
+    let results: Vec<Value> = results.into_iter().map(|(_, item, _)| item).collect();
+    let response = json::json!({ "remaining_calls": remaining, "results": results });
+    Ok(WithRemaining(Json(response), remaining))
+}
+
+/// Endpoint streaming an image generated as a URL through to the client, chunk
+/// by chunk, rather than buffering it in memory
+///
+/// Only URLs on one of `image_proxy_origins` are fetched, and redirects aren't
+/// followed, so the endpoint can't reach other hosts. The key is verified, and
+/// the call the verification spends refunded, so proxying is free.
+#[get("/images/proxy?<url>")]
+async fn proxy_image(
+    url: &str,
+    key: UnverifiedKey<'_>,
+    config: &State<AppConfig>,
+    unkey: &State<UnkeyService>,
+    proxy: &State<ImageProxyClient>,
+    request_id: RequestId,
+) -> Result<
+    WithRemaining<ProxiedImage<impl Stream<Item = impl AsRef<[u8]> + Send + Unpin> + Send>>,
+    WithRemaining<ApiError>,
+> {
+    let allowed = reqwest::Url::parse(url).is_ok_and(|url| {
+        let origin = url.origin().ascii_serialization();
+        config
+            .image_proxy_origins
+            .iter()
+            .any(|allowed| allowed.trim_end_matches('/') == origin)
+    });
+    if !allowed {
+        return Err(ApiError::BadRequest(
+            "The URL isn't on one of the image origins the proxy serves.".into(),
+        )
+        .into());
+    }
+    let key = key.verify(unkey).await?;
+    let remaining = refund_verification(unkey, &key, &request_id).await;
+
+    let upstream = proxy
+        .0
+        .get(url)
+        .send()
+        .instrument(request_id.span())
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|e| {
+            tracing::error!(request_id = %request_id, error = ?e, "failed to fetch image");
+            if e.is_timeout() {
+                ApiError::GatewayTimeout("The image host took too long to answer.".into())
+            } else {
+                ApiError::BadGateway("Unable to fetch the image.".into())
+            }
+        })?;
+    if upstream.status().is_redirection() {
+        tracing::error!(
+            request_id = %request_id,
+            status = %upstream.status(),
+            "image host redirected the proxy"
+        );
+        return Err(ApiError::BadGateway("The image host redirected the request.".into()).into());
+    }
+
+    let content_type = upstream
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(ContentType::parse_flexible)
+        .unwrap_or(ContentType::Binary);
+    let length = upstream.content_length();
+    let request_id = request_id.clone();
+    let body = ByteStream! {
+        let mut chunks = upstream.bytes_stream();
+        while let Some(chunk) = chunks.next().await {
+            match chunk {
+                Ok(chunk) => yield chunk,
+                // The headers are sent already, the client sees a truncated body
+                Err(e) => {
+                    tracing::error!(request_id = %request_id, error = ?e, "failed to stream image");
+                    break;
+                }
+            }
+        }
+    };
+    Ok(WithRemaining(
+        ProxiedImage {
+            content_type,
+            length,
+            body,
+        },
+        remaining,
+    ))
+}
+
+/// Endpoint estimating what a generation with the given parameters would cost,
+/// without calling OpenAI
+///
+/// The key is verified to report whether it has the calls left, and the call
+/// the verification spends is refunded right away. Exhausted keys still get an
+/// estimate, with `sufficient_quota` false.
+#[post("/generate_image/estimate", data = "<payload>")]
+async fn estimate_generation(
+    _json: JsonContentType,
+    key: UnverifiedKey<'_>,
+    config: &State<AppConfig>,
+    unkey: &State<UnkeyService>,
+    request_id: RequestId,
+    payload: Json<EstimateImageRequest>,
+) -> Result<WithRemaining<Json<GenerationEstimate>>, WithRemaining<ApiError>> {
+    let size = payload
+        .size
+        .as_deref()
+        .unwrap_or(&config.default_image_size);
+    let model = payload
+        .model
+        .as_deref()
+        .or(config.default_image_model.as_deref());
+    let n = payload
+        .n
+        .unwrap_or(config.default_image_count)
+        .clamp(1, MAX_IMAGE_COUNT);
+    validate_image_params(config.image_provider, size, model, n, "url")?;
+    let remaining = match key.verify(unkey).await {
+        Ok(key) => refund_verification(unkey, &key, &request_id).await,
+        // Unkey spent nothing on a key that was out of calls
+        Err(WithRemaining(error, Some(0))) if error.code() == "quota_exceeded" => Some(0),
+        Err(e) => return Err(e),
+    };
+
+    let model = model.unwrap_or(pricing::DEFAULT_MODEL);
+    // Other providers have their own prices, which the table doesn't cover
+    let estimated_cost_usd = (config.image_provider == ImageProviderKind::OpenAI)
+        .then(|| pricing::image_price(model, size))
+        .flatten()
+        .map(|price| price * f64::from(n));
+    // A generation is charged one call whatever the number of images
+    let calls = 1;
+    Ok(WithRemaining(
+        Json(GenerationEstimate {
+            model: model.into(),
+            size: size.into(),
+            n,
+            estimated_cost_usd,
+            calls,
+            remaining_calls: remaining,
+            sufficient_quota: remaining.is_none_or(|remaining| remaining >= calls),
+        }),
+        remaining,
+    ))
+}
+
+/// Gives back the call verifying a key spent, for endpoints that don't charge one,
+/// returning how many calls the key has left
+///
+/// A failed refund is only logged, the key is left with the calls it was verified with.
+async fn refund_verification(
+    unkey: &UnkeyService,
+    key: &VerifiedKey,
+    request_id: &RequestId,
+) -> Option<usize> {
+    // Keys without a quota weren't charged anything
+    key.remaining?;
+
+    match unkey
+        .refund(&key.key_id, 1)
+        .instrument(request_id.span())
+        .await
+    {
+        Ok(remaining) => Some(remaining),
+        Err(e) => {
+            tracing::error!(
+                request_id = %request_id,
+                key_id = %key.key_id,
+                error = ?e,
+                "failed to refund the verification's call"
+            );
+            key.remaining
+        }
+    }
+}
+
+/// Gives `calls` back to a key charged for images OpenAI failed to generate,
+/// returning how many calls the key has left once refunded
+///
+/// Failed refunds are only logged, the caller already gets the generation error.
+async fn refund_calls(
+    config: &AppConfig,
+    unkey: &UnkeyService,
+    key: &VerifiedKey,
+    request_id: &RequestId,
+    calls: usize,
+) -> Option<usize> {
+    // Keys without a quota weren't charged anything
+    if !config.refund_on_failure || key.remaining.is_none() || calls == 0 {
+        return None;
+    }
+
+    match unkey
+        .refund(&key.key_id, calls)
+        .instrument(request_id.span())
+        .await
+    {
+        Ok(remaining) => {
+            tracing::info!(
+                request_id = %request_id,
+                key_id = %key.key_id,
+                calls,
+                remaining,
+                "refunded calls"
+            );
+            Some(remaining)
+        }
+        Err(e) => {
+            tracing::error!(
+                request_id = %request_id,
+                key_id = %key.key_id,
+                error = ?e,
+                "failed to refund calls"
+            );
+            None
+        }
+    }
+}
+
+/// Returns the current time in Unix milliseconds
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
+
+/// Time until Unkey refills a key's calls, at midnight UTC for daily refills and
+/// on the first of the month for monthly ones
+fn until_refill(refill: &RefillInterval) -> Duration {
+    let now = OffsetDateTime::now_utc();
+    let next = match refill {
+        RefillInterval::Daily => now.date().next_day(),
+        RefillInterval::Monthly => {
+            let (year, month) = match now.month() {
+                Month::December => (now.year() + 1, Month::January),
+                month => (now.year(), month.next()),
+            };
+            Date::from_calendar_date(year, month, 1).ok()
+        }
+    };
+    next.map_or(Duration::ZERO, |date| {
+        (date.midnight().assume_utc() - now).unsigned_abs()
+    })
+}
+
+/// Owner of a key created without one, unique so users without an owner aren't
+/// limited or grouped together
+fn anonymous_owner_id() -> String {
+    format!("anonymous_{}", uuid::Uuid::new_v4().simple())
+}
+
+/// Takes a generation slot for the owner of `key`, rate limited when none frees up
+///
+/// A rate limited key gets back the call its verification spent.
+async fn owner_permit(
+    limiter: &OwnerConcurrencyLimiter,
+    unkey: &UnkeyService,
+    key: &VerifiedKey,
+    request_id: &RequestId,
+) -> Result<OwnerPermit, ApiError> {
+    match limiter.acquire(key.owner()).await {
+        Some(permit) => Ok(permit),
+        None => {
+            refund_verification(unkey, key, request_id).await;
+            Err(ApiError::RateLimited(
+                "Too many image generations in progress, try again later.".into(),
+            )
+            .retry_after(OWNER_PERMIT_WAIT))
+        }
+    }
+}
+
+/// Describes the generated images so clients can lay them out without parsing `size`
+fn image_meta(size: &str, model: Option<&str>) -> Value {
+    // Sizes are validated as `<width>x<height>` before generating anything
+    let (width, height) = size
+        .split_once('x')
+        .and_then(|(width, height)| Some((width.parse::<u32>().ok()?, height.parse::<u32>().ok()?)))
+        .unzip();
+    json::json!({ "width": width, "height": height, "model": model })
+}
+
+/// Builds an HTTP client failing requests that take longer than `timeout_secs`
+fn http_client(timeout_secs: u64) -> reqwest::Result<Client> {
+    Client::builder()
+        .connect_timeout(HTTP_CONNECT_TIMEOUT)
+        .timeout(Duration::from_secs(timeout_secs))
+        .build()
+}
+
+/// Fails endpoints creating keys when `allow_key_creation` is off
+fn require_key_creation(config: &AppConfig) -> Result<(), ApiError> {
+    if config.allow_key_creation {
+        Ok(())
+    } else {
+        Err(ApiError::Forbidden(
+            "Key creation is disabled on this deployment, use a key issued to you.".into(),
+        ))
+    }
+}
+
+/// Reads the session key from its private cookie
+///
+/// The cookie is encrypted and signed with Rocket's `secret_key`, so a missing
+/// cookie, a tampered one or a plaintext one set by an older version is
+/// unauthorized. A cookie that decrypts to anything but a session is a bad request.
+fn parse_session(jar: &CookieJar<'_>, config: &AppConfig) -> Result<KeyCreateData, ApiError> {
+    let cookie = jar
+        .get_private(&config.cookie_name)
+        .ok_or_else(|| ApiError::Unauthorized("Missing API key in cookies.".into()))?;
+    json::from_str::<KeyCreateData>(cookie.value())
+        .ok()
+        .filter(|data| !data.key.expose().is_empty() && !data.key_id.is_empty())
+        .ok_or_else(|| ApiError::BadRequest("Invalid API key format in cookies.".into()))
+}
+
+/// Builds the HTTP-only cookie holding the session key
+fn session_cookie(config: &AppConfig, data: &KeyCreateData) -> Cookie<'static> {
+    let value = json::to_string(data).unwrap();
+    let cookie = Cookie::build((config.cookie_name.clone(), value))
+        .path(config.cookie_path.clone())
+        .http_only(true)
+        .secure(config.cookie_secure)
+        .same_site(SameSite::Strict);
+    match config.cookie_max_age_secs {
+        Some(secs) => cookie
+            .max_age(rocket::time::Duration::seconds(secs as i64))
+            .build(),
+        None => cookie.build(),
+    }
+}
+
+/// Builds the cookie removing the session, which has to match its name and path
+fn removal_cookie(config: &AppConfig) -> Cookie<'static> {
+    Cookie::build(config.cookie_name.clone())
+        .path(config.cookie_path.clone())
+        .build()
+}
+
+/// Builds the placeholder images returned by dry runs, in the requested format
+fn placeholder_images(size: &str, n: u8, response_format: &str) -> Vec<GeneratedImage> {
+    (0..n)
+        .map(|_| match response_format {
+            "b64_json" => GeneratedImage::base64(DRY_RUN_IMAGE_B64),
+            _ => GeneratedImage::url(format!("https://placehold.co/{}.png?text=dry+run", size)),
+        })
+        .collect()
+}
+
+/// Helper function to reject empty or overly long prompts before calling OpenAI
+///
+/// Returns the effective prompt, wrapped in the configured prefix and suffix, which
+/// has to fit in the maximum length along with them.
+fn validate_prompt(config: &AppConfig, prompt: &str) -> Result<String, ApiError> {
+    if prompt.trim().is_empty() {
+        return Err(ApiError::BadRequest(
+            "Invalid prompt: the prompt must not be empty.".into(),
+        ));
+    }
+    let prefix = config.prompt_prefix.as_deref().unwrap_or_default();
+    let suffix = config.prompt_suffix.as_deref().unwrap_or_default();
+    let effective_prompt = format!("{prefix}{prompt}{suffix}");
+    if effective_prompt.chars().count() > config.max_prompt_length {
+        let allowed = config
+            .max_prompt_length
+            .saturating_sub(prefix.chars().count() + suffix.chars().count());
+        return Err(ApiError::BadRequest(format!(
+            "Invalid prompt: the prompt must be at most {allowed} characters long."
+        )));
+    }
+    Ok(effective_prompt)
+}
+
+/// Rejects prompts flagged by OpenAI's moderation, when enabled
+///
+/// A failed moderation request rejects the prompt too, rather than letting it through unchecked.
+async fn moderate_prompt(
+    config: &AppConfig,
+    openai: &OpenAiClient,
+    request_id: &RequestId,
+    prompt: &str,
+) -> Result<(), ApiError> {
+    if !config.enable_moderation {
+        return Ok(());
+    }
+
+    let categories = openai
+        .moderate(prompt)
+        .instrument(request_id.span())
+        .await
+        .map_err(|e| {
+            tracing::error!(request_id = %request_id, error = %e, "failed to moderate prompt");
+            ApiError::BadGateway("Unable to moderate the prompt.".into())
+        })?;
+    if categories.is_empty() {
+        Ok(())
+    } else {
+        tracing::info!(request_id = %request_id, ?categories, "prompt flagged by moderation");
+        Err(ApiError::ContentFlagged(categories))
+    }
+}
+
+/// Checks the image parameters against what the configured image provider accepts
+fn validate_image_params(
+    provider: ImageProviderKind,
+    size: &str,
+    model: Option<&str>,
+    n: u8,
+    response_format: &str,
+) -> Result<(), ApiError> {
+    let allowed_sizes: &[&str] = match provider {
+        ImageProviderKind::OpenAI => &ALLOWED_IMAGE_SIZES,
+        ImageProviderKind::Stability => &STABILITY_IMAGE_SIZES,
+    };
+    if !allowed_sizes.contains(&size) {
+        return Err(ApiError::BadRequest(format!(
+            "Invalid image size: expected one of {}.",
+            allowed_sizes.join(", ")
+        )));
+    }
+
+    // Stability ignores the model
+    if let Some(model) = model.filter(|_| provider == ImageProviderKind::OpenAI) {
+        if !ALLOWED_IMAGE_MODELS.contains(&model) {
+            return Err(ApiError::BadRequest(format!(
+                "Invalid model: expected one of {}.",
+                ALLOWED_IMAGE_MODELS.join(", ")
+            )));
+        }
+        if model == "dall-e-3" && n > 1 {
+            return Err(ApiError::BadRequest(
+                "Invalid image count: dall-e-3 only supports generating a single image (n = 1)."
+                    .into(),
+            ));
+        }
+
+        // Without a model, OpenAI picks one and any of the sizes may be valid
+        let model_sizes: &[&str] = match model {
+            "dall-e-3" => &DALL_E_3_SIZES,
+            _ => &DALL_E_2_SIZES,
+        };
+        if !model_sizes.contains(&size) {
+            return Err(ApiError::BadRequest(format!(
+                "Invalid image size for {}: expected one of {}.",
+                model,
+                model_sizes.join(", ")
+            )));
+        }
+    }
+
+    if !ALLOWED_RESPONSE_FORMATS.contains(&response_format) {
+        return Err(ApiError::BadRequest(format!(
+            "Invalid response format: expected one of {}.",
+            ALLOWED_RESPONSE_FORMATS.join(", ")
+        )));
+    }
+
+    Ok(())
+}
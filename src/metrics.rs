@@ -0,0 +1,139 @@
+//! Prometheus metrics recorded by the routes and the Unkey and OpenAI integrations
+
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+use std::time::{Duration, Instant};
+
+use crate::circuit_breaker::CircuitState;
+
+/// Counters and latency histograms exposed by the metrics endpoint
+pub struct Metrics {
+    registry: Registry,
+    keys_created: IntCounter,
+    verifications: IntCounterVec,
+    images_generated: IntCounter,
+    openai_errors: IntCounterVec,
+    openai_latency: Histogram,
+    unkey_latency: HistogramVec,
+    unkey_circuit_state: IntGauge,
+    http_latency: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new();
+
+        let keys_created = IntCounter::new("keys_created_total", "API keys created in Unkey")?;
+        let verifications = IntCounterVec::new(
+            Opts::new("key_verifications_total", "API key verifications by result"),
+            &["result"],
+        )?;
+        let images_generated = IntCounter::new(
+            "images_generated_total",
+            "Images generated by the image provider",
+        )?;
+        let openai_errors = IntCounterVec::new(
+            Opts::new(
+                "openai_errors_total",
+                "Failed image provider requests by type",
+            ),
+            &["type"],
+        )?;
+        let openai_latency = Histogram::with_opts(HistogramOpts::new(
+            "openai_request_duration_seconds",
+            "Latency of image provider requests, retries included",
+        ))?;
+        let unkey_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "unkey_request_duration_seconds",
+                "Latency of Unkey calls by operation",
+            ),
+            &["operation"],
+        )?;
+        let unkey_circuit_state = IntGauge::new(
+            "unkey_circuit_state",
+            "State of the Unkey circuit breaker: 0 closed, 1 open, 2 half-open",
+        )?;
+
+        let http_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Latency of HTTP requests by method, route template and status",
+            ),
+            &["method", "route", "status"],
+        )?;
+
+        registry.register(Box::new(keys_created.clone()))?;
+        registry.register(Box::new(verifications.clone()))?;
+        registry.register(Box::new(images_generated.clone()))?;
+        registry.register(Box::new(openai_errors.clone()))?;
+        registry.register(Box::new(openai_latency.clone()))?;
+        registry.register(Box::new(unkey_latency.clone()))?;
+        registry.register(Box::new(unkey_circuit_state.clone()))?;
+        registry.register(Box::new(http_latency.clone()))?;
+
+        Ok(Self {
+            registry,
+            keys_created,
+            verifications,
+            images_generated,
+            openai_errors,
+            openai_latency,
+            unkey_latency,
+            unkey_circuit_state,
+            http_latency,
+        })
+    }
+
+    pub fn key_created(&self) {
+        self.keys_created.inc();
+    }
+
+    /// Counts a verification, `result` is `valid`, `invalid` or `error`
+    pub fn key_verified(&self, result: &str) {
+        self.verifications.with_label_values(&[result]).inc();
+    }
+
+    pub fn images_generated(&self, count: usize) {
+        self.images_generated.inc_by(count as u64);
+    }
+
+    pub fn openai_error(&self, kind: &str) {
+        self.openai_errors.with_label_values(&[kind]).inc();
+    }
+
+    /// Records the latency of an image provider request started at `started`
+    pub fn observe_openai(&self, started: Instant) {
+        self.openai_latency.observe(started.elapsed().as_secs_f64());
+    }
+
+    /// Records the latency of an Unkey `operation` started at `started`
+    pub fn observe_unkey(&self, operation: &str, started: Instant) {
+        self.unkey_latency
+            .with_label_values(&[operation])
+            .observe(started.elapsed().as_secs_f64());
+    }
+
+    pub fn unkey_circuit(&self, state: CircuitState) {
+        self.unkey_circuit_state.set(state.as_gauge());
+    }
+
+    /// Records the latency of a request to `route`, a template like `/keys/<key_id>`
+    pub fn observe_http(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+        self.http_latency
+            .with_label_values(&[method, route, &status.to_string()])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Renders every metric in Prometheus' text exposition format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        // Encoding into a Vec can't fail on I/O, only on malformed metric families
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            tracing::error!(error = ?e, "failed to encode metrics");
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
@@ -0,0 +1,211 @@
+//! Serde and form models shared by the endpoints and the Unkey helpers
+
+use rocket::form::FromForm;
+use rocket::serde::json::Value;
+use rocket::serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+use unkey::models::{ErrorCode, RefillInterval};
+
+use crate::secret::SecretKey;
+
+/// Struct for data returned upon key creation
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct KeyCreateData {
+    pub key: SecretKey,
+    pub key_id: String,
+}
+
+/// Struct for data returned upon key verification
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct KeyVerifyData {
+    pub valid: bool,
+    pub key_id: Option<String>,
+    pub owner_id: Option<String>,
+    pub remaining: Option<usize>,
+    pub ratelimit_remaining: Option<usize>,
+    /// Reason given by Unkey for the verification result
+    #[serde(skip)]
+    pub code: Option<ErrorCode>,
+    /// When the key expires, as a unix timestamp in milliseconds
+    pub expires: Option<usize>,
+    /// When the key's ratelimit window resets, as a unix timestamp in milliseconds
+    pub ratelimit_reset: Option<usize>,
+    /// How often the key's remaining calls are refilled
+    pub refill_interval: Option<RefillInterval>,
+}
+
+/// Struct for data returned by the admin verification endpoint
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct KeyVerificationData {
+    pub valid: bool,
+    pub key_id: Option<String>,
+    pub owner_id: Option<String>,
+    pub remaining: Option<usize>,
+    pub ratelimit_remaining: Option<usize>,
+    /// Reason given by Unkey, e.g. `VALID` or `USAGE_EXCEEDED`
+    pub code: Option<String>,
+    /// When the key expires, as a unix timestamp in milliseconds
+    pub expires: Option<usize>,
+}
+
+impl From<KeyVerifyData> for KeyVerificationData {
+    fn from(data: KeyVerifyData) -> Self {
+        // The SDK's codes only implement Debug, spelled in camel case
+        let code = data.code.map(|code| {
+            let mut name = String::new();
+            for c in format!("{:?}", code).chars() {
+                if c.is_uppercase() && !name.is_empty() {
+                    name.push('_');
+                }
+                name.push(c.to_ascii_uppercase());
+            }
+            name
+        });
+        Self {
+            valid: data.valid,
+            key_id: data.key_id,
+            owner_id: data.owner_id,
+            remaining: data.remaining,
+            ratelimit_remaining: data.ratelimit_remaining,
+            code,
+            expires: data.expires,
+        }
+    }
+}
+
+/// Struct for data returned by the "me" endpoint
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct KeyInfoData {
+    pub key: SecretKey,
+    pub key_id: String,
+    pub remaining: Option<usize>,
+}
+
+/// Struct for data returned by the key details endpoint
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct KeyDetailsData {
+    pub key_id: String,
+    pub owner_id: Option<String>,
+    pub remaining: Option<usize>,
+    pub metadata: Option<Value>,
+    pub expires: Option<usize>,
+}
+
+impl KeyDetailsData {
+    /// Whether the key has calls left and hasn't expired yet
+    pub fn is_usable(&self) -> bool {
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_millis());
+        self.remaining != Some(0) && self.expires.is_none_or(|expires| expires as u128 > now_ms)
+    }
+}
+
+/// Struct for a page of keys returned by the admin key listing
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct KeyListData {
+    pub keys: Vec<KeyDetailsData>,
+    pub total: usize,
+    /// Cursor to pass back for the next page, `None` on the last page
+    pub cursor: Option<String>,
+}
+
+/// Verifications of a key during one day, as counted by Unkey's analytics
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct DailyUsage {
+    /// Start of the day in Unix milliseconds
+    pub time: u64,
+    pub success: u64,
+    pub rate_limited: u64,
+    pub usage_exceeded: u64,
+}
+
+/// Struct for data returned by the usage endpoint
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct KeyUsageData {
+    pub key_id: String,
+    pub start: u64,
+    pub end: u64,
+    pub days: Vec<DailyUsage>,
+}
+
+/// Optional request struct for the authorize endpoint
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct AuthorizeRequest {
+    pub owner_id: Option<String>,
+    pub metadata: Option<Value>,
+}
+
+/// Request struct for verifying any key from the admin endpoint
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct AdminVerifyBody {
+    pub key: SecretKey,
+}
+
+/// Request struct for updating a key's remaining calls
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct UpdateKeyBody {
+    pub remaining: u32,
+}
+
+/// Request struct for estimating a generation, with the parameters of `GenerateImageRequest`
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct EstimateImageRequest {
+    pub size: Option<String>,
+    pub model: Option<String>,
+    pub n: Option<u8>,
+}
+
+/// Struct for data returned by the generation estimate endpoint
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct GenerationEstimate {
+    pub model: String,
+    pub size: String,
+    pub n: u8,
+    /// OpenAI's price for the images in US dollars, `None` when it isn't known
+    pub estimated_cost_usd: Option<f64>,
+    /// Calls of the key the generation would consume
+    pub calls: usize,
+    pub remaining_calls: Option<usize>,
+    /// Whether the key has the calls left for the generation
+    pub sufficient_quota: bool,
+}
+
+/// Request struct for generating one image for each of several prompts
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct GenerateImageBatchRequest {
+    pub prompts: Vec<String>,
+}
+
+/// Request struct for image generation with OpenAI
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct GenerateImageRequest {
+    pub prompt: String,
+    pub size: Option<String>,
+    pub model: Option<String>,
+    pub n: Option<u8>,
+    pub response_format: Option<String>,
+}
+
+/// Request struct for image generation posted by an HTML form, with the
+/// `prompt` and `size` of `GenerateImageRequest`
+#[derive(FromForm)]
+pub struct GenerateImageForm {
+    pub prompt: String,
+    pub size: Option<String>,
+}
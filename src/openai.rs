@@ -0,0 +1,639 @@
+//! Client for OpenAI's image generation and moderation APIs
+
+use anyhow::Context;
+use rand::Rng;
+use reqwest::{Client, RequestBuilder, StatusCode};
+use rocket::serde::json;
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::metrics::Metrics;
+use crate::AppConfig;
+
+/// Response struct for OpenAI's image generation
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct OpenAIResponse {
+    pub data: Vec<ImageData>,
+}
+
+/// Struct to hold the URL or base64 data of the generated image
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ImageData {
+    pub url: Option<String>,
+    pub b64_json: Option<String>,
+    /// The prompt as DALL-E 3 rewrote it, dall-e-2 doesn't send one
+    pub revised_prompt: Option<String>,
+}
+
+/// Response struct for OpenAI's moderation
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ModerationResponse {
+    pub results: Vec<ModerationResult>,
+}
+
+/// Struct to hold whether an input was flagged, and for which categories
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ModerationResult {
+    pub flagged: bool,
+    pub categories: BTreeMap<String, bool>,
+}
+
+/// Error response struct returned by OpenAI's API
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct OpenAIErrorResponse {
+    pub error: OpenAIErrorBody,
+}
+
+/// Struct to hold the details of an OpenAI error
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct OpenAIErrorBody {
+    pub message: String,
+    pub code: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+}
+
+/// API serving the image generation requests
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum OpenAiProvider {
+    /// OpenAI's own API, authenticated with a bearer token
+    OpenAI,
+    /// Azure OpenAI, routed by deployment and authenticated with an `api-key` header
+    Azure,
+}
+
+/// Image generation parameters, already validated by the caller
+#[derive(Debug)]
+pub struct ImageRequest<'a> {
+    pub prompt: &'a str,
+    pub size: &'a str,
+    /// Model to use, OpenAI picks its default when `None`
+    pub model: Option<&'a str>,
+    pub n: u8,
+    pub response_format: &'a str,
+}
+
+/// Image generated in the requested response format
+#[derive(Debug)]
+pub struct GeneratedImage {
+    pub content: ImageContent,
+    /// How the model rewrote the prompt, only DALL-E 3 does
+    pub revised_prompt: Option<String>,
+}
+
+/// URL or base64 data of a generated image
+#[derive(Debug)]
+pub enum ImageContent {
+    Url(String),
+    Base64(String),
+}
+
+impl GeneratedImage {
+    /// Image found at `url`, without a revised prompt
+    pub fn url(url: impl Into<String>) -> Self {
+        Self {
+            content: ImageContent::Url(url.into()),
+            revised_prompt: None,
+        }
+    }
+
+    /// Image encoded as base64 `data`, without a revised prompt
+    pub fn base64(data: impl Into<String>) -> Self {
+        Self {
+            content: ImageContent::Base64(data.into()),
+            revised_prompt: None,
+        }
+    }
+
+    /// Returns the response keys used for a single image and a list of images
+    pub fn response_keys(&self) -> (&'static str, &'static str) {
+        match self.content {
+            ImageContent::Url(_) => ("image_url", "image_urls"),
+            ImageContent::Base64(_) => ("image_b64_json", "images_b64_json"),
+        }
+    }
+
+    /// Returns the URL or base64 data of the image
+    pub fn data(&self) -> &str {
+        match &self.content {
+            ImageContent::Url(data) | ImageContent::Base64(data) => data,
+        }
+    }
+}
+
+/// Error returned when requesting images from OpenAI
+#[derive(Debug)]
+pub enum OpenAIError {
+    /// The prompt was rejected by OpenAI's content policy
+    ContentPolicy(OpenAIErrorBody),
+    /// OpenAI is rate limiting our requests
+    RateLimited(OpenAIErrorBody),
+    /// The provider rejected the parameters of the user's request
+    InvalidRequest(OpenAIErrorBody),
+    /// Any other structured error returned by OpenAI
+    Api(StatusCode, OpenAIErrorBody),
+    /// OpenAI answered successfully but without any image
+    NoImage,
+    /// OpenAI didn't answer within the configured timeout
+    Timeout,
+    /// The request failed before OpenAI could answer it
+    Other(anyhow::Error),
+}
+
+impl OpenAIError {
+    /// Classifies an error response returned by OpenAI
+    fn from_response(status: StatusCode, body: OpenAIErrorBody) -> Self {
+        match body.code.as_deref() {
+            Some("content_policy_violation") => OpenAIError::ContentPolicy(body),
+            Some("rate_limit_exceeded") => OpenAIError::RateLimited(body),
+            _ if status == StatusCode::TOO_MANY_REQUESTS => OpenAIError::RateLimited(body),
+            _ => OpenAIError::Api(status, body),
+        }
+    }
+
+    /// Whether OpenAI failed rather than rejected the request, e.g. with a server
+    /// error, a timeout or a dropped connection
+    pub fn is_upstream_failure(&self) -> bool {
+        match self {
+            OpenAIError::Api(status, _) => status.is_server_error(),
+            OpenAIError::NoImage | OpenAIError::Timeout | OpenAIError::Other(_) => true,
+            OpenAIError::ContentPolicy(_)
+            | OpenAIError::RateLimited(_)
+            | OpenAIError::InvalidRequest(_) => false,
+        }
+    }
+
+    /// Returns the label of the error in metrics
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            OpenAIError::ContentPolicy(_) => "content_policy",
+            OpenAIError::RateLimited(_) => "rate_limited",
+            OpenAIError::InvalidRequest(_) => "invalid_request",
+            OpenAIError::Api(..) => "api",
+            OpenAIError::NoImage => "no_image",
+            OpenAIError::Timeout => "timeout",
+            OpenAIError::Other(_) => "other",
+        }
+    }
+}
+
+impl From<anyhow::Error> for OpenAIError {
+    fn from(e: anyhow::Error) -> Self {
+        // Tell timeouts apart, whichever step of the request they hit
+        match e.downcast_ref::<reqwest::Error>() {
+            Some(error) if error.is_timeout() => OpenAIError::Timeout,
+            _ => OpenAIError::Other(e),
+        }
+    }
+}
+
+impl std::fmt::Display for OpenAIError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OpenAIError::ContentPolicy(body)
+            | OpenAIError::RateLimited(body)
+            | OpenAIError::InvalidRequest(body) => {
+                write!(f, "OpenAI error {:?}: {}", body.code, body.message)
+            }
+            OpenAIError::Api(status, body) => {
+                write!(
+                    f,
+                    "OpenAI error {} ({:?}): {}",
+                    status, body.kind, body.message
+                )
+            }
+            OpenAIError::NoImage => write!(f, "No image returned by OpenAI"),
+            OpenAIError::Timeout => write!(f, "OpenAI timed out"),
+            OpenAIError::Other(e) => write!(f, "{:?}", e),
+        }
+    }
+}
+
+/// OpenAI client sharing the app's HTTP client and retry settings
+#[derive(Clone)]
+pub struct OpenAiClient {
+    http_client: Client,
+    provider: OpenAiProvider,
+    images_url: String,
+    models_url: String,
+    moderations_url: String,
+    api_key: String,
+    max_retries: u32,
+    retry_base_delay_ms: u64,
+    metrics: Arc<Metrics>,
+}
+
+impl OpenAiClient {
+    pub fn new(http_client: Client, config: &AppConfig, metrics: Arc<Metrics>) -> Self {
+        let base_url = config.openai_base_url.trim_end_matches('/');
+        let (images_url, models_url) = match config.openai_provider {
+            OpenAiProvider::OpenAI => (
+                format!("{}/images/generations", base_url),
+                format!("{}/models", base_url),
+            ),
+            // Azure routes requests by deployment and versions the API by query
+            OpenAiProvider::Azure => (
+                format!(
+                    "{}/openai/deployments/{}/images/generations?api-version={}",
+                    base_url,
+                    config.openai_deployment.as_deref().unwrap_or_default(),
+                    config.openai_api_version
+                ),
+                format!(
+                    "{}/openai/models?api-version={}",
+                    base_url, config.openai_api_version
+                ),
+            ),
+        };
+
+        Self {
+            http_client,
+            provider: config.openai_provider,
+            images_url,
+            models_url,
+            // Only OpenAI's own API serves moderations
+            moderations_url: format!("{}/moderations", base_url),
+            api_key: config.openai_api_key.expose().into(),
+            max_retries: config.openai_max_retries,
+            retry_base_delay_ms: config.openai_retry_base_delay_ms,
+            metrics,
+        }
+    }
+
+    /// Adds the API key to a request as the provider expects it
+    fn authenticate(&self, request: RequestBuilder) -> RequestBuilder {
+        match self.provider {
+            OpenAiProvider::OpenAI => request.bearer_auth(&self.api_key),
+            OpenAiProvider::Azure => request.header("api-key", &self.api_key),
+        }
+    }
+
+    /// Requests images from OpenAI, retrying transient failures
+    #[tracing::instrument(name = "openai_generate_image", skip_all, fields(n = req.n, size = req.size))]
+    pub async fn generate_image(
+        &self,
+        req: &ImageRequest<'_>,
+    ) -> Result<Vec<GeneratedImage>, OpenAIError> {
+        let started = Instant::now();
+        let result = self.request_images(req).await;
+        self.metrics.observe_openai(started);
+        match &result {
+            Ok(images) => self.metrics.images_generated(images.len()),
+            Err(e) => self.metrics.openai_error(e.kind()),
+        }
+        result
+    }
+
+    /// Sends the image request to OpenAI and parses its response
+    async fn request_images(
+        &self,
+        req: &ImageRequest<'_>,
+    ) -> Result<Vec<GeneratedImage>, OpenAIError> {
+        let mut body = json::json!({
+            "prompt": req.prompt,
+            "n": req.n, // Number of images to generate
+            "size": req.size,
+            "response_format": req.response_format
+        });
+        // Leave the model out to let OpenAI pick its default
+        if let Some(model) = req.model {
+            body["model"] = model.into();
+        }
+
+        // Send request to OpenAI API, retrying transient failures
+        let started = Instant::now();
+        let mut attempt = 0;
+        let response = loop {
+            let result = self
+                .authenticate(self.http_client.post(&self.images_url))
+                .json(&body)
+                .send()
+                .await;
+
+            let retryable = match &result {
+                Ok(res) => {
+                    res.status() == StatusCode::TOO_MANY_REQUESTS || res.status().is_server_error()
+                }
+                // A timed out request may still generate, and charge for, the images
+                Err(e) => e.is_connect(),
+            };
+            if !retryable || attempt >= self.max_retries {
+                break result.context("Failed to send request to OpenAI")?; // Handle potential request errors
+            }
+
+            attempt += 1;
+            let delay = retry_delay(self.retry_base_delay_ms, attempt);
+            tracing::warn!(
+                attempt,
+                delay_ms = delay.as_millis() as u64,
+                "retrying OpenAI request"
+            );
+            rocket::tokio::time::sleep(delay).await;
+        };
+
+        // Handle non-retryable or exhausted error statuses
+        let status = response.status();
+        tracing::info!(
+            status = status.as_u16(),
+            attempts = attempt + 1,
+            latency_ms = started.elapsed().as_millis() as u64,
+            "OpenAI responded"
+        );
+        if !status.is_success() {
+            return Err(error_from(response).await);
+        }
+
+        let text = response
+            .text()
+            .await
+            .context("Failed to read response from OpenAI")?;
+        let response: OpenAIResponse =
+            json::from_str(&text).context("Failed to deserialize response from OpenAI")?; // Handle potential deserialization errors
+
+        if response.data.is_empty() {
+            tracing::debug!(body = %text, "OpenAI returned no image");
+            return Err(OpenAIError::NoImage); // Handle case where no image is returned
+        }
+
+        response
+            .data
+            .into_iter()
+            .map(|image| {
+                let content = match (image.url, image.b64_json) {
+                    (Some(url), _) => ImageContent::Url(url),
+                    (None, Some(b64_json)) => ImageContent::Base64(b64_json),
+                    (None, None) => {
+                        return Err(anyhow::anyhow!("Image without data returned by OpenAI").into())
+                    }
+                };
+                Ok(GeneratedImage {
+                    content,
+                    revised_prompt: image.revised_prompt,
+                })
+            })
+            .collect()
+    }
+
+    /// Runs the prompt through OpenAI's moderation, returning the flagged categories
+    ///
+    /// An empty list means the prompt wasn't flagged.
+    #[tracing::instrument(name = "openai_moderate", skip_all)]
+    pub async fn moderate(&self, prompt: &str) -> Result<Vec<String>, OpenAIError> {
+        let started = Instant::now();
+        let response = self
+            .authenticate(self.http_client.post(&self.moderations_url))
+            .json(&json::json!({ "input": prompt }))
+            .send()
+            .await
+            .context("Failed to send moderation request to OpenAI")?;
+        if !response.status().is_success() {
+            return Err(error_from(response).await);
+        }
+
+        let response: ModerationResponse = response
+            .json()
+            .await
+            .context("Failed to deserialize moderation response from OpenAI")?;
+        let categories: Vec<String> = response
+            .results
+            .into_iter()
+            .filter(|result| result.flagged)
+            .flat_map(|result| result.categories)
+            .filter_map(|(category, flagged)| flagged.then_some(category))
+            .collect();
+        tracing::debug!(
+            flagged = !categories.is_empty(),
+            latency_ms = started.elapsed().as_millis() as u64,
+            "moderated prompt"
+        );
+        Ok(categories)
+    }
+
+    /// Checks that OpenAI's API responds to our credentials
+    pub async fn check(&self) -> bool {
+        self.authenticate(self.http_client.get(&self.models_url))
+            .send()
+            .await
+            .is_ok_and(|res| res.status().is_success())
+    }
+}
+
+/// Reads the error OpenAI answered with, keeping the raw body when it isn't structured
+async fn error_from(response: reqwest::Response) -> OpenAIError {
+    let status = response.status();
+    let text = match response.text().await {
+        Ok(text) => text,
+        Err(e) => {
+            return anyhow::Error::new(e)
+                .context("Failed to read error response from OpenAI")
+                .into()
+        }
+    };
+    match json::from_str::<OpenAIErrorResponse>(&text) {
+        Ok(res) => OpenAIError::from_response(status, res.error),
+        Err(_) => anyhow::anyhow!("OpenAI returned {}: {}", status, text).into(),
+    }
+}
+
+/// Helper function to compute an exponential backoff delay with random jitter
+fn retry_delay(base_delay_ms: u64, attempt: u32) -> Duration {
+    let backoff = base_delay_ms.saturating_mul(1 << (attempt - 1).min(16));
+    let jitter = rand::thread_rng().gen_range(0..=base_delay_ms);
+    Duration::from_millis(backoff.saturating_add(jitter))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{body_partial_json, header, method, path, query_param};
+    use wiremock::{Mock, MockServer, Request, ResponseTemplate};
+
+    /// Configuration sending OpenAI requests to `server`
+    fn figment(server: &MockServer) -> rocket::figment::Figment {
+        rocket::Config::figment()
+            .merge(("unkey_root_key", "unkey_root"))
+            .merge(("unkey_api_id", "api_test"))
+            .merge(("openai_api_key", "sk-test"))
+            .merge(("openai_max_retries", 0))
+            .merge(("openai_base_url", server.uri()))
+    }
+
+    fn client_from(figment: rocket::figment::Figment) -> OpenAiClient {
+        let config: AppConfig = figment.extract().unwrap();
+        OpenAiClient::new(Client::new(), &config, Arc::new(Metrics::new().unwrap()))
+    }
+
+    /// OpenAI client sending its requests to `server`
+    fn client(server: &MockServer) -> OpenAiClient {
+        client_from(figment(server))
+    }
+
+    fn request(n: u8, response_format: &str) -> ImageRequest<'_> {
+        ImageRequest {
+            prompt: "a red fox",
+            size: "512x512",
+            model: None,
+            n,
+            response_format,
+        }
+    }
+
+    #[rocket::async_test]
+    async fn sends_the_prompt_with_bearer_auth() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/images/generations"))
+            .and(header("Authorization", "Bearer sk-test"))
+            .and(body_partial_json(json::json!({
+                "prompt": "a red fox",
+                "n": 2,
+                "size": "512x512",
+                "response_format": "url"
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+                "created": 1,
+                "data": [{ "url": "https://images.test/1.png" }, { "url": "https://images.test/2.png" }]
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let images = client(&server)
+            .generate_image(&request(2, "url"))
+            .await
+            .unwrap();
+
+        let urls: Vec<&str> = images.iter().map(GeneratedImage::data).collect();
+        assert_eq!(
+            urls,
+            ["https://images.test/1.png", "https://images.test/2.png"]
+        );
+        assert!(matches!(images[0].content, ImageContent::Url(_)));
+    }
+
+    #[rocket::async_test]
+    async fn routes_azure_requests_by_deployment() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/openai/deployments/dalle3/images/generations"))
+            .and(query_param("api-version", "2024-02-01"))
+            .and(header("api-key", "sk-test"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+                "data": [{ "url": "https://images.test/azure.png" }]
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = client_from(
+            figment(&server)
+                .merge(("openai_provider", "azure"))
+                .merge(("openai_deployment", "dalle3")),
+        );
+        let images = client.generate_image(&request(1, "url")).await.unwrap();
+
+        assert_eq!(images[0].data(), "https://images.test/azure.png");
+    }
+
+    #[rocket::async_test]
+    async fn leaves_out_the_model_when_unset() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/images/generations"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+                "data": [{ "b64_json": "aGVsbG8=" }]
+            })))
+            .mount(&server)
+            .await;
+
+        let images = client(&server)
+            .generate_image(&request(1, "b64_json"))
+            .await
+            .unwrap();
+
+        assert!(matches!(&images[0].content, ImageContent::Base64(data) if data == "aGVsbG8="));
+        let requests: Vec<Request> = server.received_requests().await.unwrap();
+        let body: json::Value = json::from_slice(&requests[0].body).unwrap();
+        assert!(body.get("model").is_none());
+    }
+
+    #[rocket::async_test]
+    async fn classifies_content_policy_errors() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/images/generations"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(json::json!({
+                "error": {
+                    "message": "Your request was rejected by our safety system.",
+                    "code": "content_policy_violation",
+                    "type": "invalid_request_error"
+                }
+            })))
+            .mount(&server)
+            .await;
+
+        let error = client(&server)
+            .generate_image(&request(1, "url"))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(error, OpenAIError::ContentPolicy(_)));
+    }
+
+    #[rocket::async_test]
+    async fn reports_an_empty_response_as_no_image() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/images/generations"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({ "data": [] })))
+            .mount(&server)
+            .await;
+
+        let error = client(&server)
+            .generate_image(&request(1, "url"))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(error, OpenAIError::NoImage));
+    }
+
+    #[rocket::async_test]
+    async fn reports_a_slow_response_as_a_timeout_without_retrying() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/images/generations"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(
+                        json::json!({ "data": [{ "url": "https://images.test/1.png" }] }),
+                    )
+                    .set_delay(Duration::from_secs(2)),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        let config: AppConfig = figment(&server)
+            .merge(("openai_max_retries", 2))
+            .merge(("openai_retry_base_delay_ms", 1))
+            .extract()
+            .unwrap();
+        let http_client = Client::builder()
+            .timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let client = OpenAiClient::new(http_client, &config, Arc::new(Metrics::new().unwrap()));
+
+        let error = client.generate_image(&request(1, "url")).await.unwrap_err();
+
+        assert!(matches!(error, OpenAIError::Timeout));
+    }
+}
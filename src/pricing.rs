@@ -0,0 +1,35 @@
+//! Prices of OpenAI's image generations, for the cost estimates
+
+/// Model OpenAI generates with when the request names none
+pub const DEFAULT_MODEL: &str = "dall-e-2";
+
+/// Price in US dollars of one standard quality image, by model and size
+const PRICES: [(&str, &str, f64); 6] = [
+    ("dall-e-2", "256x256", 0.016),
+    ("dall-e-2", "512x512", 0.018),
+    ("dall-e-2", "1024x1024", 0.020),
+    ("dall-e-3", "1024x1024", 0.040),
+    ("dall-e-3", "1792x1024", 0.080),
+    ("dall-e-3", "1024x1792", 0.080),
+];
+
+/// Returns the price of one image of `size` generated with `model`, `None`
+/// when the table doesn't know the pair
+pub fn image_price(model: &str, size: &str) -> Option<f64> {
+    PRICES
+        .iter()
+        .find(|(m, s, _)| *m == model && *s == size)
+        .map(|(_, _, price)| *price)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prices_images_by_model_and_size() {
+        assert_eq!(image_price("dall-e-2", "512x512"), Some(0.018));
+        assert_eq!(image_price("dall-e-3", "1792x1024"), Some(0.080));
+        assert_eq!(image_price("dall-e-3", "256x256"), None);
+    }
+}
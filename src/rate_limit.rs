@@ -0,0 +1,78 @@
+//! In-memory token bucket limiting how often each client IP may call an endpoint
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Tokens left for a client and when they were last refilled
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket per client IP, refilled continuously up to `per_minute` tokens
+///
+/// Clones share the same buckets.
+#[derive(Clone)]
+pub struct IpRateLimiter {
+    per_minute: u32,
+    buckets: Arc<Mutex<HashMap<IpAddr, Bucket>>>,
+}
+
+impl IpRateLimiter {
+    /// Number of tracked clients above which full buckets are dropped
+    const MAX_TRACKED: usize = 10_000;
+
+    /// Creates a limiter allowing `per_minute` requests per IP, 0 disables it
+    pub fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Drops the buckets idle for a minute, which are full again, returning how
+    /// many were dropped
+    pub fn prune(&self) -> usize {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let before = buckets.len();
+        buckets.retain(|_, bucket| now - bucket.updated < Duration::from_secs(60));
+        before - buckets.len()
+    }
+
+    /// Takes a token for `ip`, returns how long until the next one when its bucket is empty
+    pub fn try_acquire(&self, ip: IpAddr) -> Result<(), Duration> {
+        if self.per_minute == 0 {
+            return Ok(());
+        }
+
+        let capacity = f64::from(self.per_minute);
+        let refill_per_sec = capacity / 60.0;
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        // Buckets idle for a minute are full again, so forgetting them changes nothing
+        if buckets.len() >= Self::MAX_TRACKED {
+            buckets.retain(|_, bucket| now - bucket.updated < Duration::from_secs(60));
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = (now - bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * refill_per_sec).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / refill_per_sec,
+            ))
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! Wrapper keeping secrets out of logs and serialized output
+
+use rocket::serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
+
+/// Secrets shorter than this are masked entirely, since their last characters
+/// would give away too much of them
+const MIN_PARTIAL_REVEAL_LEN: usize = 12;
+
+/// Number of trailing characters left visible in a redacted secret
+const REVEALED_CHARS: usize = 4;
+
+/// Configuration value that must never be printed
+///
+/// Both `Debug` and `Serialize` only show the redacted form, e.g. `****abcd`,
+/// so the only way to read the value is [`Secret::expose`].
+#[derive(Deserialize, Default, Clone, PartialEq, Eq)]
+#[serde(crate = "rocket::serde", transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    /// Returns the secret itself, only to hand it to the service it's meant for
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// Returns the secret masked but for its last characters, empty secrets stay empty
+    pub fn redacted(&self) -> String {
+        let chars = self.0.chars().count();
+        if chars == 0 {
+            String::new()
+        } else if chars < MIN_PARTIAL_REVEAL_LEN {
+            "****".into()
+        } else {
+            let last: String = self.0.chars().skip(chars - REVEALED_CHARS).collect();
+            format!("****{}", last)
+        }
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        Secret(value.into())
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret({})", self.redacted())
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.redacted())
+    }
+}
+
+/// API key of a user, which `Debug` and `Display` print as `****`
+///
+/// Unlike [`Secret`], it serializes to the key itself, since responses and the
+/// session cookie have to carry it to the user.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "rocket::serde", transparent)]
+pub struct SecretKey(String);
+
+impl SecretKey {
+    /// Returns the key itself, only to verify it or hand it to its user
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for SecretKey {
+    fn from(value: String) -> Self {
+        SecretKey(value)
+    }
+}
+
+impl From<&str> for SecretKey {
+    fn from(value: &str) -> Self {
+        SecretKey(value.into())
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretKey(****)")
+    }
+}
+
+impl fmt::Display for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("****")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_all_but_the_last_characters() {
+        let secret = Secret::from("unkey_3ZZ7faUrkfv1YAhffAcnuJxd");
+
+        assert_eq!(secret.redacted(), "****uJxd");
+        assert_eq!(format!("{:?}", secret), "Secret(****uJxd)");
+        assert_eq!(secret.expose(), "unkey_3ZZ7faUrkfv1YAhffAcnuJxd");
+    }
+
+    #[test]
+    fn prints_keys_masked_but_serializes_them() {
+        let key = SecretKey::from("sk_3ZZ7faUrkfv1YAhffAcnuJxd");
+
+        assert_eq!(format!("{:?} {}", key, key), "SecretKey(****) ****");
+        assert_eq!(
+            rocket::serde::json::to_string(&key).unwrap(),
+            r#""sk_3ZZ7faUrkfv1YAhffAcnuJxd""#
+        );
+    }
+
+    #[test]
+    fn masks_short_secrets_entirely() {
+        assert_eq!(Secret::from("admin_test").redacted(), "****");
+        assert_eq!(Secret::from("").redacted(), "");
+    }
+}
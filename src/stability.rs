@@ -0,0 +1,314 @@
+//! Image provider generating images with Stability AI's REST API
+
+use anyhow::Context;
+use reqwest::{Client, StatusCode};
+use rocket::serde::json;
+use rocket::serde::Deserialize;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::image_provider::{ImageError, ImageProvider};
+use crate::metrics::Metrics;
+use crate::openai::{GeneratedImage, ImageRequest, OpenAIErrorBody};
+use crate::AppConfig;
+
+/// Response struct for Stability's text-to-image generation
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct StabilityResponse {
+    artifacts: Vec<Artifact>,
+}
+
+/// Struct to hold the base64 data of a generated image
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde", rename_all = "camelCase")]
+struct Artifact {
+    base64: String,
+    finish_reason: String,
+}
+
+/// Error response struct returned by Stability's API
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct StabilityErrorResponse {
+    name: String,
+    message: String,
+}
+
+/// Stability AI client, selected with `image_provider = "stability"`
+///
+/// Stability always returns base64 images, whatever the requested format.
+pub struct StabilityProvider {
+    http_client: Client,
+    generation_url: String,
+    account_url: String,
+    api_key: String,
+    metrics: Arc<Metrics>,
+}
+
+impl StabilityProvider {
+    pub fn new(http_client: Client, config: &AppConfig, metrics: Arc<Metrics>) -> Self {
+        let base_url = config.stability_base_url.trim_end_matches('/');
+        Self {
+            http_client,
+            generation_url: format!(
+                "{}/v1/generation/{}/text-to-image",
+                base_url, config.stability_engine
+            ),
+            account_url: format!("{}/v1/user/account", base_url),
+            api_key: config.stability_api_key.expose().into(),
+            metrics,
+        }
+    }
+
+    /// Sends the image request to Stability and parses its response
+    async fn request_images(
+        &self,
+        req: &ImageRequest<'_>,
+    ) -> Result<Vec<GeneratedImage>, ImageError> {
+        // Sizes are validated as `<width>x<height>` before reaching the provider
+        let (width, height) = req
+            .size
+            .split_once('x')
+            .and_then(|(width, height)| {
+                Some((width.parse::<u32>().ok()?, height.parse::<u32>().ok()?))
+            })
+            .context("Invalid image size for Stability")?;
+        let body = json::json!({
+            "text_prompts": [{ "text": req.prompt }],
+            "width": width,
+            "height": height,
+            "samples": req.n
+        });
+
+        let started = Instant::now();
+        let response = self
+            .http_client
+            .post(&self.generation_url)
+            .bearer_auth(&self.api_key)
+            .header("Accept", "application/json")
+            .json(&body)
+            .send()
+            .await
+            .context("Failed to send request to Stability")?;
+
+        let status = response.status();
+        tracing::info!(
+            status = status.as_u16(),
+            latency_ms = started.elapsed().as_millis() as u64,
+            "Stability responded"
+        );
+        if !status.is_success() {
+            return Err(error_from(response).await);
+        }
+
+        let response: StabilityResponse = response
+            .json()
+            .await
+            .context("Failed to deserialize response from Stability")?;
+        if response.artifacts.is_empty() {
+            return Err(ImageError::NoImage);
+        }
+        // Stability blurs filtered images instead of failing the request
+        if response
+            .artifacts
+            .iter()
+            .any(|artifact| artifact.finish_reason == "CONTENT_FILTERED")
+        {
+            return Err(ImageError::ContentPolicy(OpenAIErrorBody {
+                message: "The prompt was rejected by Stability's content filter.".into(),
+                code: Some("content_policy_violation".into()),
+                kind: None,
+            }));
+        }
+
+        Ok(response
+            .artifacts
+            .into_iter()
+            .map(|artifact| GeneratedImage::base64(artifact.base64))
+            .collect())
+    }
+}
+
+#[rocket::async_trait]
+impl ImageProvider for StabilityProvider {
+    fn name(&self) -> &'static str {
+        "stability"
+    }
+
+    #[tracing::instrument(name = "stability_generate_image", skip_all, fields(n = req.n, size = req.size))]
+    async fn generate(&self, req: &ImageRequest<'_>) -> Result<Vec<GeneratedImage>, ImageError> {
+        let started = Instant::now();
+        let result = self.request_images(req).await;
+        self.metrics.observe_openai(started);
+        match &result {
+            Ok(images) => self.metrics.images_generated(images.len()),
+            Err(e) => self.metrics.openai_error(e.kind()),
+        }
+        result
+    }
+
+    async fn check(&self) -> bool {
+        self.http_client
+            .get(&self.account_url)
+            .bearer_auth(&self.api_key)
+            .send()
+            .await
+            .is_ok_and(|res| res.status().is_success())
+    }
+}
+
+/// Maps the error Stability answered with onto the variants shared by all providers
+async fn error_from(response: reqwest::Response) -> ImageError {
+    let status = response.status();
+    let text = match response.text().await {
+        Ok(text) => text,
+        Err(e) => {
+            return anyhow::Error::new(e)
+                .context("Failed to read error response from Stability")
+                .into()
+        }
+    };
+    let Ok(error) = json::from_str::<StabilityErrorResponse>(&text) else {
+        return anyhow::anyhow!("Stability returned {}: {}", status, text).into();
+    };
+
+    let body = OpenAIErrorBody {
+        message: error.message,
+        code: Some(error.name.clone()),
+        kind: None,
+    };
+    match error.name.as_str() {
+        // Stability rejects prompts caught by its moderation as invalid
+        "invalid_prompts" => ImageError::ContentPolicy(OpenAIErrorBody {
+            code: Some("content_policy_violation".into()),
+            ..body
+        }),
+        _ if status == StatusCode::TOO_MANY_REQUESTS => ImageError::RateLimited(body),
+        // Unsupported dimensions or samples for the engine
+        _ if status == StatusCode::BAD_REQUEST => ImageError::InvalidRequest(body),
+        // Our key or engine is wrong, that's on the provider's side for the user
+        _ if status.is_client_error() => {
+            anyhow::anyhow!("Stability returned {}: {}", status, body.message).into()
+        }
+        _ => ImageError::Api(status, body),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openai::ImageContent;
+    use wiremock::matchers::{body_partial_json, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    /// Stability provider sending its requests to `server`
+    fn provider(server: &MockServer) -> StabilityProvider {
+        let config: AppConfig = rocket::Config::figment()
+            .merge(("unkey_root_key", "unkey_root"))
+            .merge(("unkey_api_id", "api_test"))
+            .merge(("image_provider", "stability"))
+            .merge(("stability_api_key", "sk-stability"))
+            .merge(("stability_base_url", server.uri()))
+            .extract()
+            .unwrap();
+        StabilityProvider::new(Client::new(), &config, Arc::new(Metrics::new().unwrap()))
+    }
+
+    fn request(n: u8) -> ImageRequest<'static> {
+        ImageRequest {
+            prompt: "a red fox",
+            size: "1152x896",
+            model: None,
+            n,
+            response_format: "url",
+        }
+    }
+
+    #[rocket::async_test]
+    async fn maps_the_request_and_returns_base64_images() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path(
+                "/v1/generation/stable-diffusion-xl-1024-v1-0/text-to-image",
+            ))
+            .and(header("Authorization", "Bearer sk-stability"))
+            .and(body_partial_json(json::json!({
+                "text_prompts": [{ "text": "a red fox" }],
+                "width": 1152,
+                "height": 896,
+                "samples": 2
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+                "artifacts": [
+                    { "base64": "Zm94MQ==", "seed": 1, "finishReason": "SUCCESS" },
+                    { "base64": "Zm94Mg==", "seed": 2, "finishReason": "SUCCESS" }
+                ]
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let images = provider(&server).generate(&request(2)).await.unwrap();
+
+        assert_eq!(images.len(), 2);
+        assert!(matches!(&images[0].content, ImageContent::Base64(data) if data == "Zm94MQ=="));
+    }
+
+    #[rocket::async_test]
+    async fn normalizes_the_errors() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(json::json!({
+                "id": "err_1", "name": "invalid_prompts", "message": "Prompt was flagged"
+            })))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(429).set_body_json(json::json!({
+                "id": "err_2", "name": "rate_limit_exceeded", "message": "Slow down"
+            })))
+            .mount(&server)
+            .await;
+
+        let provider = provider(&server);
+        let flagged = provider.generate(&request(1)).await.unwrap_err();
+        let limited = provider.generate(&request(1)).await.unwrap_err();
+
+        assert!(matches!(flagged, ImageError::ContentPolicy(_)));
+        assert!(matches!(limited, ImageError::RateLimited(_)));
+        let metrics = provider.metrics.render();
+        assert!(metrics.contains(r#"openai_errors_total{type="content_policy"} 1"#));
+        assert!(metrics.contains(r#"openai_errors_total{type="rate_limited"} 1"#));
+        assert!(metrics.contains("openai_request_duration_seconds_count 2"));
+    }
+
+    #[rocket::async_test]
+    async fn rejected_parameters_are_client_errors_and_bad_keys_upstream_failures() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(json::json!({
+                "id": "err_1", "name": "invalid_samples", "message": "Too many samples"
+            })))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(401).set_body_json(json::json!({
+                "id": "err_2", "name": "unauthorized", "message": "Missing API key"
+            })))
+            .mount(&server)
+            .await;
+
+        let provider = provider(&server);
+        let rejected = provider.generate(&request(1)).await.unwrap_err();
+        let unauthorized = provider.generate(&request(1)).await.unwrap_err();
+
+        assert!(
+            matches!(&rejected, ImageError::InvalidRequest(body) if body.message == "Too many samples")
+        );
+        assert!(!rejected.is_upstream_failure());
+        assert!(unauthorized.is_upstream_failure());
+    }
+}
@@ -0,0 +1,244 @@
+//! Fakes for tests booting the app, exposed to integration tests by the `testing` feature
+//!
+//! Fakes replace the services built from the config before they're handed to
+//! `build_rocket`, e.g.
+//!
+//! ```ignore
+//! let unkey = FakeUnkey::start().await;
+//! let mut services = Services::from_config(&config)?;
+//! services.unkey = unkey.service(services.metrics.clone());
+//! services.provider = Box::new(FakeImageProvider);
+//! let rocket = build_rocket(figment, config, services);
+//! ```
+
+use rocket::serde::json::{self, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use unkey::Client as UnkeyClient;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, Request, ResponseTemplate};
+
+use crate::{GeneratedImage, ImageError, ImageProvider, ImageRequest, Metrics, UnkeyService};
+
+/// URL of every image generated by [`FakeImageProvider`]
+pub const FAKE_IMAGE_URL: &str = "https://images.test/fake.png";
+
+/// Provider answering every request with [`FAKE_IMAGE_URL`], without calling out
+pub struct FakeImageProvider;
+
+#[rocket::async_trait]
+impl ImageProvider for FakeImageProvider {
+    fn name(&self) -> &'static str {
+        "fake"
+    }
+
+    async fn generate(&self, req: &ImageRequest<'_>) -> Result<Vec<GeneratedImage>, ImageError> {
+        Ok((0..req.n)
+            .map(|_| GeneratedImage::url(FAKE_IMAGE_URL))
+            .collect())
+    }
+
+    async fn check(&self) -> bool {
+        true
+    }
+}
+
+/// Builds a service calling a fake Unkey server at `base_url`, e.g. a mock server
+pub fn unkey_service(base_url: &str, root_key: &str, metrics: Arc<Metrics>) -> UnkeyService {
+    UnkeyService::new(UnkeyClient::with_url(root_key, base_url), metrics)
+        .with_url(base_url)
+        .with_root_key(root_key)
+}
+
+/// Key stored by [`FakeUnkey`]
+#[derive(Clone, Debug)]
+struct FakeKey {
+    key_id: String,
+    owner_id: Option<String>,
+    remaining: Option<usize>,
+}
+
+/// Keys of a [`FakeUnkey`], by key
+#[derive(Default)]
+struct FakeKeys {
+    by_key: HashMap<String, FakeKey>,
+    created: usize,
+}
+
+impl FakeKeys {
+    fn insert(&mut self, key: &str, owner_id: Option<&str>, remaining: Option<usize>) -> String {
+        self.created += 1;
+        let key_id = format!("key_fake_{}", self.created);
+        self.by_key.insert(
+            key.into(),
+            FakeKey {
+                key_id: key_id.clone(),
+                owner_id: owner_id.map(Into::into),
+                remaining,
+            },
+        );
+        key_id
+    }
+
+    fn by_id(&mut self, key_id: &str) -> Option<&mut FakeKey> {
+        self.by_key.values_mut().find(|key| key.key_id == key_id)
+    }
+}
+
+/// Answers a request to one of the endpoints of [`FakeUnkey`]
+type Endpoint = fn(&mut FakeKeys, &Request) -> ResponseTemplate;
+
+/// Unkey server keeping its keys in memory, for the endpoints the app calls
+///
+/// Creating, verifying, getting, deleting keys and updating their remaining
+/// calls behave like Unkey's: each verification spends a call, and a key out of
+/// calls or deleted stops verifying. Permissions are granted to every key, and
+/// other endpoints answer `404`.
+pub struct FakeUnkey {
+    server: MockServer,
+    keys: Arc<Mutex<FakeKeys>>,
+}
+
+impl FakeUnkey {
+    /// Starts the server, without any key
+    pub async fn start() -> Self {
+        let server = MockServer::start().await;
+        let keys = Arc::new(Mutex::new(FakeKeys::default()));
+
+        let routes: [(&str, &str, Endpoint); 6] = [
+            ("POST", "/keys.createKey", create_key),
+            ("POST", "/keys.verifyKey", verify_key),
+            ("POST", "/keys.updateRemaining", update_remaining),
+            ("GET", "/keys.getKey", get_key),
+            ("POST", "/keys.deleteKey", delete_key),
+            ("GET", "/apis.getApi", get_api),
+        ];
+        for (verb, route, respond) in routes {
+            let keys = keys.clone();
+            Mock::given(method(verb))
+                .and(path(route))
+                .respond_with(move |req: &Request| respond(&mut keys.lock().unwrap(), req))
+                .mount(&server)
+                .await;
+        }
+
+        Self { server, keys }
+    }
+
+    /// Builds a service calling this server
+    pub fn service(&self, metrics: Arc<Metrics>) -> UnkeyService {
+        unkey_service(&self.server.uri(), "unkey_root", metrics)
+    }
+
+    /// Adds a key with `remaining` calls, unlimited when `None`, returning its id
+    pub fn insert(&self, key: &str, owner_id: Option<&str>, remaining: Option<usize>) -> String {
+        self.keys.lock().unwrap().insert(key, owner_id, remaining)
+    }
+
+    /// Remaining calls of a key, `None` when it's unlimited or doesn't exist
+    pub fn remaining(&self, key_id: &str) -> Option<usize> {
+        self.keys.lock().unwrap().by_id(key_id)?.remaining
+    }
+}
+
+/// Unkey's error response
+fn error(status: u16, code: &str, message: &str) -> ResponseTemplate {
+    ResponseTemplate::new(status)
+        .set_body_json(json::json!({ "error": { "code": code, "message": message } }))
+}
+
+fn body(req: &Request) -> Value {
+    req.body_json().unwrap_or_default()
+}
+
+fn create_key(keys: &mut FakeKeys, req: &Request) -> ResponseTemplate {
+    let body = body(req);
+    let key = format!("sk_fake_{}", uuid::Uuid::new_v4().simple());
+    let remaining = body["remaining"]
+        .as_u64()
+        .map(|remaining| remaining as usize);
+    let key_id = keys.insert(&key, body["ownerId"].as_str(), remaining);
+    ResponseTemplate::new(200).set_body_json(json::json!({ "keyId": key_id, "key": key }))
+}
+
+fn verify_key(keys: &mut FakeKeys, req: &Request) -> ResponseTemplate {
+    let body = body(req);
+    let Some(key) = body["key"]
+        .as_str()
+        .and_then(|key| keys.by_key.get_mut(key))
+    else {
+        return ResponseTemplate::new(200)
+            .set_body_json(json::json!({ "valid": false, "code": "NOT_FOUND" }));
+    };
+
+    let (valid, code) = match &mut key.remaining {
+        Some(0) => (false, "USAGE_EXCEEDED"),
+        Some(remaining) => {
+            *remaining -= 1;
+            (true, "VALID")
+        }
+        None => (true, "VALID"),
+    };
+    ResponseTemplate::new(200).set_body_json(json::json!({
+        "valid": valid,
+        "keyId": key.key_id,
+        "ownerId": key.owner_id,
+        "remaining": key.remaining,
+        "code": code
+    }))
+}
+
+fn update_remaining(keys: &mut FakeKeys, req: &Request) -> ResponseTemplate {
+    let body = body(req);
+    let Some(key) = body["keyId"].as_str().and_then(|key_id| keys.by_id(key_id)) else {
+        return error(404, "NOT_FOUND", "key not found");
+    };
+    let value = body["value"].as_u64().unwrap_or_default() as usize;
+    let remaining = key.remaining.unwrap_or_default();
+    let remaining = match body["op"].as_str() {
+        Some("increment") => remaining + value,
+        Some("decrement") => remaining.saturating_sub(value),
+        Some("set") => value,
+        _ => return error(400, "BAD_REQUEST", "unknown op"),
+    };
+    key.remaining = Some(remaining);
+    ResponseTemplate::new(200).set_body_json(json::json!({ "remaining": remaining }))
+}
+
+fn get_key(keys: &mut FakeKeys, req: &Request) -> ResponseTemplate {
+    let key_id = req
+        .url
+        .query_pairs()
+        .find(|(name, _)| name == "keyId")
+        .map(|(_, key_id)| key_id.into_owned());
+    let Some(key) = key_id.and_then(|key_id| keys.by_id(&key_id)) else {
+        return error(404, "NOT_FOUND", "key not found");
+    };
+    ResponseTemplate::new(200).set_body_json(json::json!({
+        "id": key.key_id,
+        "apiId": "api_fake",
+        "workspaceId": "ws_fake",
+        "start": "sk_fake",
+        "ownerId": key.owner_id,
+        "createdAt": 0,
+        "remaining": key.remaining
+    }))
+}
+
+fn delete_key(keys: &mut FakeKeys, req: &Request) -> ResponseTemplate {
+    let body = body(req);
+    let key_id = body["keyId"].as_str().unwrap_or_default();
+    let before = keys.by_key.len();
+    keys.by_key.retain(|_, key| key.key_id != key_id);
+    if keys.by_key.len() == before {
+        return error(404, "NOT_FOUND", "key not found");
+    }
+    ResponseTemplate::new(200).set_body_json(json::json!({}))
+}
+
+fn get_api(_: &mut FakeKeys, _: &Request) -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_json(json::json!({
+        "id": "api_fake", "name": "fake", "workspaceId": "ws_fake"
+    }))
+}
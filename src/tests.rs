@@ -0,0 +1,2736 @@
+use flate2::read::GzDecoder;
+use rocket::error::ErrorKind;
+use rocket::figment::value::Dict;
+use rocket::figment::Figment;
+use rocket::http::{Accept, ContentType, Cookie, Header, Status};
+use rocket::local::asynchronous::Client as LocalClient;
+use rocket::serde::json::{self, Value};
+use rocket::{Build, Rocket};
+use std::sync::Arc;
+use std::time::Duration;
+use unkey::Client as UnkeyClient;
+use wiremock::matchers::{body_partial_json, header, method, path, query_param};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use crate::image_provider::ImageProviderKind;
+use crate::testing::{self, FakeImageProvider};
+use crate::{
+    build_rocket, negotiate_encoding, validate_image_params, AppConfig, ImageProvider, Metrics,
+    SecretKey, Services, UnkeyService, VerifyError,
+};
+
+/// Mock Unkey and OpenAI servers behind a local client of the app
+struct TestApp {
+    client: LocalClient,
+    unkey: MockServer,
+    openai: MockServer,
+}
+
+impl TestApp {
+    async fn new() -> Self {
+        Self::with_config(|figment| figment).await
+    }
+
+    /// Boots the app with extra configuration applied on top of the test defaults
+    async fn with_config(configure: impl FnOnce(Figment) -> Figment) -> Self {
+        Self::start(configure, None).await
+    }
+
+    /// Boots the app generating images with `provider` instead of the mock OpenAI server
+    async fn with_provider(provider: Box<dyn ImageProvider>) -> Self {
+        Self::start(|figment| figment, Some(provider)).await
+    }
+
+    async fn start(
+        configure: impl FnOnce(Figment) -> Figment,
+        provider: Option<Box<dyn ImageProvider>>,
+    ) -> Self {
+        let unkey = MockServer::start().await;
+        let openai = MockServer::start().await;
+
+        let figment = rocket::Config::figment()
+            .merge(("log_level", "off"))
+            .merge(("unkey_root_key", "unkey_root"))
+            .merge(("unkey_api_id", "api_test"))
+            .merge(("openai_api_key", "sk-test"))
+            .merge(("cookie_secure", false))
+            .merge(("openai_max_retries", 0))
+            .merge(("openai_base_url", openai.uri()))
+            .merge(("database_url", "sqlite::memory:"));
+        let figment = configure(figment);
+        let config: AppConfig = figment.extract().expect("valid test config");
+
+        let mut services = Services::from_config(&config).expect("valid test services");
+        services.unkey =
+            testing::unkey_service(&unkey.uri(), "unkey_root", services.metrics.clone())
+                .with_verify_cache_ttl(Duration::from_millis(config.verify_cache_ttl_ms));
+        if let Some(provider) = provider {
+            services.provider = provider;
+        }
+
+        let rocket = build_rocket(figment, config, services);
+        let client = LocalClient::tracked(rocket).await.expect("valid rocket");
+
+        TestApp {
+            client,
+            unkey,
+            openai,
+        }
+    }
+
+    /// Stubs Unkey's verify endpoint with the given response body
+    async fn mock_verify(&self, body: Value) {
+        Mock::given(method("POST"))
+            .and(path("/keys.verifyKey"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(body))
+            .mount(&self.unkey)
+            .await;
+    }
+}
+
+/// Cookie holding a session for `sk_test`, as set by the authorize endpoint
+/// once encrypted with `private_cookie`
+fn session_cookie() -> Cookie<'static> {
+    let value = json::json!({ "key": "sk_test", "key_id": "key_test" }).to_string();
+    Cookie::new("unkey", value)
+}
+
+#[rocket::async_test]
+async fn authorize_sets_the_session_cookie() {
+    let app = TestApp::new().await;
+    Mock::given(method("POST"))
+        .and(path("/keys.createKey"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json::json!({ "keyId": "key_test", "key": "sk_test" })),
+        )
+        .mount(&app.unkey)
+        .await;
+
+    let res = app.client.post("/authorize").dispatch().await;
+
+    assert_eq!(res.status(), Status::SeeOther);
+    assert_eq!(res.headers().get_one("Location"), Some("/me"));
+    let cookie = res.cookies().get_private("unkey").expect("session cookie");
+    assert_eq!(cookie.http_only(), Some(true));
+    let session: Value = json::from_str(cookie.value()).unwrap();
+    assert_eq!(session["key"], "sk_test");
+    assert_eq!(session["key_id"], "key_test");
+}
+
+#[rocket::async_test]
+async fn authorize_can_require_a_user_agent() {
+    let app = TestApp::with_config(|figment| figment.merge(("require_user_agent", true))).await;
+    Mock::given(method("POST"))
+        .and(path("/keys.createKey"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json::json!({ "keyId": "key_test", "key": "sk_test" })),
+        )
+        .expect(1)
+        .mount(&app.unkey)
+        .await;
+
+    let res = app.client.post("/authorize").dispatch().await;
+    assert_eq!(res.status(), Status::BadRequest);
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(body["error"]["code"], "bad_request");
+
+    let res = app
+        .client
+        .post("/authorize")
+        .header(Header::new("User-Agent", "curl/8.5.0"))
+        .dispatch()
+        .await;
+    assert_eq!(res.status(), Status::SeeOther);
+}
+
+#[rocket::async_test]
+async fn authorize_reuses_a_usable_session() {
+    let app = TestApp::new().await;
+    Mock::given(method("GET"))
+        .and(path("/keys.getKey"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+            "id": "key_test",
+            "apiId": "api_test",
+            "workspaceId": "ws_test",
+            "start": "sk_",
+            "createdAt": 0,
+            "remaining": 3
+        })))
+        .mount(&app.unkey)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/keys.createKey"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.unkey)
+        .await;
+
+    let res = app
+        .client
+        .post("/authorize")
+        .private_cookie(session_cookie())
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::SeeOther);
+    assert_eq!(res.headers().get_one("Location"), Some("/me"));
+    assert!(res.cookies().get("unkey").is_none());
+}
+
+#[rocket::async_test]
+async fn session_cookie_uses_the_configured_name_path_and_max_age() {
+    let app = TestApp::with_config(|figment| {
+        figment
+            .merge(("cookie_name", "img_session"))
+            .merge(("cookie_path", "/api"))
+            .merge(("cookie_max_age_secs", 3600))
+    })
+    .await;
+    Mock::given(method("POST"))
+        .and(path("/keys.createKey"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json::json!({ "keyId": "key_test", "key": "sk_test" })),
+        )
+        .mount(&app.unkey)
+        .await;
+
+    let res = app.client.post("/authorize").dispatch().await;
+    // The app is served under the cookie's path, and so is the redirect
+    assert_eq!(res.headers().get_one("Location"), Some("/api/me"));
+    let cookie = res
+        .cookies()
+        .get_private("img_session")
+        .expect("session cookie");
+    assert_eq!(cookie.path(), Some("/api"));
+    assert_eq!(
+        cookie.max_age(),
+        Some(rocket::time::Duration::seconds(3600))
+    );
+
+    let logout = app
+        .client
+        .post("/logout")
+        .private_cookie(Cookie::new("img_session", cookie.value().to_string()))
+        .dispatch()
+        .await;
+    let removal = logout.cookies().get("img_session").expect("removal cookie");
+    assert_eq!(removal.path(), Some("/api"));
+    assert_eq!(removal.value(), "");
+}
+
+#[rocket::async_test]
+async fn key_creation_can_be_disabled() {
+    let app = TestApp::with_config(|figment| figment.merge(("allow_key_creation", false))).await;
+    Mock::given(method("POST"))
+        .and(path("/keys.createKey"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.unkey)
+        .await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "remaining": 4, "code": "VALID"
+    }))
+    .await;
+
+    let authorize = app.client.post("/authorize").dispatch().await;
+    let rotate = app
+        .client
+        .post("/rotate")
+        .private_cookie(session_cookie())
+        .dispatch()
+        .await;
+    let generate = app
+        .client
+        .post("/generate_image?dry_run=true")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"prompt": "a red fox"}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(authorize.status(), Status::Forbidden);
+    assert_eq!(rotate.status(), Status::Forbidden);
+    assert_eq!(generate.status(), Status::Ok);
+}
+
+#[rocket::async_test]
+async fn authorize_fails_when_unkey_rejects_the_key() {
+    let app = TestApp::new().await;
+    Mock::given(method("POST"))
+        .and(path("/keys.createKey"))
+        .respond_with(ResponseTemplate::new(401).set_body_json(json::json!({
+            "error": { "code": "UNAUTHORIZED", "message": "invalid root key" }
+        })))
+        .mount(&app.unkey)
+        .await;
+
+    let res = app.client.post("/authorize").dispatch().await;
+
+    assert_eq!(res.status(), Status::Unauthorized);
+    assert!(res.cookies().get("unkey").is_none());
+}
+
+#[rocket::async_test]
+async fn authorize_is_rate_limited_per_client_ip() {
+    let app =
+        TestApp::with_config(|figment| figment.merge(("authorize_rate_limit_per_minute", 1))).await;
+    Mock::given(method("POST"))
+        .and(path("/keys.createKey"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json::json!({ "keyId": "key_test", "key": "sk_test" })),
+        )
+        .mount(&app.unkey)
+        .await;
+
+    let first = app.client.post("/authorize").dispatch().await;
+    assert_eq!(first.status(), Status::SeeOther);
+
+    let second = app.client.post("/authorize").dispatch().await;
+    assert_eq!(second.status(), Status::TooManyRequests);
+    assert_eq!(second.headers().get_one("Retry-After"), Some("60"));
+    let body: Value = second.into_json().await.unwrap();
+    assert_eq!(body["error"]["code"], "rate_limited");
+}
+
+#[rocket::async_test]
+async fn authorize_rate_limit_ignores_spoofed_forwarded_entries() {
+    let app = TestApp::with_config(|figment| {
+        figment
+            .merge(("authorize_rate_limit_per_minute", 1))
+            .merge(("trust_proxy", true))
+    })
+    .await;
+    Mock::given(method("POST"))
+        .and(path("/keys.createKey"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json::json!({ "keyId": "key_test", "key": "sk_test" })),
+        )
+        .mount(&app.unkey)
+        .await;
+
+    let authorize = |forwarded_for: &'static str| {
+        app.client
+            .post("/authorize")
+            .header(Header::new("X-Forwarded-For", forwarded_for))
+            .dispatch()
+    };
+
+    assert_eq!(authorize("203.0.113.1").await.status(), Status::SeeOther);
+    assert_eq!(authorize("203.0.113.2").await.status(), Status::SeeOther);
+    let spoofed = authorize("198.51.100.7, 203.0.113.1").await;
+    assert_eq!(spoofed.status(), Status::TooManyRequests);
+}
+
+#[rocket::async_test]
+async fn authorize_rate_limit_ignores_x_real_ip_with_too_few_forwarded_entries() {
+    let app = TestApp::with_config(|figment| {
+        figment
+            .merge(("authorize_rate_limit_per_minute", 1))
+            .merge(("trust_proxy", true))
+            .merge(("trusted_proxy_hops", 2))
+    })
+    .await;
+    Mock::given(method("POST"))
+        .and(path("/keys.createKey"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json::json!({ "keyId": "key_test", "key": "sk_test" })),
+        )
+        .mount(&app.unkey)
+        .await;
+
+    // Either request could set any X-Real-IP, both count as the peer
+    let authorize = |real_ip: &'static str| {
+        app.client
+            .post("/authorize")
+            .header(Header::new("X-Forwarded-For", "203.0.113.1"))
+            .header(Header::new("X-Real-IP", real_ip))
+            .dispatch()
+    };
+
+    assert_eq!(authorize("198.51.100.1").await.status(), Status::SeeOther);
+    let spoofed = authorize("198.51.100.2").await;
+    assert_eq!(spoofed.status(), Status::TooManyRequests);
+}
+
+#[rocket::async_test]
+async fn me_returns_the_session_key() {
+    let app = TestApp::new().await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "remaining": 9, "code": "VALID"
+    }))
+    .await;
+
+    let res = app
+        .client
+        .get("/me")
+        .private_cookie(session_cookie())
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::Ok);
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(
+        body,
+        json::json!({ "key": "sk_test", "key_id": "key_test", "remaining": 9 })
+    );
+}
+
+#[rocket::async_test]
+async fn me_without_cookie_is_unauthorized() {
+    let app = TestApp::new().await;
+
+    let res = app.client.get("/me").dispatch().await;
+
+    assert_eq!(res.status(), Status::Unauthorized);
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(body["error"]["code"], "unauthorized");
+}
+
+#[rocket::async_test]
+async fn me_usage_returns_the_daily_verifications() {
+    let app = TestApp::with_config(|figment| figment.merge(("usage_analytics", true))).await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "remaining": 4, "code": "VALID"
+    }))
+    .await;
+    Mock::given(method("GET"))
+        .and(path("/keys.getVerifications"))
+        .and(header("Authorization", "Bearer unkey_root"))
+        .and(query_param("keyId", "key_test"))
+        .and(query_param("granularity", "day"))
+        .and(query_param("start", "1000"))
+        .and(query_param("end", "2000"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+            "verifications": [
+                { "time": 1000, "success": 3, "rateLimited": 1, "usageExceeded": 0 }
+            ]
+        })))
+        .mount(&app.unkey)
+        .await;
+
+    let res = app
+        .client
+        .get("/me/usage?start=1000&end=2000")
+        .private_cookie(session_cookie())
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::Ok);
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(body["key_id"], "key_test");
+    assert_eq!(
+        body["days"],
+        json::json!([{ "time": 1000, "success": 3, "rate_limited": 1, "usage_exceeded": 0 }])
+    );
+}
+
+#[rocket::async_test]
+async fn me_usage_is_not_implemented_without_analytics() {
+    let app = TestApp::new().await;
+
+    let res = app
+        .client
+        .get("/me/usage")
+        .private_cookie(session_cookie())
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::NotImplemented);
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(body["error"]["code"], "not_implemented");
+}
+
+#[rocket::async_test]
+async fn me_usage_is_not_implemented_when_unkey_refuses_analytics() {
+    let app = TestApp::with_config(|figment| figment.merge(("usage_analytics", true))).await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "remaining": 4, "code": "VALID"
+    }))
+    .await;
+    Mock::given(method("GET"))
+        .and(path("/keys.getVerifications"))
+        .respond_with(ResponseTemplate::new(403).set_body_json(json::json!({
+            "error": { "code": "FORBIDDEN", "message": "analytics aren't available" }
+        })))
+        .mount(&app.unkey)
+        .await;
+
+    let res = app
+        .client
+        .get("/me/usage")
+        .private_cookie(session_cookie())
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::NotImplemented);
+}
+
+#[rocket::async_test]
+async fn generate_image_with_malformed_cookie_is_bad_request() {
+    let app = TestApp::new().await;
+
+    let res = app
+        .client
+        .post("/generate_image")
+        .header(ContentType::JSON)
+        .private_cookie(Cookie::new("unkey", "not json"))
+        .body(r#"{"prompt": "a red fox"}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::BadRequest);
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(body["error"]["code"], "bad_request");
+}
+
+#[rocket::async_test]
+async fn invalid_sessions_are_bad_requests_everywhere() {
+    let app = TestApp::new().await;
+    let tampered = json::json!({ "key": "", "key_id": "key_test" }).to_string();
+
+    let me = app
+        .client
+        .get("/me")
+        .private_cookie(Cookie::new("unkey", tampered.clone()))
+        .dispatch()
+        .await;
+    let generate = app
+        .client
+        .post("/generate_image")
+        .header(ContentType::JSON)
+        .private_cookie(Cookie::new("unkey", tampered))
+        .body(r#"{"prompt": "a red fox"}"#)
+        .dispatch()
+        .await;
+
+    for res in [me, generate] {
+        assert_eq!(res.status(), Status::BadRequest);
+        let body: Value = res.into_json().await.unwrap();
+        assert_eq!(
+            body["error"]["message"],
+            "Invalid API key format in cookies."
+        );
+    }
+}
+
+#[rocket::async_test]
+async fn unencrypted_session_cookies_are_unauthenticated() {
+    let app = TestApp::new().await;
+
+    let me = app
+        .client
+        .get("/me")
+        .cookie(session_cookie())
+        .dispatch()
+        .await;
+    let generate = app
+        .client
+        .post("/generate_image")
+        .header(ContentType::JSON)
+        .cookie(session_cookie())
+        .body(r#"{"prompt": "a red fox"}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(me.status(), Status::Unauthorized);
+    assert_eq!(generate.status(), Status::Unauthorized);
+}
+
+#[rocket::async_test]
+async fn generate_image_returns_the_image_url() {
+    let app = TestApp::new().await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "remaining": 4, "code": "VALID"
+    }))
+    .await;
+    Mock::given(method("POST"))
+        .and(path("/images/generations"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+            "created": 1, "data": [{ "url": "https://images.test/fox.png" }]
+        })))
+        .mount(&app.openai)
+        .await;
+
+    let res = app
+        .client
+        .post("/generate_image")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"prompt": "a red fox"}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::Ok);
+    assert_eq!(res.headers().get_one("X-RateLimit-Remaining"), Some("4"));
+    assert_eq!(res.headers().get_one("X-RateLimit-Warning"), None);
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(body["image_url"], "https://images.test/fox.png");
+    assert_eq!(body["remaining_calls"], 4);
+    assert_eq!(body.get("revised_prompt"), Some(&Value::Null));
+    assert_eq!(
+        body["request"],
+        json::json!({
+            "prompt": "a red fox",
+            "effective_prompt": "a red fox",
+            "size": "1024x1024",
+            "model": null,
+            "n": 1
+        })
+    );
+}
+
+#[rocket::async_test]
+async fn generate_image_serves_the_raw_png_when_accepted() {
+    let app = TestApp::new().await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "remaining": 4, "code": "VALID"
+    }))
+    .await;
+    Mock::given(method("POST"))
+        .and(path("/images/generations"))
+        .and(body_partial_json(
+            json::json!({ "n": 1, "response_format": "b64_json" }),
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+            "data": [{ "b64_json": "iVBORw0KGgo=" }]
+        })))
+        .mount(&app.openai)
+        .await;
+
+    let res = app
+        .client
+        .post("/generate_image")
+        .header(ContentType::JSON)
+        .header(Accept::PNG)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"prompt": "a red fox"}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::Ok);
+    assert_eq!(res.content_type(), Some(ContentType::PNG));
+    assert_eq!(res.headers().get_one("X-RateLimit-Remaining"), Some("4"));
+    assert_eq!(
+        res.into_bytes().await.unwrap(),
+        b"\x89PNG\r\n\x1a\n".to_vec()
+    );
+
+    let res = app
+        .client
+        .post("/generate_image")
+        .header(ContentType::JSON)
+        .header(Accept::PNG)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"prompt": "a red fox", "n": 2}"#)
+        .dispatch()
+        .await;
+    assert_eq!(res.status(), Status::BadRequest);
+}
+
+#[rocket::async_test]
+async fn generate_image_replays_repeats_of_an_idempotency_key() {
+    let app = TestApp::new().await;
+    Mock::given(method("POST"))
+        .and(path("/keys.verifyKey"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+            "valid": true, "keyId": "key_test", "remaining": 4, "code": "VALID"
+        })))
+        .expect(1)
+        .mount(&app.unkey)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/images/generations"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+            "data": [{ "url": "https://images.test/fox.png" }]
+        })))
+        .expect(1)
+        .mount(&app.openai)
+        .await;
+    // Repeats look the key up instead of verifying it
+    Mock::given(method("GET"))
+        .and(path("/keys.getKey"))
+        .and(query_param("keyId", "key_test"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+            "id": "key_test",
+            "apiId": "api_test",
+            "workspaceId": "ws_test",
+            "start": "sk_",
+            "createdAt": 0,
+            "remaining": 3
+        })))
+        .expect(2)
+        .mount(&app.unkey)
+        .await;
+
+    let generate = |body: &'static str| {
+        app.client
+            .post("/generate_image")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", "Bearer sk_test"))
+            .header(Header::new("Idempotency-Key", "retry-1"))
+            .body(body)
+            .dispatch()
+    };
+    let first = generate(r#"{"prompt": "a red fox"}"#).await;
+    assert_eq!(first.status(), Status::Ok);
+    let first: Value = first.into_json().await.unwrap();
+
+    let repeat = generate(r#"{"prompt": "a red fox"}"#).await;
+    assert_eq!(repeat.status(), Status::Ok);
+    assert_eq!(repeat.headers().get_one("X-RateLimit-Remaining"), Some("4"));
+    assert_eq!(repeat.into_json::<Value>().await.unwrap(), first);
+
+    let other = generate(r#"{"prompt": "a blue owl"}"#).await;
+    assert_eq!(other.status(), Status::Conflict);
+    let body: Value = other.into_json().await.unwrap();
+    assert_eq!(body["error"]["code"], "conflict");
+}
+
+#[rocket::async_test]
+async fn generate_image_keeps_the_idempotency_keys_of_anonymous_keys_apart() {
+    let app = TestApp::with_config(|figment| figment.merge(("dry_run", true))).await;
+    for key in ["a", "b"] {
+        Mock::given(method("POST"))
+            .and(path("/keys.verifyKey"))
+            .and(body_partial_json(
+                json::json!({ "key": format!("sk_{}", key) }),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+                "valid": true,
+                "keyId": format!("key_{}", key),
+                "ownerId": "superuser",
+                "remaining": 3,
+                "code": "VALID"
+            })))
+            .expect(1)
+            .mount(&app.unkey)
+            .await;
+    }
+
+    let generate = |key: &'static str, prompt: &'static str| {
+        app.client
+            .post("/generate_image")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", format!("Bearer {}", key)))
+            .header(Header::new("Idempotency-Key", "retry-1"))
+            .body(format!(r#"{{"prompt": "{}"}}"#, prompt))
+            .dispatch()
+    };
+    let first = generate("sk_a", "a red fox").await;
+    // The other key neither replays the first image nor conflicts with it
+    let other = generate("sk_b", "a blue owl").await;
+
+    assert_eq!(first.status(), Status::Ok);
+    assert_eq!(other.status(), Status::Ok);
+    let body: Value = other.into_json().await.unwrap();
+    assert_eq!(body["request"]["prompt"], "a blue owl");
+}
+
+#[rocket::async_test]
+async fn generate_image_does_not_replay_to_revoked_keys() {
+    let app = TestApp::with_config(|figment| figment.merge(("dry_run", true))).await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "remaining": 4, "code": "VALID"
+    }))
+    .await;
+    Mock::given(method("GET"))
+        .and(path("/keys.getKey"))
+        .respond_with(ResponseTemplate::new(404).set_body_json(json::json!({
+            "error": { "code": "NOT_FOUND", "message": "key not found", "docs": "", "requestId": "" }
+        })))
+        .expect(1)
+        .mount(&app.unkey)
+        .await;
+
+    let generate = || {
+        app.client
+            .post("/generate_image")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", "Bearer sk_test"))
+            .header(Header::new("Idempotency-Key", "retry-1"))
+            .body(r#"{"prompt": "a red fox"}"#)
+            .dispatch()
+    };
+    assert_eq!(generate().await.status(), Status::Ok);
+
+    let repeat = generate().await;
+    assert_eq!(repeat.status(), Status::Unauthorized);
+}
+
+#[rocket::async_test]
+async fn generate_image_uses_the_managed_provider() {
+    let app = TestApp::with_provider(Box::new(FakeImageProvider)).await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "remaining": 4, "code": "VALID"
+    }))
+    .await;
+
+    let res = app
+        .client
+        .post("/generate_image")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"prompt": "a red fox"}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::Ok);
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(body["image_url"], "https://images.test/fake.png");
+    assert!(app.openai.received_requests().await.unwrap().is_empty());
+}
+
+#[rocket::async_test]
+async fn generate_image_warns_when_the_key_runs_low() {
+    let app = TestApp::new().await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "remaining": 1, "code": "VALID"
+    }))
+    .await;
+
+    let res = app
+        .client
+        .post("/generate_image?dry_run=true")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"prompt": "a red fox"}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::Ok);
+    assert_eq!(res.headers().get_one("X-RateLimit-Remaining"), Some("1"));
+    assert_eq!(res.headers().get_one("X-RateLimit-Warning"), Some("true"));
+}
+
+#[rocket::async_test]
+async fn generate_image_with_exhausted_key_is_too_many_requests() {
+    let app = TestApp::new().await;
+    app.mock_verify(json::json!({
+        "valid": false, "keyId": "key_test", "remaining": 0, "code": "USAGE_EXCEEDED",
+        "refill": { "interval": "daily", "amount": 10 }
+    }))
+    .await;
+
+    let res = app
+        .client
+        .post("/generate_image")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"prompt": "a red fox"}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::TooManyRequests);
+    assert_eq!(res.headers().get_one("X-RateLimit-Remaining"), Some("0"));
+    // Daily refills happen at the next midnight UTC
+    let retry_after: u64 = res
+        .headers()
+        .get_one("Retry-After")
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((1..=24 * 60 * 60).contains(&retry_after));
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(body["error"]["code"], "quota_exceeded");
+}
+
+#[rocket::async_test]
+async fn generate_image_over_the_ratelimit_retries_after_its_reset() {
+    let app = TestApp::new().await;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    app.mock_verify(json::json!({
+        "valid": false, "keyId": "key_test", "code": "RATE_LIMITED",
+        "ratelimit": { "limit": 10, "remaining": 0, "reset": now + 29_500 }
+    }))
+    .await;
+
+    let res = app
+        .client
+        .post("/generate_image")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"prompt": "a red fox"}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::TooManyRequests);
+    assert_eq!(res.headers().get_one("Retry-After"), Some("30"));
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(body["error"]["code"], "rate_limited");
+}
+
+#[rocket::async_test]
+async fn generate_image_verifies_keys_of_the_selected_environment() {
+    let app = TestApp::with_config(|figment| {
+        figment.merge((
+            "unkey_environments",
+            std::collections::HashMap::from([("staging", "api_staging")]),
+        ))
+    })
+    .await;
+    Mock::given(method("POST"))
+        .and(path("/keys.verifyKey"))
+        .and(body_partial_json(json::json!({ "apiId": "api_staging" })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+            "valid": true, "keyId": "key_test", "code": "VALID"
+        })))
+        .expect(1)
+        .mount(&app.unkey)
+        .await;
+
+    let generate = |env: &'static str| {
+        app.client
+            .post("/generate_image?dry_run=true")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", "Bearer sk_test"))
+            .header(Header::new("X-Unkey-Env", env))
+            .body(r#"{"prompt": "a red fox"}"#)
+            .dispatch()
+    };
+    let staging = generate("staging").await;
+    let unknown = generate("qa").await;
+
+    assert_eq!(staging.status(), Status::Ok);
+    assert_eq!(unknown.status(), Status::BadRequest);
+    let body: Value = unknown.into_json().await.unwrap();
+    assert_eq!(body["error"]["message"], "Unknown Unkey environment `qa`.");
+}
+
+#[rocket::async_test]
+async fn generate_image_is_unavailable_while_unkey_is_down() {
+    let app = TestApp::new().await;
+    Mock::given(method("POST"))
+        .and(path("/keys.verifyKey"))
+        .respond_with(ResponseTemplate::new(503).set_body_string("upstream connect error"))
+        .mount(&app.unkey)
+        .await;
+
+    let res = app
+        .client
+        .post("/generate_image")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"prompt": "a red fox"}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::ServiceUnavailable);
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(body["error"]["code"], "service_unavailable");
+}
+
+#[rocket::async_test]
+async fn generate_image_accepts_form_encoded_prompts() {
+    let app = TestApp::new().await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "remaining": 4, "code": "VALID"
+    }))
+    .await;
+
+    let res = app
+        .client
+        .post("/generate_image?dry_run=true")
+        .header(ContentType::Form)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body("prompt=a+red+fox&size=512x512")
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::Ok);
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(body["request"]["prompt"], "a red fox");
+    assert_eq!(body["request"]["size"], "512x512");
+    assert_eq!(body["remaining_calls"], 4);
+}
+
+#[rocket::async_test]
+async fn generate_image_rejects_bodies_that_are_not_declared_json() {
+    let app = TestApp::new().await;
+
+    let res = app
+        .client
+        .post("/generate_image")
+        .header(ContentType::Plain)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"prompt": "a red fox"}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::UnsupportedMediaType);
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(body["error"]["code"], "unsupported_media_type");
+    let requests = app.unkey.received_requests().await.unwrap();
+    assert!(!requests
+        .iter()
+        .any(|request| request.url.path() == "/keys.verifyKey"));
+}
+
+#[rocket::async_test]
+async fn generate_image_refunds_the_call_when_openai_fails() {
+    let app = TestApp::new().await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "remaining": 4, "code": "VALID"
+    }))
+    .await;
+    Mock::given(method("POST"))
+        .and(path("/images/generations"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.openai)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/keys.updateRemaining"))
+        .and(body_partial_json(json::json!({
+            "keyId": "key_test", "op": "increment", "value": 1
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({ "remaining": 5 })))
+        .expect(1)
+        .mount(&app.unkey)
+        .await;
+
+    let res = app
+        .client
+        .post("/generate_image")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"prompt": "a red fox"}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::InternalServerError);
+}
+
+#[rocket::async_test]
+async fn generate_image_keeps_the_call_when_refunds_are_off() {
+    let app = TestApp::with_config(|figment| figment.merge(("refund_on_failure", false))).await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "remaining": 4, "code": "VALID"
+    }))
+    .await;
+    Mock::given(method("POST"))
+        .and(path("/images/generations"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.openai)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/keys.updateRemaining"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({ "remaining": 5 })))
+        .expect(0)
+        .mount(&app.unkey)
+        .await;
+
+    let res = app
+        .client
+        .post("/generate_image")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"prompt": "a red fox"}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::InternalServerError);
+}
+
+#[rocket::async_test]
+async fn only_verifications_of_unlimited_keys_are_cached() {
+    let app = TestApp::with_config(|figment| {
+        figment
+            .merge(("verify_cache_ttl_ms", 60_000))
+            .merge(("dry_run", true))
+    })
+    .await;
+    // Every request after the first is answered from the cache
+    Mock::given(method("POST"))
+        .and(path("/keys.verifyKey"))
+        .and(body_partial_json(json::json!({ "key": "sk_unlimited" })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+            "valid": true, "keyId": "key_unlimited", "code": "VALID"
+        })))
+        .expect(1)
+        .mount(&app.unkey)
+        .await;
+    // Keys with a quota spend each call with Unkey
+    Mock::given(method("POST"))
+        .and(path("/keys.verifyKey"))
+        .and(body_partial_json(json::json!({ "key": "sk_limited" })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+            "valid": true, "keyId": "key_limited", "remaining": 10, "code": "VALID"
+        })))
+        .expect(3)
+        .mount(&app.unkey)
+        .await;
+
+    for key in ["sk_unlimited", "sk_limited"] {
+        for _ in 0..3 {
+            let res = app
+                .client
+                .post("/generate_image")
+                .header(ContentType::JSON)
+                .header(Header::new("Authorization", format!("Bearer {}", key)))
+                .body(r#"{"prompt": "a red fox"}"#)
+                .dispatch()
+                .await;
+            assert_eq!(res.status(), Status::Ok);
+        }
+    }
+}
+
+#[rocket::async_test]
+async fn generate_image_rejects_oversized_bodies() {
+    let app = TestApp::new().await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "remaining": 4, "code": "VALID"
+    }))
+    .await;
+
+    let prompt = "fox ".repeat(8 * 1024);
+    let res = app
+        .client
+        .post("/generate_image")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(json::json!({ "prompt": prompt }).to_string())
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::PayloadTooLarge);
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(body["error"]["code"], "payload_too_large");
+}
+
+#[rocket::async_test]
+async fn admin_lists_the_keys_of_an_owner() {
+    let app = TestApp::with_config(|figment| figment.merge(("admin_root_key", "admin_test"))).await;
+    Mock::given(method("GET"))
+        .and(path("/apis.listKeys"))
+        .and(query_param("ownerId", "user_test"))
+        .and(query_param("cursor", "key_prev"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+            "keys": [{
+                "id": "key_test",
+                "apiId": "api_test",
+                "workspaceId": "ws_test",
+                "start": "sk_",
+                "createdAt": 0,
+                "ownerId": "user_test",
+                "remaining": 3
+            }],
+            "total": 2,
+            "cursor": "key_test"
+        })))
+        .mount(&app.unkey)
+        .await;
+
+    let res = app
+        .client
+        .get("/admin/keys?owner_id=user_test&cursor=key_prev")
+        .header(Header::new("X-Admin-Key", "admin_test"))
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::Ok);
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(body["keys"][0]["key_id"], "key_test");
+    assert_eq!(body["keys"][0]["remaining"], 3);
+    assert_eq!(body["total"], 2);
+    assert_eq!(body["cursor"], "key_test");
+}
+
+#[rocket::async_test]
+async fn admin_lists_the_keys_of_the_selected_environment() {
+    let app = TestApp::with_config(|figment| {
+        figment.merge(("admin_root_key", "admin_test")).merge((
+            "unkey_environments",
+            std::collections::HashMap::from([("staging", "api_staging")]),
+        ))
+    })
+    .await;
+    Mock::given(method("GET"))
+        .and(path("/apis.listKeys"))
+        .and(query_param("apiId", "api_staging"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+            "keys": [], "total": 0
+        })))
+        .expect(1)
+        .mount(&app.unkey)
+        .await;
+
+    let res = app
+        .client
+        .get("/admin/keys?owner_id=user_test")
+        .header(Header::new("X-Admin-Key", "admin_test"))
+        .header(Header::new("X-Unkey-Env", "staging"))
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::Ok);
+}
+
+#[rocket::async_test]
+async fn generate_image_applies_the_configured_defaults() {
+    let app = TestApp::with_config(|figment| {
+        figment
+            .merge(("default_image_model", "dall-e-3"))
+            .merge(("default_image_size", "1792x1024"))
+    })
+    .await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "remaining": 4, "code": "VALID"
+    }))
+    .await;
+    Mock::given(method("POST"))
+        .and(path("/images/generations"))
+        .and(body_partial_json(json::json!({
+            "model": "dall-e-3", "size": "1024x1792", "n": 1
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+            "created": 1, "data": [{ "url": "https://images.test/fox.png" }]
+        })))
+        .expect(1)
+        .mount(&app.openai)
+        .await;
+
+    let res = app
+        .client
+        .post("/generate_image")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"prompt": "a red fox", "size": "1024x1792"}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::Ok);
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(
+        body["meta"],
+        json::json!({ "width": 1024, "height": 1792, "model": "dall-e-3" })
+    );
+}
+
+#[rocket::async_test]
+async fn generate_image_returns_the_prompt_revised_by_dall_e_3() {
+    let app = TestApp::new().await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "remaining": 4, "code": "VALID"
+    }))
+    .await;
+    Mock::given(method("POST"))
+        .and(path("/images/generations"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+            "created": 1,
+            "data": [{
+                "url": "https://images.test/fox.png",
+                "revised_prompt": "A red fox sitting in a snowy forest at dawn"
+            }]
+        })))
+        .mount(&app.openai)
+        .await;
+
+    let res = app
+        .client
+        .post("/generate_image")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"prompt": "a red fox", "model": "dall-e-3"}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::Ok);
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(
+        body["revised_prompt"],
+        "A red fox sitting in a snowy forest at dawn"
+    );
+}
+
+#[rocket::async_test]
+async fn generate_image_wraps_prompts_in_the_configured_prefix_and_suffix() {
+    let app = TestApp::with_config(|figment| {
+        figment
+            .merge(("prompt_prefix", "Safe for work: "))
+            .merge(("prompt_suffix", ", digital art"))
+            .merge(("max_prompt_length", 35))
+    })
+    .await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "remaining": 4, "code": "VALID"
+    }))
+    .await;
+    Mock::given(method("POST"))
+        .and(path("/images/generations"))
+        .and(body_partial_json(json::json!({
+            "prompt": "Safe for work: a fox, digital art"
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+            "created": 1, "data": [{ "url": "https://images.test/fox.png" }]
+        })))
+        .expect(1)
+        .mount(&app.openai)
+        .await;
+
+    let res = app
+        .client
+        .post("/generate_image")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"prompt": "a red fox"}"#)
+        .dispatch()
+        .await;
+    assert_eq!(res.status(), Status::BadRequest);
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(
+        body["error"]["message"],
+        "Invalid prompt: the prompt must be at most 7 characters long."
+    );
+
+    let res = app
+        .client
+        .post("/generate_image")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"prompt": "a fox"}"#)
+        .dispatch()
+        .await;
+    assert_eq!(res.status(), Status::Ok);
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(body["request"]["prompt"], "a fox");
+    assert_eq!(
+        body["request"]["effective_prompt"],
+        "Safe for work: a fox, digital art"
+    );
+}
+
+#[cfg(feature = "persistence")]
+#[rocket::async_test]
+async fn history_lists_the_keys_created_by_authorize() {
+    let app = TestApp::new().await;
+    Mock::given(method("POST"))
+        .and(path("/keys.createKey"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json::json!({ "keyId": "key_test", "key": "sk_test" })),
+        )
+        .mount(&app.unkey)
+        .await;
+    app.client.post("/authorize").dispatch().await;
+
+    let res = app
+        .client
+        .get("/my/history")
+        .private_cookie(session_cookie())
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::Ok);
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(body[0]["key_id"], "key_test");
+    assert!(body[0]["owner_id"]
+        .as_str()
+        .is_some_and(|owner_id| owner_id.starts_with("anonymous_")));
+}
+
+#[cfg(feature = "persistence")]
+#[rocket::async_test]
+async fn history_keeps_anonymous_sessions_apart() {
+    let app = TestApp::new().await;
+    for key in ["a", "b"] {
+        Mock::given(method("POST"))
+            .and(path("/keys.createKey"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+                "keyId": format!("key_{}", key), "key": format!("sk_{}", key)
+            })))
+            .up_to_n_times(1)
+            .mount(&app.unkey)
+            .await;
+        app.client.post("/authorize").dispatch().await;
+    }
+
+    for key in ["a", "b"] {
+        let session =
+            json::json!({ "key": format!("sk_{}", key), "key_id": format!("key_{}", key) });
+        let res = app
+            .client
+            .get("/my/history")
+            .private_cookie(Cookie::new("unkey", session.to_string()))
+            .dispatch()
+            .await;
+
+        let body: Value = res.into_json().await.unwrap();
+        let ids: Vec<&str> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|entry| entry["key_id"].as_str())
+            .collect();
+        assert_eq!(ids, [format!("key_{}", key)]);
+    }
+}
+
+#[cfg(feature = "persistence")]
+#[rocket::async_test]
+async fn history_lists_rotated_keys() {
+    let app = TestApp::new().await;
+    for key in ["old", "new"] {
+        Mock::given(method("POST"))
+            .and(path("/keys.createKey"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+                "keyId": format!("key_{}", key), "key": format!("sk_{}", key)
+            })))
+            .up_to_n_times(1)
+            .mount(&app.unkey)
+            .await;
+    }
+    app.client.post("/authorize").dispatch().await;
+    let old_session = json::json!({ "key": "sk_old", "key_id": "key_old" });
+    let res = app
+        .client
+        .get("/my/history")
+        .private_cookie(Cookie::new("unkey", old_session.to_string()))
+        .dispatch()
+        .await;
+    let body: Value = res.into_json().await.unwrap();
+    let owner_id = body[0]["owner_id"].as_str().unwrap().to_owned();
+
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_old", "ownerId": owner_id, "remaining": 5, "code": "VALID"
+    }))
+    .await;
+    Mock::given(method("POST"))
+        .and(path("/keys.deleteKey"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({})))
+        .mount(&app.unkey)
+        .await;
+    let res = app
+        .client
+        .post("/rotate")
+        .private_cookie(Cookie::new("unkey", old_session.to_string()))
+        .dispatch()
+        .await;
+    assert_eq!(res.status(), Status::Ok);
+
+    let new_session = json::json!({ "key": "sk_new", "key_id": "key_new" });
+    let res = app
+        .client
+        .get("/my/history")
+        .private_cookie(Cookie::new("unkey", new_session.to_string()))
+        .dispatch()
+        .await;
+    let body: Value = res.into_json().await.unwrap();
+    let entries = body.as_array().unwrap();
+    let ids: Vec<&str> = entries
+        .iter()
+        .filter_map(|entry| entry["key_id"].as_str())
+        .collect();
+    assert_eq!(ids.len(), 2);
+    assert!(ids.contains(&"key_old") && ids.contains(&"key_new"));
+    assert!(entries
+        .iter()
+        .all(|entry| entry["owner_id"] == owner_id.as_str()));
+}
+
+#[rocket::async_test]
+async fn generate_image_stream_checks_the_prompt_before_the_key() {
+    let app = TestApp::new().await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "remaining": 4, "code": "VALID"
+    }))
+    .await;
+
+    let res = app
+        .client
+        .get("/generate_image/stream?prompt=%20")
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::BadRequest);
+    let requests = app.unkey.received_requests().await.unwrap();
+    assert!(!requests
+        .iter()
+        .any(|request| request.url.path() == "/keys.verifyKey"));
+}
+
+#[rocket::async_test]
+async fn generate_image_rejects_flagged_prompts() {
+    let app = TestApp::with_config(|figment| figment.merge(("enable_moderation", true))).await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "remaining": 4, "code": "VALID"
+    }))
+    .await;
+    Mock::given(method("POST"))
+        .and(path("/moderations"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+            "results": [{
+                "flagged": true,
+                "categories": { "violence": true, "hate": false }
+            }]
+        })))
+        .mount(&app.openai)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/images/generations"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.openai)
+        .await;
+
+    let res = app
+        .client
+        .post("/generate_image")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"prompt": "a red fox"}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::UnprocessableEntity);
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(body["error"]["code"], "content_flagged");
+    assert_eq!(body["error"]["categories"], json::json!(["violence"]));
+    // The prompt is moderated before the key is verified, so it costs no call
+    let requests = app.unkey.received_requests().await.unwrap();
+    assert!(!requests
+        .iter()
+        .any(|request| request.url.path() == "/keys.verifyKey"));
+}
+
+#[rocket::async_test]
+async fn rotate_replaces_the_key_keeping_owner_and_quota() {
+    let app = TestApp::new().await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "ownerId": "user_test", "remaining": 5, "code": "VALID"
+    }))
+    .await;
+    Mock::given(method("POST"))
+        .and(path("/keys.deleteKey"))
+        .and(body_partial_json(json::json!({ "keyId": "key_test" })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({})))
+        .expect(1)
+        .mount(&app.unkey)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/keys.createKey"))
+        .and(body_partial_json(
+            json::json!({ "ownerId": "user_test", "remaining": 5 }),
+        ))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json::json!({ "keyId": "key_new", "key": "sk_new" })),
+        )
+        .expect(1)
+        .mount(&app.unkey)
+        .await;
+
+    let res = app
+        .client
+        .post("/rotate")
+        .private_cookie(session_cookie())
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::Ok);
+    let cookie = res.cookies().get_private("unkey").expect("session cookie");
+    let session: Value = json::from_str(cookie.value()).unwrap();
+    assert_eq!(session["key_id"], "key_new");
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(body, json::json!({ "key": "sk_new", "key_id": "key_new" }));
+}
+
+#[test]
+fn image_sizes_are_validated_against_the_model() {
+    let openai = ImageProviderKind::OpenAI;
+    for size in ["256x256", "512x512", "1024x1024"] {
+        assert!(validate_image_params(openai, size, Some("dall-e-2"), 1, "url").is_ok());
+    }
+    for size in ["1024x1024", "1792x1024", "1024x1792"] {
+        assert!(validate_image_params(openai, size, Some("dall-e-3"), 1, "url").is_ok());
+    }
+    // Without a model, OpenAI decides
+    assert!(validate_image_params(openai, "1792x1024", None, 1, "url").is_ok());
+
+    assert!(validate_image_params(openai, "1792x1024", Some("dall-e-2"), 1, "url").is_err());
+    assert!(validate_image_params(openai, "256x256", Some("dall-e-3"), 1, "url").is_err());
+}
+
+#[test]
+fn image_sizes_are_validated_against_stability_s_dimensions() {
+    let stability = ImageProviderKind::Stability;
+    for size in ["1024x1024", "1152x896", "640x1536"] {
+        assert!(validate_image_params(stability, size, None, 1, "url").is_ok());
+    }
+    // The model is ignored, not checked against the size
+    assert!(validate_image_params(stability, "1216x832", Some("dall-e-3"), 1, "url").is_ok());
+
+    for size in ["256x256", "512x512", "1792x1024"] {
+        assert!(validate_image_params(stability, size, None, 1, "url").is_err());
+    }
+}
+
+#[rocket::async_test]
+async fn generate_image_rejects_a_size_unsupported_by_the_model() {
+    let app = TestApp::new().await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "remaining": 4, "code": "VALID"
+    }))
+    .await;
+
+    let res = app
+        .client
+        .post("/generate_image")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"prompt": "a red fox", "model": "dall-e-2", "size": "1792x1024"}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::BadRequest);
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(
+        body["error"]["message"],
+        "Invalid image size for dall-e-2: expected one of 256x256, 512x512, 1024x1024."
+    );
+}
+
+#[rocket::async_test]
+async fn generate_image_rejects_invalid_prompts_without_spending_a_call() {
+    let app = TestApp::new().await;
+    Mock::given(method("POST"))
+        .and(path("/keys.verifyKey"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+            "valid": true, "keyId": "key_test", "remaining": 4, "code": "VALID"
+        })))
+        .expect(0)
+        .mount(&app.unkey)
+        .await;
+
+    let res = app
+        .client
+        .post("/generate_image")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"prompt": "   "}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::BadRequest);
+}
+
+#[rocket::async_test]
+async fn openapi_documents_every_route() {
+    let app = TestApp::new().await;
+
+    let res = app.client.get("/openapi.json").dispatch().await;
+    assert_eq!(res.status(), Status::Ok);
+    let spec: Value = res.into_json().await.unwrap();
+
+    for route in app.client.rocket().routes() {
+        if route.method == rocket::http::Method::Options {
+            continue;
+        }
+        // `/keys/<key_id>` is documented as `/keys/{key_id}`
+        let path = route.uri.path().replace('<', "{").replace('>', "}");
+        let method = route.method.as_str().to_lowercase();
+        assert!(
+            spec["paths"][&path][&method].is_object(),
+            "{} {} is missing from the OpenAPI document",
+            method,
+            path
+        );
+    }
+}
+
+/// Builds the app with the services built from its config, as `run` does
+fn build_app(figment: Figment) -> Rocket<Build> {
+    let config: AppConfig = figment.extract().expect("valid test config");
+    let services = Services::from_config(&config).expect("valid test services");
+    build_rocket(figment, config, services)
+}
+
+#[rocket::async_test]
+async fn launch_fails_without_required_secrets() {
+    let figment = rocket::Config::figment()
+        .merge(("log_level", "off"))
+        .merge(("unkey_root_key", "unkey_root"))
+        .merge(("unkey_api_id", "api_test"))
+        .merge(("openai_api_key", " "));
+    match LocalClient::tracked(build_app(figment.clone())).await {
+        Err(e) => assert!(matches!(e.kind(), ErrorKind::FailedFairings(_))),
+        Ok(_) => panic!("launched without OPENAI_API_KEY"),
+    }
+
+    let figment = figment.merge(("allow_missing_secrets", 1));
+    assert!(LocalClient::tracked(build_app(figment)).await.is_ok());
+}
+
+#[rocket::async_test]
+async fn stability_needs_its_key_but_not_openai_s() {
+    let figment = rocket::Config::figment()
+        .merge(("log_level", "off"))
+        .merge(("unkey_root_key", "unkey_root"))
+        .merge(("unkey_api_id", "api_test"))
+        .merge(("image_provider", "stability"));
+    match LocalClient::tracked(build_app(figment.clone())).await {
+        Err(e) => assert!(matches!(e.kind(), ErrorKind::FailedFairings(_))),
+        Ok(_) => panic!("launched without STABILITY_API_KEY"),
+    }
+
+    let figment = figment.merge(("stability_api_key", "sk-stability"));
+    assert!(LocalClient::tracked(build_app(figment)).await.is_ok());
+}
+
+#[rocket::async_test]
+async fn authorize_creates_keys_with_the_configured_refill() {
+    let app = TestApp::with_config(|figment| {
+        figment
+            .merge(("default_remaining", 5))
+            .merge(("refill_interval", "monthly"))
+            .merge(("refill_amount", 50))
+    })
+    .await;
+    Mock::given(method("POST"))
+        .and(path("/keys.createKey"))
+        .and(body_partial_json(json::json!({
+            "remaining": 5,
+            "refill": { "interval": "monthly", "amount": 50 }
+        })))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json::json!({ "keyId": "key_test", "key": "sk_test" })),
+        )
+        .expect(1)
+        .mount(&app.unkey)
+        .await;
+
+    let res = app.client.post("/authorize").dispatch().await;
+
+    assert_eq!(res.status(), Status::SeeOther);
+}
+
+#[rocket::async_test]
+async fn authorize_creates_keys_without_refill_by_default() {
+    let app = TestApp::new().await;
+    Mock::given(method("POST"))
+        .and(path("/keys.createKey"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json::json!({ "keyId": "key_test", "key": "sk_test" })),
+        )
+        .mount(&app.unkey)
+        .await;
+
+    app.client.post("/authorize").dispatch().await;
+
+    let requests = app.unkey.received_requests().await.unwrap();
+    let create = requests
+        .iter()
+        .find(|request| request.url.path() == "/keys.createKey")
+        .unwrap();
+    let body: Value = json::from_slice(&create.body).unwrap();
+    assert_eq!(body["remaining"], 10);
+    assert!(body.get("refill").is_none());
+}
+
+#[test]
+fn unknown_refill_intervals_are_rejected() {
+    let figment = rocket::Config::figment().merge(("refill_interval", "weekly"));
+
+    let error = figment.extract::<AppConfig>().unwrap_err();
+
+    assert!(error
+        .to_string()
+        .contains("found `weekly`, expected ``daily` or `monthly``"));
+}
+
+#[rocket::async_test]
+async fn generate_image_dry_run_skips_openai() {
+    let app = TestApp::new().await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "remaining": 3, "code": "VALID"
+    }))
+    .await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.openai)
+        .await;
+
+    let res = app
+        .client
+        .post("/generate_image?dry_run=true")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"prompt": "a red fox", "size": "512x512"}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::Ok);
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(body["dry_run"], true);
+    assert_eq!(body["remaining_calls"], 3);
+    assert_eq!(
+        body["image_url"],
+        "https://placehold.co/512x512.png?text=dry+run"
+    );
+}
+
+#[rocket::async_test]
+async fn generate_image_requires_the_configured_permission() {
+    let app = TestApp::with_config(|figment| {
+        figment
+            .merge(("required_permissions.generate_image", "image:generate"))
+            .merge(("dry_run", true))
+    })
+    .await;
+    Mock::given(method("POST"))
+        .and(path("/keys.verifyKey"))
+        .and(body_partial_json(json::json!({
+            "key": "sk_test",
+            "apiId": "api_test",
+            "authorization": { "permissions": "image:generate" }
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+            "valid": false, "keyId": "key_test", "code": "INSUFFICIENT_PERMISSIONS"
+        })))
+        .expect(1)
+        .mount(&app.unkey)
+        .await;
+
+    let res = app
+        .client
+        .post("/generate_image")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"prompt": "a red fox"}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::Forbidden);
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(
+        body["error"]["message"],
+        "The API key lacks the `image:generate` permission."
+    );
+}
+
+#[rocket::async_test]
+async fn generate_image_requires_the_image_permission_by_default() {
+    let app = TestApp::with_config(|figment| figment.merge(("dry_run", true))).await;
+    Mock::given(method("POST"))
+        .and(path("/keys.verifyKey"))
+        .and(body_partial_json(json::json!({
+            "authorization": { "permissions": "image:generate" }
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+            "valid": true, "keyId": "key_test", "remaining": 3, "code": "VALID"
+        })))
+        .expect(1)
+        .mount(&app.unkey)
+        .await;
+
+    let res = app
+        .client
+        .post("/generate_image")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"prompt": "a red fox"}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::Ok);
+}
+
+#[rocket::async_test]
+async fn generate_image_requires_no_permission_once_opted_out() {
+    let app = TestApp::with_config(|figment| {
+        figment
+            .merge(("required_permissions", Dict::new()))
+            .merge(("dry_run", true))
+    })
+    .await;
+    Mock::given(method("POST"))
+        .and(path("/keys.verifyKey"))
+        .and(body_partial_json(json::json!({
+            "authorization": { "permissions": "image:generate" }
+        })))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(0)
+        .mount(&app.unkey)
+        .await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "remaining": 3, "code": "VALID"
+    }))
+    .await;
+
+    let res = app
+        .client
+        .post("/generate_image")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"prompt": "a red fox"}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::Ok);
+}
+
+#[rocket::async_test]
+async fn generate_image_accepts_a_key_with_the_permission() {
+    let app = TestApp::with_config(|figment| {
+        figment
+            .merge(("required_permissions.generate_image", "image:generate"))
+            .merge(("dry_run", true))
+    })
+    .await;
+    Mock::given(method("POST"))
+        .and(path("/keys.verifyKey"))
+        .and(body_partial_json(json::json!({
+            "authorization": { "permissions": "image:generate" }
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+            "valid": true, "keyId": "key_test", "remaining": 3, "code": "VALID"
+        })))
+        .expect(1)
+        .mount(&app.unkey)
+        .await;
+
+    let res = app
+        .client
+        .post("/generate_image")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"prompt": "a red fox"}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::Ok);
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(body["remaining_calls"], 3);
+}
+
+#[rocket::async_test]
+async fn admin_config_redacts_the_secrets() {
+    let app = TestApp::with_config(|figment| {
+        figment
+            .merge(("admin_root_key", "admin_test"))
+            .merge(("unkey_root_key", "unkey-root-9876543210wxyz"))
+            .merge(("openai_api_key", "sk-test-0123456789abcd"))
+            .merge(("webhook_url", "https://hooks.example.com/T0123/secret"))
+    })
+    .await;
+
+    let res = app
+        .client
+        .get("/admin/config")
+        .header(Header::new("X-Admin-Key", "admin_test"))
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::Ok);
+    let text = res.into_string().await.unwrap();
+    for secret in ["9876543210", "admin_test", "0123456789", "T0123"] {
+        assert!(!text.contains(secret), "{} leaked in {}", secret, text);
+    }
+    let body: Value = json::from_str(&text).unwrap();
+    assert_eq!(body["openai_api_key"], "****abcd");
+    assert_eq!(body["unkey_root_key"], "****wxyz");
+    assert_eq!(body["admin_root_key"], "****");
+    assert_eq!(body["webhook_url"], "****cret");
+    assert_eq!(body["unkey_api_id"], "api_test");
+    assert_eq!(body["openai_max_retries"], 0);
+}
+
+#[rocket::async_test]
+async fn admin_config_requires_the_admin_key() {
+    let app = TestApp::with_config(|figment| figment.merge(("admin_root_key", "admin_test"))).await;
+
+    let res = app.client.get("/admin/config").dispatch().await;
+
+    assert_eq!(res.status(), Status::Forbidden);
+}
+
+#[rocket::async_test]
+async fn admins_verify_any_key_without_a_session() {
+    let app = TestApp::with_config(|figment| figment.merge(("admin_root_key", "admin_test"))).await;
+    app.mock_verify(json::json!({
+        "valid": false, "keyId": "key_test", "remaining": 0,
+        "expires": 1700000000000u64, "code": "USAGE_EXCEEDED"
+    }))
+    .await;
+
+    let forbidden = app
+        .client
+        .post("/admin/verify")
+        .header(ContentType::JSON)
+        .body(r#"{"key": "sk_test"}"#)
+        .dispatch()
+        .await;
+    let res = app
+        .client
+        .post("/admin/verify")
+        .header(ContentType::JSON)
+        .header(Header::new("X-Admin-Key", "admin_test"))
+        .body(r#"{"key": "sk_test"}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(forbidden.status(), Status::Forbidden);
+    assert_eq!(res.status(), Status::Ok);
+    assert!(res.cookies().get("unkey").is_none());
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(body["valid"], false);
+    assert_eq!(body["remaining"], 0);
+    assert_eq!(body["code"], "USAGE_EXCEEDED");
+    assert_eq!(body["expires"], 1700000000000u64);
+}
+
+#[rocket::async_test]
+async fn unkey_verifies_with_the_verify_key() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/keys.verifyKey"))
+        .and(header("Authorization", "Bearer unkey_verify"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+            "valid": true, "keyId": "key_test", "code": "VALID"
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/keys.createKey"))
+        .and(header("Authorization", "Bearer unkey_root"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json::json!({ "keyId": "key_test", "key": "sk_test" })),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+    let config: AppConfig = rocket::Config::figment()
+        .merge(("unkey_root_key", "unkey_root"))
+        .extract()
+        .unwrap();
+    let unkey = UnkeyService::new(
+        UnkeyClient::with_url("unkey_root", &server.uri()),
+        Arc::new(Metrics::new().unwrap()),
+    )
+    .with_url(server.uri())
+    .with_verify_key("unkey_verify");
+
+    let verification = unkey
+        .verify("api_test", &SecretKey::from("sk_test"), None)
+        .await
+        .expect("verification");
+    let created = unkey
+        .create(&config, "api_test", None, "user_test", None, None)
+        .await;
+
+    assert!(verification.valid);
+    assert_eq!(created.map(|data| data.key_id).as_deref(), Some("key_test"));
+}
+
+#[rocket::async_test]
+async fn creates_keys_with_the_configured_byte_length() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/keys.createKey"))
+        .and(body_partial_json(json::json!({ "byteLength": 32 })))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json::json!({ "keyId": "key_test", "key": "sk_test" })),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+    let figment = rocket::Config::figment().merge(("unkey_root_key", "unkey_root"));
+    let config: AppConfig = figment
+        .clone()
+        .merge(("key_byte_length", 32))
+        .extract()
+        .unwrap();
+    let unkey = UnkeyService::new(
+        UnkeyClient::with_url("unkey_root", &server.uri()),
+        Arc::new(Metrics::new().unwrap()),
+    );
+
+    let created = unkey
+        .create(&config, "api_test", None, "user_test", None, None)
+        .await;
+
+    assert!(created.is_some());
+    assert!(figment
+        .merge(("key_byte_length", 8))
+        .extract::<AppConfig>()
+        .is_err());
+}
+
+#[rocket::async_test]
+async fn unkey_calls_time_out() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/keys.verifyKey"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json::json!({ "valid": true, "keyId": "key_test", "code": "VALID" }))
+                .set_delay(std::time::Duration::from_secs(2)),
+        )
+        .mount(&server)
+        .await;
+    let unkey = UnkeyService::new(
+        UnkeyClient::with_url("unkey_root", &server.uri()),
+        Arc::new(Metrics::new().unwrap()),
+    )
+    .with_timeout(std::time::Duration::from_millis(100));
+
+    let started = std::time::Instant::now();
+    let verification = unkey
+        .verify("api_test", &SecretKey::from("sk_test"), None)
+        .await;
+
+    assert!(matches!(verification, Err(VerifyError::Unavailable(_))));
+    assert!(started.elapsed() < std::time::Duration::from_secs(1));
+}
+
+#[rocket::async_test]
+async fn verifications_fail_fast_while_the_circuit_is_open() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/keys.verifyKey"))
+        .respond_with(ResponseTemplate::new(503).set_body_string("upstream connect error"))
+        .expect(2)
+        .mount(&server)
+        .await;
+    let metrics = Arc::new(Metrics::new().unwrap());
+    let unkey = UnkeyService::new(
+        UnkeyClient::with_url("unkey_root", &server.uri()),
+        metrics.clone(),
+    )
+    .with_circuit_breaker(2, std::time::Duration::from_secs(60));
+
+    for _ in 0..3 {
+        let verification = unkey
+            .verify("api_test", &SecretKey::from("sk_test"), None)
+            .await;
+        assert!(matches!(verification, Err(VerifyError::Unavailable(_))));
+    }
+
+    assert!(metrics.render().contains("unkey_circuit_state 1"));
+}
+
+#[rocket::async_test]
+async fn permission_verifications_refused_by_unkey_keep_the_circuit_closed() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/keys.verifyKey"))
+        .respond_with(ResponseTemplate::new(400))
+        .expect(2)
+        .mount(&server)
+        .await;
+    let metrics = Arc::new(Metrics::new().unwrap());
+    let unkey = testing::unkey_service(&server.uri(), "unkey_root", metrics.clone())
+        .with_circuit_breaker(1, std::time::Duration::from_secs(60));
+
+    for _ in 0..2 {
+        let verification = unkey
+            .verify(
+                "api_test",
+                &SecretKey::from("sk_test"),
+                Some("image:generate"),
+            )
+            .await;
+        assert!(matches!(verification, Err(VerifyError::Rejected(_))));
+    }
+
+    assert!(metrics.render().contains("unkey_circuit_state 0"));
+}
+
+#[rocket::async_test]
+async fn proxies_images_of_the_allowed_origins_only() {
+    let images = MockServer::start().await;
+    let origin = images.uri();
+    let app =
+        TestApp::with_config(|figment| figment.merge(("image_proxy_origins", [origin]))).await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "remaining": 4, "code": "VALID"
+    }))
+    .await;
+    Mock::given(method("GET"))
+        .and(path("/fox.png"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_raw(vec![0x89, b'P', b'N', b'G'], "image/png"),
+        )
+        .expect(1)
+        .mount(&images)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/keys.updateRemaining"))
+        .and(body_partial_json(json::json!({
+            "keyId": "key_test", "op": "increment", "value": 1
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({ "remaining": 5 })))
+        .expect(1)
+        .mount(&app.unkey)
+        .await;
+
+    let proxy = |url: String| {
+        app.client
+            .get(format!(
+                "/images/proxy?url={}",
+                url.replace(':', "%3A").replace('/', "%2F")
+            ))
+            .header(Header::new("Authorization", "Bearer sk_test"))
+            .dispatch()
+    };
+    let res = proxy(format!("{}/fox.png", images.uri())).await;
+    let other = proxy("https://example.com/fox.png".into()).await;
+
+    assert_eq!(other.status(), Status::BadRequest);
+    assert_eq!(res.status(), Status::Ok);
+    assert_eq!(res.content_type(), Some(ContentType::PNG));
+    assert_eq!(res.headers().get_one("Content-Length"), Some("4"));
+    assert_eq!(res.headers().get_one("X-RateLimit-Remaining"), Some("5"));
+    assert_eq!(
+        res.into_bytes().await.unwrap(),
+        vec![0x89, b'P', b'N', b'G']
+    );
+}
+
+#[rocket::async_test]
+async fn proxy_does_not_follow_redirects() {
+    let images = MockServer::start().await;
+    let origin = images.uri();
+    let app =
+        TestApp::with_config(|figment| figment.merge(("image_proxy_origins", [origin]))).await;
+    app.mock_verify(json::json!({ "valid": true, "keyId": "key_test", "code": "VALID" }))
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/fox.png"))
+        .respond_with(
+            ResponseTemplate::new(302)
+                .insert_header("Location", format!("{}/internal", images.uri())),
+        )
+        .mount(&images)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/internal"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&images)
+        .await;
+
+    let url = format!("{}/fox.png", images.uri());
+    let res = app
+        .client
+        .get(format!(
+            "/images/proxy?url={}",
+            url.replace(':', "%3A").replace('/', "%2F")
+        ))
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::BadGateway);
+}
+
+#[rocket::async_test]
+async fn estimates_generations_without_calling_openai() {
+    let app = TestApp::new().await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "remaining": 4, "code": "VALID"
+    }))
+    .await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(0)
+        .mount(&app.openai)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/keys.updateRemaining"))
+        .and(body_partial_json(json::json!({
+            "keyId": "key_test", "op": "increment", "value": 1
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({ "remaining": 5 })))
+        .expect(1)
+        .mount(&app.unkey)
+        .await;
+
+    let res = app
+        .client
+        .post("/generate_image/estimate")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"model": "dall-e-3", "size": "1792x1024", "n": 1}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::Ok);
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(body["estimated_cost_usd"], 0.08);
+    assert_eq!(body["calls"], 1);
+    assert_eq!(body["remaining_calls"], 5);
+    assert_eq!(body["sufficient_quota"], true);
+}
+
+#[rocket::async_test]
+async fn estimates_refund_the_verification_of_a_key_with_one_call_left() {
+    let app = TestApp::new().await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "remaining": 0, "code": "VALID"
+    }))
+    .await;
+    Mock::given(method("POST"))
+        .and(path("/keys.updateRemaining"))
+        .and(body_partial_json(json::json!({
+            "keyId": "key_test", "op": "increment", "value": 1
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({ "remaining": 1 })))
+        .expect(1)
+        .mount(&app.unkey)
+        .await;
+
+    let res = app
+        .client
+        .post("/generate_image/estimate")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"model": "dall-e-3", "size": "1024x1024"}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::Ok);
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(body["remaining_calls"], 1);
+    assert_eq!(body["sufficient_quota"], true);
+}
+
+#[rocket::async_test]
+async fn estimates_report_an_exhausted_key_as_short_of_calls() {
+    let app = TestApp::new().await;
+    app.mock_verify(json::json!({
+        "valid": false, "keyId": "key_test", "remaining": 0, "code": "USAGE_EXCEEDED"
+    }))
+    .await;
+    Mock::given(method("POST"))
+        .and(path("/keys.updateRemaining"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.unkey)
+        .await;
+
+    let res = app
+        .client
+        .post("/generate_image/estimate")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"model": "dall-e-3", "size": "1024x1024"}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::Ok);
+    assert_eq!(res.headers().get_one("X-RateLimit-Remaining"), Some("0"));
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(body["remaining_calls"], 0);
+    assert_eq!(body["sufficient_quota"], false);
+}
+
+#[rocket::async_test]
+async fn generate_image_batch_reports_each_prompt() {
+    let app = TestApp::new().await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "remaining": 3, "code": "VALID"
+    }))
+    .await;
+    Mock::given(method("POST"))
+        .and(path("/keys.updateRemaining"))
+        .and(body_partial_json(json::json!({
+            "keyId": "key_test", "op": "decrement", "value": 1
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({ "remaining": 2 })))
+        .expect(1)
+        .mount(&app.unkey)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/images/generations"))
+        .and(body_partial_json(
+            json::json!({ "prompt": "a red fox", "n": 1 }),
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+            "data": [{ "url": "https://images.test/fox.png" }]
+        })))
+        .mount(&app.openai)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/images/generations"))
+        .and(body_partial_json(json::json!({ "prompt": "a blue owl" })))
+        .respond_with(ResponseTemplate::new(400).set_body_json(json::json!({
+            "error": {
+                "message": "Your request was rejected by our safety system.",
+                "code": "content_policy_violation"
+            }
+        })))
+        .mount(&app.openai)
+        .await;
+
+    let res = app
+        .client
+        .post("/generate_image/batch")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"prompts": ["a red fox", "a blue owl"]}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::Ok);
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(body["remaining_calls"], 2);
+    let results = body["results"].as_array().unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0]["prompt"], "a red fox");
+    assert_eq!(results[0]["image_url"], "https://images.test/fox.png");
+    assert_eq!(results[1]["prompt"], "a blue owl");
+    assert_eq!(results[1]["error"]["code"], "content_policy_violation");
+}
+
+#[rocket::async_test]
+async fn generate_image_batch_needs_a_call_per_prompt() {
+    let app = TestApp::new().await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "remaining": 1, "code": "VALID"
+    }))
+    .await;
+    // Only the verification's call is refunded, nothing else is spent
+    Mock::given(method("POST"))
+        .and(path("/keys.updateRemaining"))
+        .and(body_partial_json(json::json!({
+            "keyId": "key_test", "op": "increment", "value": 1
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({ "remaining": 2 })))
+        .expect(1)
+        .mount(&app.unkey)
+        .await;
+
+    let res = app
+        .client
+        .post("/generate_image/batch")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"prompts": ["a red fox", "a blue owl", "a green frog"]}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::TooManyRequests);
+    assert_eq!(res.headers().get_one("X-RateLimit-Remaining"), Some("2"));
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(body["error"]["code"], "quota_exceeded");
+}
+
+#[rocket::async_test]
+async fn generate_image_batch_caps_the_prompts() {
+    let app = TestApp::new().await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "remaining": 10, "code": "VALID"
+    }))
+    .await;
+
+    for body in [
+        r#"{"prompts": ["a", "b", "c", "d", "e", "f"]}"#,
+        r#"{"prompts": []}"#,
+        r#"{"prompts": ["a red fox", "   "]}"#,
+    ] {
+        let res = app
+            .client
+            .post("/generate_image/batch")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", "Bearer sk_test"))
+            .body(body)
+            .dispatch()
+            .await;
+
+        assert_eq!(res.status(), Status::BadRequest);
+    }
+    // Rejected batches are rejected before the key is verified
+    let requests = app.unkey.received_requests().await.unwrap();
+    assert!(!requests
+        .iter()
+        .any(|request| request.url.path() == "/keys.verifyKey"));
+}
+
+#[rocket::async_test]
+async fn generate_image_limits_concurrent_generations_per_owner() {
+    let app =
+        TestApp::with_config(|figment| figment.merge(("max_concurrent_generations", 1))).await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "ownerId": "user_test", "remaining": 3, "code": "VALID"
+    }))
+    .await;
+    Mock::given(method("POST"))
+        .and(path("/images/generations"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json::json!({ "data": [{ "url": "https://images.test/1.png" }] }))
+                .set_delay(std::time::Duration::from_secs(2)),
+        )
+        .mount(&app.openai)
+        .await;
+
+    let request = || {
+        app.client
+            .post("/generate_image")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", "Bearer sk_test"))
+            .body(r#"{"prompt": "a red fox"}"#)
+            .dispatch()
+    };
+    let (first, second) = rocket::tokio::join!(request(), request());
+
+    let mut statuses = [first.status(), second.status()];
+    statuses.sort_by_key(|status| status.code);
+    assert_eq!(statuses, [Status::Ok, Status::TooManyRequests]);
+}
+
+#[rocket::async_test]
+async fn generate_image_limits_anonymous_keys_each_on_their_own() {
+    let app =
+        TestApp::with_config(|figment| figment.merge(("max_concurrent_generations", 1))).await;
+    for key in ["a", "b"] {
+        // Keys created before anonymous owners were unique share the legacy owner
+        Mock::given(method("POST"))
+            .and(path("/keys.verifyKey"))
+            .and(body_partial_json(
+                json::json!({ "key": format!("sk_{}", key) }),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+                "valid": true,
+                "keyId": format!("key_{}", key),
+                "ownerId": "superuser",
+                "remaining": 3,
+                "code": "VALID"
+            })))
+            .mount(&app.unkey)
+            .await;
+    }
+    Mock::given(method("POST"))
+        .and(path("/images/generations"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json::json!({ "data": [{ "url": "https://images.test/1.png" }] }))
+                .set_delay(std::time::Duration::from_secs(2)),
+        )
+        .mount(&app.openai)
+        .await;
+
+    let request = |key: &'static str| {
+        app.client
+            .post("/generate_image")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", format!("Bearer {}", key)))
+            .body(r#"{"prompt": "a red fox"}"#)
+            .dispatch()
+    };
+    let (first, second) = rocket::tokio::join!(request("sk_a"), request("sk_b"));
+
+    assert_eq!(first.status(), Status::Ok);
+    assert_eq!(second.status(), Status::Ok);
+}
+
+#[rocket::async_test]
+async fn generate_image_refunds_the_call_when_the_owner_is_busy() {
+    let app =
+        TestApp::with_config(|figment| figment.merge(("max_concurrent_generations", 1))).await;
+    app.mock_verify(json::json!({
+        "valid": true, "keyId": "key_test", "ownerId": "user_test", "remaining": 3, "code": "VALID"
+    }))
+    .await;
+    Mock::given(method("POST"))
+        .and(path("/images/generations"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json::json!({ "data": [{ "url": "https://images.test/1.png" }] }))
+                .set_delay(std::time::Duration::from_secs(2)),
+        )
+        .mount(&app.openai)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/keys.updateRemaining"))
+        .and(body_partial_json(json::json!({
+            "keyId": "key_test", "op": "increment", "value": 1
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({ "remaining": 3 })))
+        .expect(1)
+        .mount(&app.unkey)
+        .await;
+
+    let request = || {
+        app.client
+            .post("/generate_image")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", "Bearer sk_test"))
+            .body(r#"{"prompt": "a red fox"}"#)
+            .dispatch()
+    };
+    let (first, second) = rocket::tokio::join!(request(), request());
+
+    let mut statuses = [first.status(), second.status()];
+    statuses.sort_by_key(|status| status.code);
+    assert_eq!(statuses, [Status::Ok, Status::TooManyRequests]);
+}
+
+#[rocket::async_test]
+async fn metrics_label_requests_by_route_template() {
+    let app = TestApp::new().await;
+
+    app.client.get("/keys/key_123").dispatch().await;
+    app.client.get("/nowhere").dispatch().await;
+    let res = app.client.get("/metrics").dispatch().await;
+
+    let text = res.into_string().await.unwrap();
+    assert!(text.contains(r#"route="/keys/<key_id>""#), "{}", text);
+    assert!(text.contains(r#"route="unmatched""#), "{}", text);
+    assert!(!text.contains("key_123"));
+}
+
+#[rocket::async_test]
+async fn ready_once_unkey_answers_the_startup_check() {
+    let app =
+        TestApp::with_config(|figment| figment.merge(("readiness_retry_interval_ms", 20))).await;
+
+    let starting = app.client.get("/ready").dispatch().await;
+    assert_eq!(starting.status(), Status::ServiceUnavailable);
+
+    Mock::given(method("GET"))
+        .and(path("/apis.getApi"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json::json!({
+            "id": "api_test", "name": "images", "workspaceId": "ws_test"
+        })))
+        .mount(&app.unkey)
+        .await;
+    let mut status = Status::ServiceUnavailable;
+    for _ in 0..50 {
+        status = app.client.get("/ready").dispatch().await.status();
+        if status == Status::Ok {
+            break;
+        }
+        rocket::tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+
+    assert_eq!(status, Status::Ok);
+}
+
+#[rocket::async_test]
+async fn preflight_responses_are_cacheable_by_allowed_origins() {
+    let app = TestApp::with_config(|figment| figment.merge(("cors_max_age_secs", 600))).await;
+
+    let preflight = app
+        .client
+        .options("/generate_image")
+        .header(Header::new("Origin", "http://localhost:3000"))
+        .header(Header::new("Access-Control-Request-Method", "POST"))
+        .dispatch()
+        .await;
+    let options = app
+        .client
+        .options("/generate_image")
+        .header(Header::new("Origin", "http://localhost:3000"))
+        .dispatch()
+        .await;
+    let simple = app
+        .client
+        .get("/health")
+        .header(Header::new("Origin", "http://localhost:3000"))
+        .dispatch()
+        .await;
+    let foreign = app
+        .client
+        .options("/generate_image")
+        .header(Header::new("Origin", "https://evil.test"))
+        .header(Header::new("Access-Control-Request-Method", "POST"))
+        .dispatch()
+        .await;
+
+    assert_eq!(preflight.status(), Status::NoContent);
+    assert_eq!(
+        preflight.headers().get_one("Access-Control-Max-Age"),
+        Some("600")
+    );
+    assert_eq!(options.headers().get_one("Access-Control-Max-Age"), None);
+    assert_eq!(
+        options.headers().get_one("Access-Control-Allow-Methods"),
+        None
+    );
+    assert_eq!(simple.headers().get_one("Access-Control-Max-Age"), None);
+    assert_eq!(foreign.headers().get_one("Access-Control-Max-Age"), None);
+}
+
+#[rocket::async_test]
+async fn preflights_are_only_answered_for_served_paths() {
+    let app = TestApp::new().await;
+
+    for (path, status) in [
+        ("/keys/key_test", Status::NoContent),
+        ("/generate_image/stream", Status::NoContent),
+        ("/keys/key_test/update", Status::NoContent),
+        ("/keys", Status::NotFound),
+        ("/keys/key_test/extra", Status::NotFound),
+        ("/nothing/here", Status::NotFound),
+    ] {
+        let res = app
+            .client
+            .options(path)
+            .header(Header::new("Origin", "http://localhost:3000"))
+            .header(Header::new("Access-Control-Request-Method", "GET"))
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), status, "{}", path);
+    }
+}
+
+#[rocket::async_test]
+async fn compresses_large_json_responses_when_accepted() {
+    let app = TestApp::new().await;
+
+    let res = app
+        .client
+        .get("/openapi.json")
+        .header(Header::new("Accept-Encoding", "br, gzip"))
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::Ok);
+    assert_eq!(res.headers().get_one("Content-Encoding"), Some("gzip"));
+    assert_eq!(res.headers().get_one("Vary"), Some("Accept-Encoding"));
+    let mut body = String::new();
+    let compressed = res.into_bytes().await.unwrap();
+    std::io::Read::read_to_string(&mut GzDecoder::new(&compressed[..]), &mut body).unwrap();
+    assert_eq!(body, include_str!("openapi.json"));
+}
+
+#[rocket::async_test]
+async fn leaves_small_or_unaccepted_responses_uncompressed() {
+    let app = TestApp::new().await;
+
+    let small = app
+        .client
+        .get("/health")
+        .header(Header::new("Accept-Encoding", "gzip"))
+        .dispatch()
+        .await;
+    let unaccepted = app.client.get("/openapi.json").dispatch().await;
+
+    assert_eq!(small.headers().get_one("Content-Encoding"), None);
+    assert_eq!(unaccepted.headers().get_one("Content-Encoding"), None);
+}
+
+#[test]
+fn negotiate_encoding_prefers_gzip_and_skips_refused_encodings() {
+    assert_eq!(negotiate_encoding("deflate, gzip;q=0.5"), Some("gzip"));
+    assert_eq!(negotiate_encoding("gzip;q=0, deflate"), Some("deflate"));
+    assert_eq!(negotiate_encoding("br"), None);
+    assert_eq!(negotiate_encoding("*"), Some("gzip"));
+}
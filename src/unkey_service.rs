@@ -0,0 +1,612 @@
+//! Service wrapping every call the app makes to Unkey
+
+use anyhow::{Context, Result};
+use rocket::serde::json::{self, Value};
+use rocket::serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use unkey::models::{
+    ApiKey, CreateKeyRequest, ErrorCode, GetApiRequest, GetKeyRequest, HttpError, ListKeysRequest,
+    RevokeKeyRequest, UpdateKeyRequest, UpdateOp, UpdateRemainingRequest, VerifyKeyRequest,
+    VerifyKeyResponse,
+};
+use unkey::Client as UnkeyClient;
+
+use crate::circuit_breaker::CircuitBreaker;
+use crate::metrics::Metrics;
+use crate::models::{DailyUsage, KeyCreateData, KeyDetailsData, KeyListData, KeyVerifyData};
+use crate::secret::SecretKey;
+use crate::verify_cache::VerifyCache;
+use crate::webhook::{KeyEvent, Webhook};
+use crate::AppConfig;
+
+/// Base URL of Unkey's production API, as used by the SDK
+const UNKEY_BASE_URL: &str = "https://api.unkey.dev/v1";
+
+/// Time Unkey gets to answer a call unless configured otherwise
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Verify request asking Unkey to check a permission, which the SDK's request can't express
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct VerifyKeyWithPermission<'a> {
+    #[serde(flatten)]
+    request: VerifyKeyRequest,
+    authorization: Authorization<'a>,
+}
+
+/// Verification counts returned by Unkey's analytics, which the SDK doesn't cover
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Verifications {
+    verifications: Vec<VerificationCount>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "camelCase")]
+struct VerificationCount {
+    time: u64,
+    success: u64,
+    rate_limited: u64,
+    usage_exceeded: u64,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Authorization<'a> {
+    permissions: &'a str,
+}
+
+/// Error verifying a key, when Unkey couldn't tell whether the key is valid
+#[derive(Debug)]
+pub enum VerifyError {
+    /// Unkey is unreachable, too slow or failing on its side, so a retry may succeed
+    Unavailable(HttpError),
+    /// Unkey refused the verification itself, e.g. for a malformed key
+    Rejected(HttpError),
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyError::Unavailable(error) => {
+                write!(f, "Unkey unavailable ({:?}): {}", error.code, error.message)
+            }
+            VerifyError::Rejected(error) => {
+                write!(f, "Unkey error {:?}: {}", error.code, error.message)
+            }
+        }
+    }
+}
+
+impl From<HttpError> for VerifyError {
+    fn from(error: HttpError) -> Self {
+        if is_unavailable(&error) {
+            VerifyError::Unavailable(error)
+        } else {
+            VerifyError::Rejected(error)
+        }
+    }
+}
+
+/// Whether Unkey failed to answer, rather than answering with an error
+fn is_unavailable(error: &HttpError) -> bool {
+    // Transport errors and timeouts have no code of their own
+    matches!(
+        error.code,
+        ErrorCode::Unknown | ErrorCode::InternalServerError
+    )
+}
+
+/// Maps an error status of Unkey to its code, only server errors count as unavailable
+fn status_error_code(status: reqwest::StatusCode) -> ErrorCode {
+    match status {
+        reqwest::StatusCode::BAD_REQUEST => ErrorCode::BadRequest,
+        reqwest::StatusCode::UNAUTHORIZED => ErrorCode::Unauthorized,
+        reqwest::StatusCode::FORBIDDEN => ErrorCode::Forbidden,
+        reqwest::StatusCode::NOT_FOUND => ErrorCode::NotFound,
+        reqwest::StatusCode::CONFLICT => ErrorCode::Conflict,
+        reqwest::StatusCode::TOO_MANY_REQUESTS => ErrorCode::RateLimited,
+        status if status.is_server_error() => ErrorCode::InternalServerError,
+        _ => ErrorCode::BadRequest,
+    }
+}
+
+/// Unkey client bound to the API whose keys the app manages
+///
+/// Clones share the verification cache and the circuit breaker.
+#[derive(Clone)]
+pub struct UnkeyService {
+    client: UnkeyClient,
+    /// Lower-privilege client and key for verifications, `client` verifies when unset
+    verify_client: Option<UnkeyClient>,
+    verify_key: Option<String>,
+    /// Root key for the calls the SDK doesn't support, which the SDK client keeps to itself
+    root_key: Option<String>,
+    /// Client and base URL for the calls the SDK doesn't support
+    http_client: reqwest::Client,
+    url: String,
+    timeout: Duration,
+    verify_cache: VerifyCache,
+    /// Fails verifications fast while Unkey keeps failing
+    breaker: CircuitBreaker,
+    webhook: Option<Webhook>,
+    metrics: Arc<Metrics>,
+}
+
+impl UnkeyService {
+    pub fn new(client: UnkeyClient, metrics: Arc<Metrics>) -> Self {
+        Self {
+            client,
+            verify_client: None,
+            verify_key: None,
+            root_key: None,
+            http_client: reqwest::Client::new(),
+            url: UNKEY_BASE_URL.into(),
+            timeout: DEFAULT_TIMEOUT,
+            verify_cache: VerifyCache::new(Duration::ZERO),
+            breaker: CircuitBreaker::new(0, Duration::ZERO),
+            webhook: None,
+            metrics,
+        }
+    }
+
+    /// Uses `http_client` for the calls the SDK doesn't support
+    pub fn with_http_client(mut self, http_client: reqwest::Client) -> Self {
+        self.http_client = http_client;
+        self
+    }
+
+    /// Sends the calls the SDK doesn't support to `url` rather than Unkey's production API
+    ///
+    /// Set it to the URL the SDK client was built with.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn with_url(mut self, url: impl Into<String>) -> Self {
+        self.url = url.into();
+        self
+    }
+
+    /// Authenticates the calls the SDK doesn't support with `key`, the root key
+    /// the service's client was built with
+    pub fn with_root_key(mut self, key: &str) -> Self {
+        self.root_key = Some(key.into());
+        self
+    }
+
+    /// Verifies keys with `key` instead of the root key the service was built with
+    ///
+    /// Only verifications use it, keys are still created and deleted with the root key.
+    pub fn with_verify_key(mut self, key: &str) -> Self {
+        let mut client = self.client.clone();
+        // The SDK sends the value as the whole Authorization header
+        client.set_key(&format!("Bearer {}", key));
+        self.verify_client = Some(client);
+        self.verify_key = Some(key.into());
+        self
+    }
+
+    /// Fails Unkey calls that take longer than `timeout`
+    ///
+    /// The SDK builds its own HTTP client without a timeout, so calls are timed here.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Runs an Unkey call, failing it once the timeout elapses
+    async fn timed<T>(
+        &self,
+        call: impl Future<Output = Result<T, HttpError>>,
+    ) -> Result<T, HttpError> {
+        rocket::tokio::time::timeout(self.timeout, call)
+            .await
+            .unwrap_or_else(|_| {
+                Err(HttpError {
+                    code: ErrorCode::Unknown,
+                    message: format!("Unkey didn't answer within {:?}", self.timeout),
+                })
+            })
+    }
+
+    /// Caches successful verifications for `ttl` to spare round-trips to Unkey
+    pub fn with_verify_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.verify_cache = VerifyCache::new(ttl);
+        self
+    }
+
+    /// Stops calling Unkey to verify keys for `cooldown` after `threshold`
+    /// consecutive failures, a zero `threshold` disables it
+    ///
+    /// Verifications fail with [`VerifyError::Unavailable`] while the circuit is open,
+    /// then a single one probes whether Unkey recovered.
+    pub fn with_circuit_breaker(mut self, threshold: u32, cooldown: Duration) -> Self {
+        self.breaker = CircuitBreaker::new(threshold, cooldown);
+        self
+    }
+
+    /// Handle on the verification cache, sharing its entries
+    pub fn verify_cache(&self) -> VerifyCache {
+        self.verify_cache.clone()
+    }
+
+    /// Notifies the webhook whenever a key is created or deleted
+    pub fn with_webhook(mut self, webhook: Webhook) -> Self {
+        self.webhook = Some(webhook);
+        self
+    }
+
+    fn notify(&self, event: KeyEvent) {
+        if let Some(webhook) = &self.webhook {
+            webhook.notify(event);
+        }
+    }
+
+    /// Creates a new API key in `api_id` with the quota and ratelimit from the config
+    ///
+    /// `remaining` overrides the configured quota, e.g. to carry it over to a rotated key.
+    #[tracing::instrument(name = "unkey_create", skip_all, fields(api_id = %api_id, owner_id = %owner_id))]
+    pub async fn create(
+        &self,
+        config: &AppConfig,
+        api_id: &str,
+        expires_in_secs: Option<u64>,
+        owner_id: &str,
+        metadata: Option<Value>,
+        remaining: Option<usize>,
+    ) -> Option<KeyCreateData> {
+        let mut req = CreateKeyRequest::new(api_id)
+            .set_remaining(remaining.unwrap_or(config.default_remaining))
+            .set_owner_id(owner_id);
+
+        if let Some(refill) = config.refill() {
+            req = req.set_refill(refill);
+        }
+
+        if let Some(metadata) = metadata {
+            req = req.set_meta(metadata);
+        }
+
+        if !config.key_prefix.is_empty() {
+            req = req.set_prefix(config.key_prefix.as_str());
+        }
+
+        if let Some(length) = config.key_byte_length {
+            req = req.set_byte_length(length);
+        }
+
+        if let Some(ratelimit) = config.ratelimit() {
+            req = req.set_ratelimit(ratelimit);
+        }
+
+        // Unkey expects the expiration as a unix timestamp in milliseconds
+        if let Some(secs) = expires_in_secs {
+            let expires = SystemTime::now().checked_add(Duration::from_secs(secs))?;
+            let millis = expires.duration_since(UNIX_EPOCH).ok()?.as_millis();
+            req = req.set_expires(usize::try_from(millis).ok()?);
+        }
+
+        let started = Instant::now();
+        let result = self.timed(self.client.create_key(req)).await;
+        self.metrics.observe_unkey("create", started);
+        match &result {
+            Ok(res) => {
+                self.metrics.key_created();
+                self.notify(KeyEvent::created(&res.key_id, owner_id));
+                tracing::info!(
+                    key_id = %res.key_id,
+                    latency_ms = started.elapsed().as_millis() as u64,
+                    "created key"
+                )
+            }
+            Err(e) => tracing::warn!(
+                code = ?e.code,
+                latency_ms = started.elapsed().as_millis() as u64,
+                "failed to create key"
+            ),
+        }
+
+        result.ok().map(|res| KeyCreateData {
+            key: res.key.into(),
+            key_id: res.key_id,
+        })
+    }
+
+    /// Verifies an API key of `api_id`, consuming one of its remaining calls
+    ///
+    /// With `permission` set, the key must also carry that permission in Unkey,
+    /// a key lacking it fails with [`ErrorCode::Forbidden`].
+    #[tracing::instrument(name = "unkey_verify", skip_all, fields(api_id = %api_id, permission))]
+    pub async fn verify(
+        &self,
+        api_id: &str,
+        key: &SecretKey,
+        permission: Option<&str>,
+    ) -> Result<KeyVerifyData, VerifyError> {
+        let key = key.expose();
+        if let Some(data) = self.verify_cache.get(api_id, key, permission) {
+            self.metrics.key_verified("valid");
+            return Ok(data);
+        }
+
+        if !self.breaker.allow() {
+            self.metrics.key_verified("error");
+            tracing::warn!("circuit open, not verifying key");
+            return Err(VerifyError::Unavailable(HttpError {
+                code: ErrorCode::Unknown,
+                message: "Unkey is failing, verifications are paused".into(),
+            }));
+        }
+
+        let req = VerifyKeyRequest::new(key, api_id);
+
+        let started = Instant::now();
+        let client = self.verify_client.as_ref().unwrap_or(&self.client);
+        let result = match permission {
+            Some(permission) => {
+                self.timed(self.verify_with_permission(req, permission))
+                    .await
+            }
+            None => self.timed(client.verify_key(req)).await,
+        };
+        self.metrics.observe_unkey("verify", started);
+        match &result {
+            Err(e) if is_unavailable(e) => self.breaker.record_failure(),
+            _ => self.breaker.record_success(),
+        }
+        self.metrics.unkey_circuit(self.breaker.state());
+        match &result {
+            Ok(res) => {
+                self.metrics
+                    .key_verified(if res.valid { "valid" } else { "invalid" });
+                tracing::debug!(
+                    valid = res.valid,
+                    code = ?res.code,
+                    latency_ms = started.elapsed().as_millis() as u64,
+                    "verified key"
+                )
+            }
+            Err(e) => {
+                self.metrics.key_verified("error");
+                tracing::warn!(
+                    code = ?e.code,
+                    latency_ms = started.elapsed().as_millis() as u64,
+                    "failed to verify key"
+                )
+            }
+        }
+
+        let data = result.map(|res| KeyVerifyData {
+            valid: res.valid,
+            key_id: res.key_id,
+            owner_id: res.owner_id,
+            remaining: res.remaining,
+            ratelimit_remaining: res.ratelimit.as_ref().map(|ratelimit| ratelimit.remaining),
+            code: Some(res.code),
+            expires: res.expires,
+            ratelimit_reset: res.ratelimit.map(|ratelimit| ratelimit.reset),
+            refill_interval: res.refill.map(|refill| refill.interval),
+        })?;
+        self.verify_cache.insert(api_id, key, permission, &data);
+        Ok(data)
+    }
+
+    /// Calls Unkey's verify endpoint directly to have it check `permission`
+    async fn verify_with_permission(
+        &self,
+        request: VerifyKeyRequest,
+        permission: &str,
+    ) -> Result<VerifyKeyResponse, HttpError> {
+        let body = VerifyKeyWithPermission {
+            request,
+            authorization: Authorization {
+                permissions: permission,
+            },
+        };
+        let to_error = |e: reqwest::Error| HttpError {
+            code: ErrorCode::Unknown,
+            message: e.to_string(),
+        };
+
+        // Unkey doesn't require a key to verify, but send the verify key like the SDK would
+        let mut req = self
+            .http_client
+            .post(format!("{}/keys.verifyKey", self.url))
+            .json(&body);
+        if let Some(key) = &self.verify_key {
+            req = req.bearer_auth(key);
+        }
+        let res = req.send().await.map_err(to_error)?;
+        let status = res.status();
+        if !status.is_success() {
+            return Err(HttpError {
+                code: status_error_code(status),
+                message: format!("Unkey answered {}", status),
+            });
+        }
+        let mut body: Value = res.json().await.map_err(to_error)?;
+
+        // The SDK's error codes predate permissions, so map Unkey's code for a missing one
+        if body["code"] == "INSUFFICIENT_PERMISSIONS" {
+            body["code"] = "FORBIDDEN".into();
+        }
+        json::from_value(body).map_err(|e| HttpError {
+            code: ErrorCode::Unknown,
+            message: e.to_string(),
+        })
+    }
+
+    /// Counts the verifications of an API key per day between `start` and `end`,
+    /// in Unix milliseconds
+    ///
+    /// Returns `None` when Unkey refuses to serve analytics, e.g. on a plan without them.
+    pub async fn get_verifications(
+        &self,
+        key_id: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<Option<Vec<DailyUsage>>> {
+        let mut req = self
+            .http_client
+            .get(format!("{}/keys.getVerifications", self.url))
+            .query(&[("keyId", key_id), ("granularity", "day")])
+            .query(&[("start", start), ("end", end)]);
+        if let Some(key) = &self.root_key {
+            req = req.bearer_auth(key);
+        }
+
+        let started = Instant::now();
+        let result = self
+            .timed(async {
+                req.send().await.map_err(|e| HttpError {
+                    code: ErrorCode::Unknown,
+                    message: e.to_string(),
+                })
+            })
+            .await;
+        self.metrics.observe_unkey("verifications", started);
+        let res = result
+            .map_err(|e| anyhow::anyhow!("{:?}: {}", e.code, e.message))
+            .context("Failed to read verifications of key in Unkey")?;
+        if res.status() == reqwest::StatusCode::FORBIDDEN {
+            return Ok(None);
+        }
+
+        let body: Verifications = res
+            .error_for_status()?
+            .json()
+            .await
+            .context("Failed to read verifications of key in Unkey")?;
+        Ok(Some(
+            body.verifications
+                .into_iter()
+                .map(|count| DailyUsage {
+                    time: count.time,
+                    success: count.success,
+                    rate_limited: count.rate_limited,
+                    usage_exceeded: count.usage_exceeded,
+                })
+                .collect(),
+        ))
+    }
+
+    /// Deletes an API key so it no longer verifies
+    pub async fn delete(&self, key_id: &str) -> Result<()> {
+        let req = RevokeKeyRequest::new(key_id);
+
+        let started = Instant::now();
+        let result = self.timed(self.client.revoke_key(req)).await;
+        self.metrics.observe_unkey("delete", started);
+        if result.is_ok() {
+            self.notify(KeyEvent::deleted(key_id));
+        }
+        result
+            .map_err(|e| anyhow::anyhow!("{:?}: {}", e.code, e.message))
+            .context("Failed to delete key in Unkey")
+    }
+
+    /// Reads the details of an API key, `None` when Unkey doesn't know it
+    pub async fn get(&self, key_id: &str) -> Result<Option<KeyDetailsData>> {
+        let req = GetKeyRequest::new(key_id);
+
+        let started = Instant::now();
+        let result = self.timed(self.client.get_key(req)).await;
+        self.metrics.observe_unkey("get", started);
+        match result {
+            Ok(res) => Ok(Some(details(res))),
+            Err(e) if e.code == ErrorCode::NotFound => Ok(None),
+            Err(e) => Err(anyhow::anyhow!("{:?}: {}", e.code, e.message))
+                .context("Failed to get key in Unkey"),
+        }
+    }
+
+    /// Lists a page of the keys of `api_id` owned by `owner_id`, starting after `cursor`
+    pub async fn list_keys(
+        &self,
+        api_id: &str,
+        owner_id: &str,
+        limit: usize,
+        cursor: Option<&str>,
+    ) -> Result<KeyListData> {
+        let mut req = ListKeysRequest::new(api_id)
+            .set_owner_id(owner_id)
+            .set_limit(limit);
+        if let Some(cursor) = cursor {
+            req = req.set_cursor(cursor);
+        }
+
+        let started = Instant::now();
+        let result = self.timed(self.client.list_keys(req)).await;
+        self.metrics.observe_unkey("list", started);
+        let res = result
+            .map_err(|e| anyhow::anyhow!("{:?}: {}", e.code, e.message))
+            .context("Failed to list keys in Unkey")?;
+
+        Ok(KeyListData {
+            keys: res.keys.into_iter().map(details).collect(),
+            total: res.total,
+            cursor: res.cursor,
+        })
+    }
+
+    /// Sets the remaining calls of an API key
+    pub async fn update(&self, key_id: &str, remaining: usize) -> Result<()> {
+        let req = UpdateKeyRequest::new(key_id).set_remaining(Some(remaining));
+
+        let started = Instant::now();
+        let result = self.timed(self.client.update_key(req)).await;
+        self.metrics.observe_unkey("update", started);
+        result
+            .map_err(|e| anyhow::anyhow!("{:?}: {}", e.code, e.message))
+            .context("Failed to update key in Unkey")
+    }
+
+    /// Takes `calls` off the remaining calls of an API key, returning how many are left
+    pub async fn spend(&self, key_id: &str, calls: usize) -> Result<usize> {
+        self.update_remaining("spend", key_id, calls, UpdateOp::Decrement)
+            .await
+            .context("Failed to spend calls of key in Unkey")
+    }
+
+    /// Gives `calls` back to an API key, returning how many it has left
+    pub async fn refund(&self, key_id: &str, calls: usize) -> Result<usize> {
+        self.update_remaining("refund", key_id, calls, UpdateOp::Increment)
+            .await
+            .context("Failed to refund calls of key in Unkey")
+    }
+
+    async fn update_remaining(
+        &self,
+        operation: &str,
+        key_id: &str,
+        calls: usize,
+        op: UpdateOp,
+    ) -> Result<usize> {
+        let req = UpdateRemainingRequest::new(key_id, Some(calls), op);
+
+        let started = Instant::now();
+        let result = self.timed(self.client.update_remaining(req)).await;
+        self.metrics.observe_unkey(operation, started);
+        result
+            .map(|res| res.remaining)
+            .map_err(|e| anyhow::anyhow!("{:?}: {}", e.code, e.message))
+    }
+
+    /// Checks that Unkey responds with the API `api_id`
+    pub async fn check(&self, api_id: &str) -> bool {
+        let req = GetApiRequest::new(api_id);
+
+        self.timed(self.client.get_api(req)).await.is_ok()
+    }
+}
+
+/// Keeps the fields of an Unkey key the endpoints expose
+fn details(key: ApiKey) -> KeyDetailsData {
+    KeyDetailsData {
+        key_id: key.id,
+        owner_id: key.owner_id,
+        remaining: key.remaining,
+        metadata: key.meta,
+        expires: key.expires,
+    }
+}
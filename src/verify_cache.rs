@@ -0,0 +1,134 @@
+//! Short-lived cache of successful key verifications
+
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::models::KeyVerifyData;
+
+/// Verification cached for a key until it expires
+struct CachedVerification {
+    data: KeyVerifyData,
+    expires: Instant,
+}
+
+/// In-memory TTL cache of valid verification results, keyed by the SHA-256 of the key,
+/// the Unkey API and the permission it was verified for
+///
+/// Only valid results of keys without a quota or an expiry are cached, since the
+/// calls of the others have to be spent with Unkey anyway. Hits skip Unkey's
+/// revocation and ratelimit checks: each hit spends one of the cached ratelimit
+/// tokens, and an entry is dropped once it's down to its last one, so Unkey still
+/// decides on it. Clones share the same entries.
+#[derive(Clone)]
+pub struct VerifyCache {
+    ttl: Duration,
+    entries: Arc<Mutex<HashMap<[u8; 32], CachedVerification>>>,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+}
+
+impl VerifyCache {
+    /// Creates a cache keeping results for `ttl`, a zero TTL disables caching
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            hits: Arc::new(AtomicU64::new(0)),
+            misses: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        !self.ttl.is_zero()
+    }
+
+    /// Hashes the key along with the API and the permission it was verified for,
+    /// so a verification is only reused for the same API and permission
+    fn hash(api_id: &str, key: &str, permission: Option<&str>) -> [u8; 32] {
+        let permission = permission.unwrap_or_default();
+        Sha256::new()
+            .chain_update((api_id.len() as u64).to_le_bytes())
+            .chain_update(api_id.as_bytes())
+            .chain_update((permission.len() as u64).to_le_bytes())
+            .chain_update(permission.as_bytes())
+            .chain_update(key.as_bytes())
+            .finalize()
+            .into()
+    }
+
+    /// Returns the cached verification of a key, counting the call against its ratelimit
+    pub fn get(&self, api_id: &str, key: &str, permission: Option<&str>) -> Option<KeyVerifyData> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        let hash = Self::hash(api_id, key, permission);
+        let mut entries = self.entries.lock().unwrap();
+        let hit = entries
+            .get_mut(&hash)
+            .filter(|entry| entry.expires > Instant::now())
+            .and_then(|entry| {
+                // Keep the last call of the ratelimit for Unkey to decide on
+                entry.data.ratelimit_remaining = spend(entry.data.ratelimit_remaining)?;
+                Some(entry.data.clone())
+            });
+        if hit.is_none() {
+            entries.remove(&hash);
+        }
+        drop(entries);
+
+        let (hits, misses) = if hit.is_some() {
+            (
+                self.hits.fetch_add(1, Ordering::Relaxed) + 1,
+                self.misses.load(Ordering::Relaxed),
+            )
+        } else {
+            (
+                self.hits.load(Ordering::Relaxed),
+                self.misses.fetch_add(1, Ordering::Relaxed) + 1,
+            )
+        };
+        tracing::debug!(hit = hit.is_some(), hits, misses, "verify cache lookup");
+        hit
+    }
+
+    /// Caches the verification of a key if it was valid, for a key without a quota or an expiry
+    pub fn insert(&self, api_id: &str, key: &str, permission: Option<&str>, data: &KeyVerifyData) {
+        let cacheable = data.valid && data.remaining.is_none() && data.expires.is_none();
+        if !self.is_enabled() || !cacheable {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| entry.expires > now);
+        entries.insert(
+            Self::hash(api_id, key, permission),
+            CachedVerification {
+                data: data.clone(),
+                expires: now + self.ttl,
+            },
+        );
+    }
+
+    /// Drops the expired verifications, returning how many were dropped
+    pub fn prune(&self) -> usize {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let before = entries.len();
+        entries.retain(|_, entry| entry.expires > now);
+        before - entries.len()
+    }
+}
+
+/// Spends one call of an optional budget, `None` when it's down to its last call
+fn spend(budget: Option<usize>) -> Option<Option<usize>> {
+    match budget {
+        Some(calls) if calls <= 1 => None,
+        Some(calls) => Some(Some(calls - 1)),
+        None => Some(None),
+    }
+}
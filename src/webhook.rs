@@ -0,0 +1,140 @@
+//! Webhook notifying downstream systems of key lifecycle events
+
+use reqwest::Client;
+use rocket::serde::Serialize;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Delay before the single retry of a failed delivery
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Event posted to the webhook as JSON
+#[derive(Serialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct KeyEvent {
+    /// `key.created` or `key.deleted`
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub key_id: String,
+    /// Owner of the key, unknown for deleted keys
+    pub owner_id: Option<String>,
+    /// Unix timestamp in milliseconds
+    pub timestamp: u64,
+}
+
+impl KeyEvent {
+    pub fn created(key_id: &str, owner_id: &str) -> Self {
+        Self::new("key.created", key_id, Some(owner_id))
+    }
+
+    pub fn deleted(key_id: &str) -> Self {
+        Self::new("key.deleted", key_id, None)
+    }
+
+    fn new(kind: &'static str, key_id: &str, owner_id: Option<&str>) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_millis() as u64);
+
+        Self {
+            kind,
+            key_id: key_id.into(),
+            owner_id: owner_id.map(Into::into),
+            timestamp,
+        }
+    }
+}
+
+/// Webhook receiving [`KeyEvent`]s
+#[derive(Clone)]
+pub struct Webhook {
+    http_client: Client,
+    url: String,
+}
+
+impl Webhook {
+    pub fn new(http_client: Client, url: impl Into<String>) -> Self {
+        Self {
+            http_client,
+            url: url.into(),
+        }
+    }
+
+    /// Posts the event in the background, retrying once before logging the failure
+    pub fn notify(&self, event: KeyEvent) {
+        let webhook = self.clone();
+        rocket::tokio::spawn(async move {
+            if webhook.deliver(&event).await.is_ok() {
+                return;
+            }
+            rocket::tokio::time::sleep(RETRY_DELAY).await;
+            if let Err(e) = webhook.deliver(&event).await {
+                tracing::error!(
+                    error = ?e,
+                    event = event.kind,
+                    key_id = %event.key_id,
+                    "failed to deliver webhook"
+                );
+            }
+        });
+    }
+
+    async fn deliver(&self, event: &KeyEvent) -> reqwest::Result<()> {
+        self.http_client
+            .post(&self.url)
+            .json(event)
+            .send()
+            .await?
+            .error_for_status()
+            .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{body_partial_json, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    /// Waits for the background delivery to reach the server
+    async fn wait_for_requests(server: &MockServer, count: usize) {
+        for _ in 0..50 {
+            if server.received_requests().await.unwrap().len() >= count {
+                return;
+            }
+            rocket::tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("webhook wasn't delivered {} times", count);
+    }
+
+    #[rocket::async_test]
+    async fn posts_the_event() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(rocket::serde::json::json!({
+                "type": "key.created", "key_id": "key_test", "owner_id": "user_test"
+            })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        Webhook::new(Client::new(), server.uri())
+            .notify(KeyEvent::created("key_test", "user_test"));
+
+        wait_for_requests(&server, 1).await;
+    }
+
+    #[rocket::async_test]
+    async fn retries_a_failed_delivery_once() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        Webhook::new(Client::new(), server.uri()).notify(KeyEvent::deleted("key_test"));
+
+        wait_for_requests(&server, 2).await;
+    }
+}
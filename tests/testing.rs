@@ -0,0 +1,65 @@
+//! Boots the app from outside the crate, as enabled by the `testing` feature
+#![cfg(feature = "testing")]
+
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::asynchronous::Client;
+use rocket::serde::json::Value;
+use unkey_rust_rocket::testing::{FakeImageProvider, FakeUnkey, FAKE_IMAGE_URL};
+use unkey_rust_rocket::{build_rocket, AppConfig, Services};
+
+/// Client for the app calling `unkey` and generating fake images
+async fn client(unkey: &FakeUnkey) -> Client {
+    let figment = rocket::Config::figment()
+        .merge(("log_level", "off"))
+        .merge(("unkey_root_key", "unkey_root"))
+        .merge(("unkey_api_id", "api_test"))
+        .merge(("openai_api_key", "sk-test"));
+    let config: AppConfig = figment.extract().unwrap();
+    let mut services = Services::from_config(&config).unwrap();
+    services.unkey = unkey.service(services.metrics.clone());
+    services.provider = Box::new(FakeImageProvider);
+    let rocket = build_rocket(figment, config, services);
+    Client::tracked(rocket).await.unwrap()
+}
+
+#[rocket::async_test]
+async fn generates_images_with_injected_fakes() {
+    let unkey = FakeUnkey::start().await;
+    let key_id = unkey.insert("sk_test", Some("user_test"), Some(4));
+    let client = client(&unkey).await;
+
+    let res = client
+        .post("/generate_image")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer sk_test"))
+        .body(r#"{"prompt": "a red fox"}"#)
+        .dispatch()
+        .await;
+
+    assert_eq!(res.status(), Status::Ok);
+    let body: Value = res.into_json().await.unwrap();
+    assert_eq!(body["image_url"], FAKE_IMAGE_URL);
+    assert_eq!(unkey.remaining(&key_id), Some(3));
+}
+
+#[rocket::async_test]
+async fn fake_unkey_keys_run_out_of_calls() {
+    let unkey = FakeUnkey::start().await;
+    unkey.insert("sk_test", None, Some(1));
+    let client = client(&unkey).await;
+
+    let generate = || {
+        client
+            .post("/generate_image")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", "Bearer sk_test"))
+            .body(r#"{"prompt": "a red fox"}"#)
+            .dispatch()
+    };
+
+    assert_eq!(generate().await.status(), Status::Ok);
+    let exhausted = generate().await;
+    assert_eq!(exhausted.status(), Status::TooManyRequests);
+    let body: Value = exhausted.into_json().await.unwrap();
+    assert_eq!(body["error"]["code"], "quota_exceeded");
+}